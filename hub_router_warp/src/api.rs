@@ -1,46 +1,86 @@
 //! The API server which serves the UI and provides a configuration interface
 
-use crate::hub::{Hub, HubState, HubMetadata};
+use crate::audit::{HistoryQuery, SessionEvent, AUDIT_LOG};
+use crate::api_auth::{cors_policy, handle_rejection, require_role, ApiAuth, ApiAuthConfig, ApiRole};
+use crate::proxy_clients::{ClientQuota, ClientUsageStatus};
+use crate::client::{
+    pool_status, HostPoolStatus, HttpClient, OutboundPoolConfig, OutboundPoolStatus,
+};
+use crate::credentials::{apply_hub_auth, without_credentials, HubAuth, Secret};
+use crate::handler::close_session;
+use crate::hub::{deregister_drained_hubs, Hub, HubMetadata, HubNode, HubState};
 use crate::logger::SEVERE_LOG_STORE;
-use crate::routing::RoutingDecision;
+use crate::metrics::METRICS;
+use crate::queue::QueueDepth;
+use crate::peers::PeerSession;
+use crate::routing::{RoutingDecision, RoutingPrecedentMap};
 use crate::schema::Session;
 use crate::state::{HubRouterPrimitiveConfigs, HubRouterState};
+use crate::strategy::RoutingStrategyKind;
+use crate::listener::{serve_following_config, ListenerConfig};
 use crate::ui::WebUIAssets;
-use dashmap::DashMap;
 use hyper::body::Bytes;
-use hyper::{Client, Request, StatusCode, Uri};
+use hyper::{Request, StatusCode, Uri};
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::task::JoinSet;
 use tokio::time::timeout;
 use url::Url;
-use utoipa::OpenApi;
+use utoipa::{OpenApi, ToSchema};
 use uuid::Uuid;
 use warp::path::Tail;
 use warp::reply::Response;
-use warp::{reply, Filter};
+use warp::Filter;
 
 /// Primary entrypoint for the API. Will run and provide information and capabilities to
 /// update information on the running Hub programatically.
+/// The API is rebuilt on a new listener whenever its address, TLS, authentication
+/// or CORS settings change.
 pub async fn hub_api_thread(
     state: Arc<HubRouterState>,
-    sessions: Arc<DashMap<String, RoutingDecision>>,
+    sessions: Arc<RoutingPrecedentMap>,
 ) {
-    let bind_tuple = match state.configs.read() {
-        Ok(conf) => (conf.api_bind_ip, conf.api_bind_port),
-        Err(err) => {
-            warn!("RWLock was poisoned generating api bind tuple: {}", err);
-            let conf = HubRouterPrimitiveConfigs::default();
-            (conf.api_bind_ip, conf.api_bind_port)
-        }
-    };
     info!("starting api thread");
+    serve_following_config(
+        "API",
+        state.clone(),
+        |conf| {
+            (
+                ListenerConfig {
+                    addr: SocketAddr::new(IpAddr::V4(conf.api_bind_ip), conf.api_bind_port),
+                    tls: conf.api_tls.clone(),
+                },
+                (conf.api_auth.clone(), conf.api_cors_origins.clone()),
+            )
+        },
+        |(api_auth, cors_origins), incoming, shutdown| {
+            let routes = api_routes(state.clone(), sessions.clone(), api_auth, cors_origins);
+            warp::serve(routes).serve_incoming_with_graceful_shutdown(incoming, shutdown)
+        },
+    )
+    .await
+}
+
+/// Build every route the API serves.
+pub fn api_routes(
+    state: Arc<HubRouterState>,
+    sessions: Arc<RoutingPrecedentMap>,
+    api_auth: &ApiAuthConfig,
+    cors_origins: &[String],
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + Send + Sync + 'static {
     let state_filter = warp::any().map(move || state.clone());
     let sessions_filter = warp::any().map(move || sessions.clone());
 
+    // Every route but the UI and the OpenAPI spec needs a role, and anything
+    // which changes the Hub Router needs the admin role
+    let auth = Arc::new(ApiAuth::from_config(api_auth));
+    let read_only = require_role(auth.clone(), ApiRole::ReadOnly);
+    let admin = require_role(auth, ApiRole::Admin);
+
     let openapi_spec = warp::get()
         .and(warp::path!("swagger.json"))
         .and(warp::path::end())
@@ -49,44 +89,99 @@ pub async fn hub_api_thread(
     let get_hubs = warp::get()
         .and(warp::path!("api" / "hubs"))
         .and(warp::path::end())
+        .and(read_only.clone())
         .and(state_filter.clone())
         .and_then(get_hubs);
 
     let create_hub = warp::post()
         .and(warp::path!("api" / "hubs"))
         .and(warp::path::end())
+        .and(admin.clone())
         .and(state_filter.clone())
         .and(warp::body::content_length_limit(1024 * 16))
         .and(warp::body::json())
         .and_then(create_hub);
 
+    let update_hub = warp::patch()
+        .and(warp::path!("api" / "hubs" / Uuid))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(state_filter.clone())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and_then(update_hub);
+
+    let get_hub_drain = warp::get()
+        .and(warp::path!("api" / "hubs" / Uuid / "drain"))
+        .and(warp::path::end())
+        .and(read_only.clone())
+        .and(state_filter.clone())
+        .and(sessions_filter.clone())
+        .and_then(get_hub_drain);
+
+    let get_hub_nodes = warp::get()
+        .and(warp::path!("api" / "hubs" / Uuid / "nodes"))
+        .and(warp::path::end())
+        .and(read_only.clone())
+        .and(state_filter.clone())
+        .and_then(get_hub_nodes);
+
+    let start_hub_drain = warp::post()
+        .and(warp::path!("api" / "hubs" / Uuid / "drain"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(warp::query::<DrainOptions>())
+        .and(state_filter.clone())
+        .and(sessions_filter.clone())
+        .and_then(start_hub_drain);
+
+    let stop_hub_drain = warp::delete()
+        .and(warp::path!("api" / "hubs" / Uuid / "drain"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(state_filter.clone())
+        .and(sessions_filter.clone())
+        .and_then(stop_hub_drain);
+
     let delete_hub = warp::delete()
         .and(warp::path!("api" / "hubs" / Uuid))
         .and(warp::path::end())
+        .and(admin.clone())
         .and(state_filter.clone())
         .and_then(delete_hub);
 
     let get_config_values = warp::get()
         .and(warp::path!("api" / "config" / String))
         .and(warp::path::end())
+        .and(read_only.clone())
         .and(state_filter.clone())
         .and_then(get_config);
 
     let set_config_values = warp::post()
         .and(warp::path!("api" / "config" / String / u64))
         .and(warp::path::end())
+        .and(admin.clone())
         .and(state_filter.clone())
         .and_then(set_config);
 
+    let set_routing_strategy = warp::post()
+        .and(warp::path!("api" / "config" / "routing_strategy" / String))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(state_filter.clone())
+        .and_then(set_routing_strategy);
+
     let get_router_config = warp::get()
         .and(warp::path!("api" / "config"))
         .and(warp::path::end())
+        .and(read_only.clone())
         .and(state_filter.clone())
         .and_then(get_entire_config);
 
     let set_router_config = warp::post()
         .and(warp::path!("api" / "config"))
         .and(warp::path::end())
+        .and(admin.clone())
         .and(warp::body::json::<HubRouterPrimitiveConfigs>())
         .and(state_filter.clone())
         .and_then(set_entire_config);
@@ -94,17 +189,44 @@ pub async fn hub_api_thread(
     let get_severe_logs = warp::get()
         .and(warp::path!("api" / "logs"))
         .and(warp::path::end())
+        .and(read_only.clone())
         .and_then(get_logs);
 
     let get_sessions = warp::get()
         .and(warp::path!("api" / "sessions"))
         .and(warp::path::end())
+        .and(read_only.clone())
+        .and(state_filter.clone())
         .and(sessions_filter.clone())
         .and_then(get_sessions);
 
+    let get_session_history = warp::get()
+        .and(warp::path!("api" / "sessions" / "history"))
+        .and(warp::path::end())
+        .and(read_only.clone())
+        .and(warp::query::<HistoryQuery>())
+        .and_then(get_session_history);
+
+    let get_session = warp::get()
+        .and(warp::path!("api" / "sessions" / String))
+        .and(warp::path::end())
+        .and(read_only.clone())
+        .and(state_filter.clone())
+        .and(sessions_filter.clone())
+        .and_then(get_session);
+
+    let delete_session = warp::delete()
+        .and(warp::path!("api" / "sessions" / String))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(state_filter.clone())
+        .and(sessions_filter.clone())
+        .and_then(delete_session);
+
     let aggregate_graphql_responses = warp::post()
         .and(warp::path!("api" / "graphql"))
         .and(warp::path::end())
+        .and(read_only.clone())
         .and(warp::body::bytes())
         .and(state_filter.clone())
         .and_then(aggregate_graphql_responses);
@@ -112,40 +234,107 @@ pub async fn hub_api_thread(
     let aggregate_status_responses = warp::get()
         .and(warp::path!("api" / "hubs" / "status"))
         .and(warp::path::end())
+        .and(read_only.clone())
         .and(state_filter.clone())
         .and_then(aggregate_status_responses);
 
+    let get_queue = warp::get()
+        .and(warp::path!("api" / "queue"))
+        .and(warp::path::end())
+        .and(read_only.clone())
+        .and(state_filter.clone())
+        .and_then(get_queue);
+
+    let get_clients = warp::get()
+        .and(warp::path!("api" / "clients"))
+        .and(warp::path::end())
+        .and(read_only.clone())
+        .and(state_filter.clone())
+        .and(sessions_filter.clone())
+        .and_then(get_clients);
+
+    let get_pool = warp::get()
+        .and(warp::path!("api" / "pool"))
+        .and(warp::path::end())
+        .and(read_only.clone())
+        .and(state_filter.clone())
+        .and_then(get_pool);
+
+    let get_metrics = warp::get()
+        .and(warp::path!("metrics"))
+        .and(warp::path::end())
+        .and(read_only.clone())
+        .and(state_filter.clone())
+        .and(sessions_filter.clone())
+        .and_then(serve_metrics);
+
+    let get_peer_sessions = warp::get()
+        .and(warp::path!("api" / "peer" / "sessions"))
+        .and(warp::path::end())
+        .and(read_only.clone())
+        .and(sessions_filter.clone())
+        .and_then(get_peer_sessions);
+
+    let get_peer_session = warp::get()
+        .and(warp::path!("api" / "peer" / "sessions" / String))
+        .and(warp::path::end())
+        .and(read_only.clone())
+        .and(sessions_filter.clone())
+        .and_then(get_peer_session);
+
+    let put_peer_session = warp::put()
+        .and(warp::path!("api" / "peer" / "sessions" / String))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(sessions_filter.clone())
+        .and_then(put_peer_session);
+
+    let delete_peer_session = warp::delete()
+        .and(warp::path!("api" / "peer" / "sessions" / String))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(sessions_filter.clone())
+        .and_then(delete_peer_session);
+
     let get_ui = warp::get()
         .and(warp::path("ui"))
         .and(warp::path::tail())
         .and_then(serve_ui);
 
-    let routes = get_hubs
+    get_hubs
         .or(create_hub)
+        .or(update_hub)
+        .or(get_hub_drain)
+        .or(get_hub_nodes)
+        .or(start_hub_drain)
+        .or(stop_hub_drain)
         .or(delete_hub)
         .or(get_sessions)
+        .or(get_session_history)
+        .or(get_session)
+        .or(delete_session)
+        .or(get_queue)
+        .or(get_clients)
+        .or(get_pool)
         .or(get_ui)
         .or(aggregate_graphql_responses)
         .or(aggregate_status_responses)
         .or(set_config_values)
+        .or(set_routing_strategy)
         .or(get_config_values)
         .or(get_router_config)
         .or(set_router_config)
         .or(get_severe_logs)
         .or(openapi_spec)
-        .or(warp::any().map(|| {
-            Ok(warp::reply::with_status(
-                reply::reply(),
-                StatusCode::NOT_FOUND,
-            ))
-        }))
-        .with(
-            warp::cors()
-                .allow_any_origin()
-                .allow_methods(vec!["GET", "POST", "OPTIONS"]),
-        );
-
-    warp::serve(routes).run(bind_tuple).await;
+        .or(get_metrics)
+        .or(get_peer_sessions)
+        .or(get_peer_session)
+        .or(put_peer_session)
+        .or(delete_peer_session)
+        .recover(handle_rejection)
+        .with(cors_policy(cors_origins))
 }
 
 #[derive(OpenApi)]
@@ -153,15 +342,27 @@ pub async fn hub_api_thread(
     paths(
         get_hubs,
         create_hub,
+        update_hub,
+        get_hub_drain,
+        get_hub_nodes,
+        start_hub_drain,
+        stop_hub_drain,
         delete_hub,
         get_sessions,
+        get_session_history,
+        get_session,
+        delete_session,
+        get_queue,
+        get_clients,
+        get_pool,
         set_config,
+        set_routing_strategy,
         get_config,
         get_entire_config,
         set_entire_config,
         get_logs,
     ),
-    components(schemas(Hub, HubRouterState, HubState, HubMetadata)),
+    components(schemas(Hub, HubRouterState, HubState, HubNode, HubMetadata, HubSettings, HubAuth, Secret, DrainStatus, ClientUsageStatus, ClientQuota, SessionEvent, OutboundPoolStatus, OutboundPoolConfig, HostPoolStatus)),
     info(
         description = "OpenAPI specification for the Hub Router API.",
         title = "Hub Router API",
@@ -185,8 +386,7 @@ async fn get_hubs(state: Arc<HubRouterState>) -> Result<impl warp::Reply, warp::
     let mut lhubs: Vec<Hub> = vec![];
 
     for hub in state.hubs.iter() {
-        let c = hub.clone();
-        lhubs.push(c);
+        lhubs.push(hub.redacted());
     }
 
     Ok(warp::reply::json(&lhubs))
@@ -196,6 +396,57 @@ async fn get_hubs(state: Arc<HubRouterState>) -> Result<impl warp::Reply, warp::
 struct HubNameAndURL {
     name: String,
     url: String,
+
+    #[serde(flatten)]
+    settings: HubSettings,
+}
+
+/// Routing settings for a hub, any of which may be left out to keep their current value.
+#[derive(Serialize, Deserialize, Default, ToSchema)]
+struct HubSettings {
+    name: Option<String>,
+    weight: Option<u32>,
+    priority: Option<u32>,
+    /// Zero removes the cap.
+    max_concurrent_sessions: Option<u32>,
+    enabled: Option<bool>,
+    draining: Option<bool>,
+    insecure_skip_verify: Option<bool>,
+    /// Null removes the hub's credentials.
+    #[serde(default, deserialize_with = "crate::utils::deserialize_some")]
+    auth: Option<Option<HubAuth>>,
+}
+
+impl HubSettings {
+    fn apply(self, meta: &mut HubMetadata) {
+        if let Some(name) = self.name {
+            meta.name = name;
+        }
+        if let Some(weight) = self.weight {
+            meta.weight = weight;
+        }
+        if let Some(priority) = self.priority {
+            meta.priority = priority;
+        }
+        if let Some(cap) = self.max_concurrent_sessions {
+            meta.max_concurrent_sessions = if cap == 0 { None } else { Some(cap) };
+        }
+        if let Some(enabled) = self.enabled {
+            meta.enabled = enabled;
+        }
+        if let Some(draining) = self.draining {
+            meta.draining = draining;
+        }
+        if let Some(insecure_skip_verify) = self.insecure_skip_verify {
+            meta.insecure_skip_verify = insecure_skip_verify;
+        }
+        if let Some(mut auth) = self.auth {
+            if let (Some(auth), Some(current)) = (auth.as_mut(), &meta.auth) {
+                auth.restore_redacted(current);
+            }
+            meta.auth = auth;
+        }
+    }
 }
 
 #[utoipa::path(
@@ -225,13 +476,14 @@ async fn create_hub(
         }
     };
 
-    if state.hubs.iter().any(|e| &e.meta.url == &url) {
+    if state.hubs.iter().any(|e| e.meta.url == url) {
         return Ok(warp::reply::with_status(
             format!("hub at {} already registered", url.as_str()),
             StatusCode::NOT_ACCEPTABLE,
         ));
     } else {
-        let new_hub = Hub::new_with_name(&meta.name, url);
+        let mut new_hub = Hub::new_with_name(&meta.name, url);
+        meta.settings.apply(&mut new_hub.meta);
         state.hubs.insert(new_hub.meta.uuid, new_hub);
         if let Err(e) = state.persist() {
             return Ok(warp::reply::with_status(
@@ -241,10 +493,214 @@ async fn create_hub(
         }
     }
 
-    return Ok(warp::reply::with_status(
-        format!("registered hub successfully"),
+    Ok(warp::reply::with_status(
+        "registered hub successfully".to_string(),
         StatusCode::CREATED,
-    ));
+    ))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/hubs/{uuid}",
+    request_body = HubSettings,
+    responses(
+        (status = 200, description = "Updated Hub successfully"),
+        (status = NOT_FOUND, description = "No Hub with that UUID is registered"),
+        (status = INTERNAL_SERVER_ERROR, description = "Unable to persist updated Hub"),
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of Hub to update."),
+    )
+)]
+async fn update_hub(
+    uuid: Uuid,
+    state: Arc<HubRouterState>,
+    settings: HubSettings,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match state.hubs.get_mut(&uuid) {
+        Some(mut hub) => settings.apply(&mut hub.meta),
+        None => {
+            return Ok(warp::reply::with_status(
+                format!("no hub with uuid {} is registered", uuid),
+                StatusCode::NOT_FOUND,
+            ))
+        }
+    }
+
+    // Sessions waiting in the queue may be able to go to the hub now
+    state.new_session_queue.notify_slot_freed();
+
+    if let Err(e) = state.persist() {
+        return Ok(warp::reply::with_status(
+            format!("Unable to persist updated hub: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ));
+    }
+
+    Ok(warp::reply::with_status(
+        "updated hub successfully".to_string(),
+        StatusCode::OK,
+    ))
+}
+
+/// How far along a hub is in draining its sessions.
+#[derive(Serialize, Deserialize, ToSchema)]
+struct DrainStatus {
+    #[serde(serialize_with = "crate::utils::serialize_uuid")]
+    #[serde(deserialize_with = "crate::utils::deserialize_uuid")]
+    uuid: Uuid,
+    draining: bool,
+    deregister_when_drained: bool,
+    /// Sessions this router has sent to the hub which haven't finished yet.
+    remaining_sessions: usize,
+    session_ids: Vec<String>,
+    /// Sessions the hub itself reported running at its last healthcheck,
+    /// including any which weren't created through this router.
+    hub_active_sessions: u8,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct DrainOptions {
+    deregister: bool,
+}
+
+fn drain_status(
+    uuid: Uuid,
+    state: &HubRouterState,
+    sessions: &RoutingPrecedentMap,
+) -> Option<DrainStatus> {
+    let hub = state.hubs.get(&uuid)?;
+    let session_ids = sessions.sessions_at_endpoint(&hub.meta.url);
+    Some(DrainStatus {
+        uuid,
+        draining: hub.meta.draining,
+        deregister_when_drained: hub.meta.deregister_when_drained,
+        remaining_sessions: session_ids.len(),
+        session_ids,
+        hub_active_sessions: hub.state.get_stereotype_fullness(None).0,
+    })
+}
+
+fn drain_status_reply(
+    uuid: Uuid,
+    state: &HubRouterState,
+    sessions: &RoutingPrecedentMap,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    match drain_status(uuid, state, sessions) {
+        Some(status) => warp::reply::with_status(warp::reply::json(&status), StatusCode::OK),
+        // The hub may have been deregistered as soon as it drained
+        None => warp::reply::with_status(
+            warp::reply::json(&format!("no hub with uuid {} is registered", uuid)),
+            StatusCode::NOT_FOUND,
+        ),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/hubs/{uuid}/drain",
+    responses(
+        (status = 200, description = "Returned the drain status of the Hub", body = DrainStatus),
+        (status = NOT_FOUND, description = "No Hub with that UUID is registered"),
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the Hub."),
+    )
+)]
+async fn get_hub_drain(
+    uuid: Uuid,
+    state: Arc<HubRouterState>,
+    sessions: Arc<RoutingPrecedentMap>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(drain_status_reply(uuid, &state, &sessions))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/hubs/{uuid}/nodes",
+    responses(
+        (status = 200, description = "Returned the nodes the Hub reported at its last healthcheck", body = [HubNode]),
+        (status = NOT_FOUND, description = "No Hub with that UUID is registered"),
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the Hub."),
+    )
+)]
+async fn get_hub_nodes(
+    uuid: Uuid,
+    state: Arc<HubRouterState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(match state.hubs.get(&uuid) {
+        Some(hub) => warp::reply::with_status(warp::reply::json(&hub.state.nodes), StatusCode::OK),
+        None => warp::reply::with_status(
+            warp::reply::json(&format!("no hub with uuid {} is registered", uuid)),
+            StatusCode::NOT_FOUND,
+        ),
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/hubs/{uuid}/drain",
+    responses(
+        (status = 200, description = "The Hub is draining", body = DrainStatus),
+        (status = NOT_FOUND, description = "No Hub with that UUID is registered"),
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the Hub to drain."),
+        ("deregister" = Option<bool>, Query, description = "Deregister the Hub once its last session finishes."),
+    )
+)]
+async fn start_hub_drain(
+    uuid: Uuid,
+    options: DrainOptions,
+    state: Arc<HubRouterState>,
+    sessions: Arc<RoutingPrecedentMap>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(mut hub) = state.hubs.get_mut(&uuid) {
+        hub.meta.draining = true;
+        hub.meta.deregister_when_drained = options.deregister;
+        info!("Draining hub {}", hub.meta.name);
+    }
+
+    if let Err(e) = state.persist() {
+        warn!("Unable to persist draining hub: {}", e);
+    }
+
+    let reply = drain_status_reply(uuid, &state, &sessions);
+    deregister_drained_hubs(&state, &sessions);
+    Ok(reply)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/hubs/{uuid}/drain",
+    responses(
+        (status = 200, description = "The Hub is accepting new sessions again", body = DrainStatus),
+        (status = NOT_FOUND, description = "No Hub with that UUID is registered"),
+    ),
+    params(
+        ("uuid" = Uuid, Path, description = "UUID of the Hub to stop draining."),
+    )
+)]
+async fn stop_hub_drain(
+    uuid: Uuid,
+    state: Arc<HubRouterState>,
+    sessions: Arc<RoutingPrecedentMap>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(mut hub) = state.hubs.get_mut(&uuid) {
+        hub.meta.draining = false;
+        hub.meta.deregister_when_drained = false;
+        info!("Hub {} is no longer draining", hub.meta.name);
+    }
+    state.new_session_queue.notify_slot_freed();
+
+    if let Err(e) = state.persist() {
+        warn!("Unable to persist hub which stopped draining: {}", e);
+    }
+
+    Ok(drain_status_reply(uuid, &state, &sessions))
 }
 
 #[utoipa::path(
@@ -270,10 +726,10 @@ async fn delete_hub(
         ));
     }
 
-    return Ok(warp::reply::with_status(
-        format!("deleted hub successfully"),
+    Ok(warp::reply::with_status(
+        "deleted hub successfully".to_string(),
         StatusCode::CREATED,
-    ));
+    ))
 }
 
 #[utoipa::path(
@@ -285,17 +741,271 @@ async fn delete_hub(
     params()
 )]
 async fn get_sessions(
-    sessions: Arc<DashMap<String, RoutingDecision>>,
+    state: Arc<HubRouterState>,
+    sessions: Arc<RoutingPrecedentMap>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let mut sess: Vec<Session> = vec![];
 
     for s in sessions.iter() {
-        sess.push(Session::new(s.key(), &s.value().hub_endpoint));
+        sess.push(session_details(s.key(), s.value(), &state, &sessions));
     }
 
     Ok(warp::reply::json(&sess))
 }
 
+fn session_details(
+    session_id: &str,
+    decision: &RoutingDecision,
+    state: &HubRouterState,
+    sessions: &RoutingPrecedentMap,
+) -> Session {
+    Session::new(
+        session_id,
+        decision,
+        state.hubs.get(&decision.hub_uuid).map(|h| h.meta.name.clone()),
+        sessions.command_count(session_id),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sessions/{id}",
+    responses(
+        (status = 200, description = "Returned the session's hub, browser and activity"),
+        (status = NOT_FOUND, description = "No hub is running a session with that ID"),
+    ),
+    params(
+        ("id" = String, Path, description = "ID of the session."),
+    )
+)]
+async fn get_session(
+    session_id: String,
+    state: Arc<HubRouterState>,
+    sessions: Arc<RoutingPrecedentMap>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match sessions.get(&session_id) {
+        Some(decision) => Ok(warp::reply::with_status(
+            warp::reply::json(&session_details(&session_id, &decision, &state, &sessions)),
+            StatusCode::OK,
+        )),
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&format!("no session with id {}", session_id)),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/sessions/{id}",
+    responses(
+        (status = 200, description = "Closed the session on its hub, and forgot it"),
+        (status = NOT_FOUND, description = "No hub is running a session with that ID"),
+        (status = BAD_GATEWAY, description = "Forgot the session, but its hub could not be told to close it"),
+    ),
+    params(
+        ("id" = String, Path, description = "ID of the session to close."),
+    )
+)]
+async fn delete_session(
+    session_id: String,
+    state: Arc<HubRouterState>,
+    sessions: Arc<RoutingPrecedentMap>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if sessions.get(&session_id).is_none() {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&format!("no session with id {}", session_id)),
+            StatusCode::NOT_FOUND,
+        ));
+    }
+
+    info!("Closing session {} through the API", session_id);
+    match close_session(
+        &session_id,
+        SessionEvent::Deleted,
+        "closed through the API",
+        &sessions,
+        &state,
+    )
+    .await
+    {
+        Ok(()) => Ok(warp::reply::with_status(
+            warp::reply::json(&format!("closed session {}", session_id)),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            warn!("Forgot session {}, but couldn't close it on its hub: {}", session_id, e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&format!(
+                    "forgot session {}, but couldn't close it on its hub: {}",
+                    session_id, e
+                )),
+                StatusCode::BAD_GATEWAY,
+            ))
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sessions/history",
+    responses(
+        (status = 200, description = "Returned the most recent session lifecycle records matching the filters, oldest first"),
+    ),
+    params(
+        ("hub" = Option<String>, Query, description = "Name or UUID of the hub the session was routed to."),
+        ("browser" = Option<String>, Query, description = "Browser name, ignoring case."),
+        ("since" = Option<u64>, Query, description = "Earliest time, in seconds since the Unix epoch."),
+        ("until" = Option<u64>, Query, description = "Latest time, in seconds since the Unix epoch."),
+        ("event" = Option<SessionEvent>, Query, description = "Only records of this event, such as created, failed or reaped."),
+        ("session_id" = Option<String>, Query, description = "Only records about this session."),
+        ("request_id" = Option<String>, Query, description = "Only records about this new session request."),
+        ("client" = Option<String>, Query, description = "Only records about sessions requested by this proxy client."),
+        ("limit" = Option<usize>, Query, description = "The most records to return, 100 by default."),
+    )
+)]
+async fn get_session_history(query: HistoryQuery) -> Result<impl warp::Reply, warp::Rejection> {
+    // The history may span several large files, so don't read them on the runtime's threads
+    match tokio::task::spawn_blocking(move || AUDIT_LOG.history(&query)).await {
+        Ok(records) => Ok(warp::reply::with_status(
+            warp::reply::json(&records),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&format!("unable to read session history: {}", e)),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+/// Serve all Hub Router metrics in the Prometheus text exposition format.
+async fn serve_metrics(
+    state: Arc<HubRouterState>,
+    sessions: Arc<RoutingPrecedentMap>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::with_header(
+        METRICS.render(&state, &sessions),
+        "Content-Type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+/// Serve the whole session table, for a replica which has just started.
+async fn get_peer_sessions(
+    sessions: Arc<RoutingPrecedentMap>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let peer_sessions: Vec<PeerSession> = sessions
+        .iter()
+        .map(|s| PeerSession {
+            session_id: s.key().clone(),
+            decision: s.value().clone(),
+        })
+        .collect();
+
+    Ok(warp::reply::json(&peer_sessions))
+}
+
+/// Serve our routing decision for a single session, for a replica which was sent a command for it.
+async fn get_peer_session(
+    session_id: String,
+    sessions: Arc<RoutingPrecedentMap>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match sessions.get(&session_id) {
+        Some(decision) => Ok(warp::reply::with_status(
+            warp::reply::json(&decision),
+            StatusCode::OK,
+        )),
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&"session not found"),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+/// Accept a routing decision made by another replica.
+async fn put_peer_session(
+    session_id: String,
+    decision: RoutingDecision,
+    sessions: Arc<RoutingPrecedentMap>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    sessions.insert_replica(session_id, decision);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Forget a session which another replica has deleted or reaped.
+async fn delete_peer_session(
+    session_id: String,
+    sessions: Arc<RoutingPrecedentMap>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    sessions.remove_replica(&session_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/queue",
+    responses(
+        (status = 200, description = "Returned the number of new session requests waiting for a free slot"),
+    ),
+    params()
+)]
+async fn get_queue(state: Arc<HubRouterState>) -> Result<impl warp::Reply, warp::Rejection> {
+    let depths = state.new_session_queue.depths();
+    Ok(warp::reply::json(&QueueStatus {
+        total: depths.iter().map(|d| d.depth).sum(),
+        queues: depths,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/clients",
+    responses(
+        (status = 200, description = "Returned each proxy client's sessions and quota", body = [ClientUsageStatus]),
+    ),
+    params()
+)]
+async fn get_clients(
+    state: Arc<HubRouterState>,
+    sessions: Arc<RoutingPrecedentMap>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let config = match state.configs.read() {
+        Ok(conf) => conf.proxy_clients.clone(),
+        Err(e) => {
+            warn!("RwLock was poisoned reading proxy clients config: {}", e);
+            HubRouterPrimitiveConfigs::default().proxy_clients
+        }
+    };
+    Ok(warp::reply::json(
+        &state.client_usage.status(&config, &sessions),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/pool",
+    responses(
+        (status = 200, description = "Returned the outbound connection pool settings, and each host's connection and request counts", body = OutboundPoolStatus),
+    ),
+    params()
+)]
+async fn get_pool(state: Arc<HubRouterState>) -> Result<impl warp::Reply, warp::Rejection> {
+    let config = match state.configs.read() {
+        Ok(conf) => conf.outbound_pool.clone(),
+        Err(e) => {
+            warn!("RwLock was poisoned reading outbound pool config: {}", e);
+            HubRouterPrimitiveConfigs::default().outbound_pool
+        }
+    };
+    Ok(warp::reply::json(&pool_status(config)))
+}
+
+#[derive(Serialize)]
+struct QueueStatus {
+    total: usize,
+    queues: Vec<QueueDepth>,
+}
+
 async fn serve_ui(tail: Tail) -> Result<impl warp::Reply, warp::Rejection> {
     let path = if tail.as_str() == "" {
         "index.html"
@@ -333,7 +1043,17 @@ async fn set_config(
 ) -> Result<impl warp::Reply, warp::Rejection> {
     if matches!(
         key.as_str(),
-        "healthcheck_interval" | "reaper_interval" | "reaper_max_duration" | "healthcheck_timeout"
+        "healthcheck_interval"
+            | "reaper_interval"
+            | "reaper_max_duration"
+            | "reaper_max_idle"
+            | "healthcheck_timeout"
+            | "new_session_queue_size"
+            | "new_session_wait_timeout"
+            | "new_session_max_attempts"
+            | "new_session_retry_deadline"
+            | "new_session_timeout"
+            | "command_timeout"
     ) {
         let res = if let Ok(mut conf) = state.configs.write() {
             match key.as_str() {
@@ -365,6 +1085,55 @@ async fn set_config(
                         StatusCode::OK,
                     ))
                 }
+                "reaper_max_idle" => {
+                    conf.reaper_thread_idle_max = value;
+                    Ok(warp::reply::with_status(
+                        "successfully set reaper max session idle time".into(),
+                        StatusCode::OK,
+                    ))
+                }
+                "new_session_queue_size" => {
+                    conf.new_session_queue_size = value;
+                    Ok(warp::reply::with_status(
+                        "successfully set new session queue size".into(),
+                        StatusCode::OK,
+                    ))
+                }
+                "new_session_wait_timeout" => {
+                    conf.new_session_wait_timeout = value;
+                    Ok(warp::reply::with_status(
+                        "successfully set new session wait timeout".into(),
+                        StatusCode::OK,
+                    ))
+                }
+                "new_session_max_attempts" => {
+                    conf.new_session_max_attempts = value;
+                    Ok(warp::reply::with_status(
+                        "successfully set new session max attempts".into(),
+                        StatusCode::OK,
+                    ))
+                }
+                "new_session_retry_deadline" => {
+                    conf.new_session_retry_deadline = value;
+                    Ok(warp::reply::with_status(
+                        "successfully set new session retry deadline".into(),
+                        StatusCode::OK,
+                    ))
+                }
+                "new_session_timeout" => {
+                    conf.proxy_timeouts.new_session = value;
+                    Ok(warp::reply::with_status(
+                        "successfully set new session timeout".into(),
+                        StatusCode::OK,
+                    ))
+                }
+                "command_timeout" => {
+                    conf.proxy_timeouts.command = value;
+                    Ok(warp::reply::with_status(
+                        "successfully set command timeout".into(),
+                        StatusCode::OK,
+                    ))
+                }
                 _ => Ok(warp::reply::with_status(
                     "invalid config parameter to set".into(),
                     StatusCode::NOT_ACCEPTABLE,
@@ -377,20 +1146,68 @@ async fn set_config(
             ))
         };
 
+        // Let the background tasks and listeners pick up their new settings
+        state.notify_config_changed();
+
         if let Err(e) = state.persist() {
-            return Ok(warp::reply::with_status(
+            Ok(warp::reply::with_status(
                 format!("Unable to persist new hub: {}", e),
                 StatusCode::INTERNAL_SERVER_ERROR,
-            ));
+            ))
         } else {
             res
         }
     } else {
-        return Ok(warp::reply::with_status(
+        Ok(warp::reply::with_status(
             "invalid config parameter to set".into(),
             StatusCode::NOT_ACCEPTABLE,
+        ))
+    }
+}
+
+#[utoipa::path(post,
+    path = "/api/config/routing_strategy/{strategy}",
+    responses(
+        (status = 200, description = "Set the routing strategy."),
+        (status = NOT_ACCEPTABLE, description = "Unknown routing strategy."),
+    ),
+    params(
+        ("strategy" = String, Path, description = "One of weighted_random, least_loaded, round_robin, power_of_two_choices or priority."),
+    )
+)]
+async fn set_routing_strategy(
+    strategy: String,
+    state: Arc<HubRouterState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let strategy = match RoutingStrategyKind::from_str(&strategy) {
+        Ok(strategy) => strategy,
+        Err(e) => return Ok(warp::reply::with_status(e, StatusCode::NOT_ACCEPTABLE)),
+    };
+
+    let res = match state.configs.write() {
+        Ok(mut conf) => {
+            conf.routing_strategy = strategy;
+            Ok(warp::reply::with_status(
+                format!("successfully set routing strategy to {}", strategy),
+                StatusCode::OK,
+            ))
+        }
+        Err(e) => Ok(warp::reply::with_status(
+            format!("unable to acquire write lock for configs: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    };
+
+    state.notify_config_changed();
+
+    if let Err(e) = state.persist() {
+        return Ok(warp::reply::with_status(
+            format!("Unable to persist configuration changes: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
         ));
     }
+
+    res
 }
 
 #[utoipa::path(get, 
@@ -408,7 +1225,18 @@ async fn get_config(
 ) -> Result<impl warp::Reply, warp::Rejection> {
     if matches!(
         key.as_str(),
-        "healthcheck_interval" | "reaper_interval" | "reaper_max_duration" | "healthcheck_timeout"
+        "healthcheck_interval"
+            | "reaper_interval"
+            | "reaper_max_duration"
+            | "reaper_max_idle"
+            | "healthcheck_timeout"
+            | "new_session_queue_size"
+            | "new_session_wait_timeout"
+            | "new_session_max_attempts"
+            | "new_session_retry_deadline"
+            | "new_session_timeout"
+            | "command_timeout"
+            | "routing_strategy"
     ) {
         let res = if let Ok(conf) = state.configs.read() {
             match key.as_str() {
@@ -428,6 +1256,38 @@ async fn get_config(
                     conf.reaper_thread_duration_max.to_string(),
                     StatusCode::OK,
                 )),
+                "reaper_max_idle" => Ok(warp::reply::with_status(
+                    conf.reaper_thread_idle_max.to_string(),
+                    StatusCode::OK,
+                )),
+                "new_session_queue_size" => Ok(warp::reply::with_status(
+                    conf.new_session_queue_size.to_string(),
+                    StatusCode::OK,
+                )),
+                "new_session_wait_timeout" => Ok(warp::reply::with_status(
+                    conf.new_session_wait_timeout.to_string(),
+                    StatusCode::OK,
+                )),
+                "new_session_max_attempts" => Ok(warp::reply::with_status(
+                    conf.new_session_max_attempts.to_string(),
+                    StatusCode::OK,
+                )),
+                "new_session_retry_deadline" => Ok(warp::reply::with_status(
+                    conf.new_session_retry_deadline.to_string(),
+                    StatusCode::OK,
+                )),
+                "new_session_timeout" => Ok(warp::reply::with_status(
+                    conf.proxy_timeouts.new_session.to_string(),
+                    StatusCode::OK,
+                )),
+                "command_timeout" => Ok(warp::reply::with_status(
+                    conf.proxy_timeouts.command.to_string(),
+                    StatusCode::OK,
+                )),
+                "routing_strategy" => Ok(warp::reply::with_status(
+                    conf.routing_strategy.to_string(),
+                    StatusCode::OK,
+                )),
                 _ => Ok(warp::reply::with_status(
                     "invalid config parameter to set".into(),
                     StatusCode::NOT_ACCEPTABLE,
@@ -441,18 +1301,18 @@ async fn get_config(
         };
 
         if let Err(e) = state.persist() {
-            return Ok(warp::reply::with_status(
+            Ok(warp::reply::with_status(
                 format!("Unable to persist new hub: {}", e),
                 StatusCode::INTERNAL_SERVER_ERROR,
-            ));
+            ))
         } else {
             res
         }
     } else {
-        return Ok(warp::reply::with_status(
+        Ok(warp::reply::with_status(
             "invalid config parameter to set".into(),
             StatusCode::NOT_ACCEPTABLE,
-        ));
+        ))
     }
 }
 
@@ -468,7 +1328,11 @@ async fn get_entire_config(
 ) -> Result<impl warp::Reply, warp::Rejection> {
     match state.configs.read() {
         Ok(conf) => Ok(warp::reply::with_status(
-            warp::reply::json(&*conf),
+            warp::reply::json(&HubRouterPrimitiveConfigs {
+                api_auth: conf.api_auth.redacted(),
+                proxy_clients: conf.proxy_clients.redacted(),
+                ..conf.clone()
+            }),
             StatusCode::OK,
         )),
         Err(e) => Ok(warp::reply::with_status(
@@ -488,11 +1352,14 @@ async fn get_entire_config(
     )
 )]
 async fn set_entire_config(
-    config: HubRouterPrimitiveConfigs,
+    mut config: HubRouterPrimitiveConfigs,
     state: Arc<HubRouterState>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let res = match state.configs.write() {
         Ok(mut conf) => {
+            // Secrets are redacted when the config is read, so keep the current ones
+            config.api_auth.restore_redacted(&conf.api_auth);
+            config.proxy_clients.restore_redacted(&conf.proxy_clients);
             *conf = config;
             Ok(warp::reply::with_status("ok".into(), StatusCode::OK))
         }
@@ -502,6 +1369,8 @@ async fn set_entire_config(
         )),
     };
 
+    state.notify_config_changed();
+
     if let Err(e) = state.persist() {
         return Ok(warp::reply::with_status(
             format!("Unable to persist configuration changes: {}", e),
@@ -524,21 +1393,21 @@ async fn get_logs() -> Result<impl warp::Reply, warp::Rejection> {
             let serialized = serde_json::to_string_pretty(&*store);
             match serialized {
                 Ok(string) => {
-                    return Ok(warp::reply::with_status(string, StatusCode::OK));
+                    Ok(warp::reply::with_status(string, StatusCode::OK))
                 }
                 Err(e) => {
-                    return Ok(warp::reply::with_status(
+                    Ok(warp::reply::with_status(
                         format!("Error serializing logs: {}", e),
                         StatusCode::INTERNAL_SERVER_ERROR,
-                    ));
+                    ))
                 }
             }
         }
         Err(e) => {
-            return Ok(warp::reply::with_status(
+            Ok(warp::reply::with_status(
                 format!("Unable to acquire read lock for logs: {}", e),
                 StatusCode::INTERNAL_SERVER_ERROR,
-            ));
+            ))
         }
     }
 }
@@ -558,19 +1427,19 @@ where
     T: ToString,
 {
     fn from(value: T) -> Self {
-        return AggregatedError {
+        AggregatedError {
             error: value.to_string(),
-        };
+        }
     }
 }
 
 async fn make_single_aggregate_request(
+    client: HttpClient,
     req: Request<Bytes>,
 ) -> Result<AggregatedResponse, AggregatedError> {
     let (parts, body_bytes) = req.into_parts();
     let uri_path = parts.uri.clone();
     let built_request = hyper::Request::from_parts(parts, hyper::Body::from(body_bytes));
-    let client = Client::new();
     let response_future = client.request(built_request);
     let response = match timeout(Duration::from_secs(2), response_future).await {
         Ok(body) => body?,
@@ -587,12 +1456,12 @@ async fn make_single_aggregate_request(
 }
 
 async fn aggregate_request(
-    reqs: Vec<Request<Bytes>>,
+    reqs: Vec<(HttpClient, Request<Bytes>)>,
 ) -> Vec<Result<AggregatedResponse, AggregatedError>> {
     let mut join_set: JoinSet<Result<AggregatedResponse, AggregatedError>> = JoinSet::new();
 
-    for request in reqs {
-        join_set.spawn(async move { make_single_aggregate_request(request).await });
+    for (client, request) in reqs {
+        join_set.spawn(async move { make_single_aggregate_request(client, request).await });
     }
 
     let mut serialized_json_responses: Vec<Result<AggregatedResponse, AggregatedError>> = vec![];
@@ -608,7 +1477,7 @@ async fn aggregate_request(
         }
     }
 
-    return serialized_json_responses;
+    serialized_json_responses
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -626,16 +1495,18 @@ async fn aggregate_status_responses(
         .map(|h| {
             let uri = Uri::from_str(
                 {
-                    let mut status_endpoint = h.meta.url.clone();
+                    let mut status_endpoint = without_credentials(&h.meta.url);
                     status_endpoint.set_path("/status");
                     status_endpoint
                 }
                 .as_str(),
             )?;
-            return Request::builder()
+            let mut request = Request::builder()
                 .method("GET")
                 .uri(uri)
-                .body(hyper::body::Bytes::default());
+                .body(hyper::body::Bytes::default())?;
+            apply_hub_auth(request.headers_mut(), &h.meta);
+            Ok::<_, hyper::http::Error>(request)
         })
         .collect();
 
@@ -655,9 +1526,10 @@ async fn aggregate_status_responses(
     let mut req_join_set: JoinSet<APIHubsStatusResponse> = JoinSet::new();
 
     for (req, hub) in validated_requests.into_iter().zip(state.hubs.iter()) {
-        let cloned_hub = hub.clone();
+        let cloned_hub = hub.redacted();
+        let client = state.clients.for_hub(hub.meta.insecure_skip_verify);
         req_join_set.spawn(async move {
-            let response = make_single_aggregate_request(req).await;
+            let response = make_single_aggregate_request(client, req).await;
             APIHubsStatusResponse {
                 hub_status_response: response,
                 router_hub_state: cloned_hub,
@@ -705,24 +1577,28 @@ async fn aggregate_graphql_responses(
         .map(|h| {
             let uri = Uri::from_str(
                 {
-                    let mut status_endpoint = h.meta.url.clone();
+                    let mut status_endpoint = without_credentials(&h.meta.url);
                     status_endpoint.set_path("/graphql");
                     status_endpoint
                 }
                 .as_str(),
             )?;
-            return Request::builder()
+            let mut request = Request::builder()
                 .method("POST")
                 .header("Content-Type", "application/json")
                 .uri(uri)
-                .body(graphql_request.clone());
+                .body(graphql_request.clone())?;
+            apply_hub_auth(request.headers_mut(), &h.meta);
+            Ok::<_, hyper::http::Error>(request)
         })
         .collect();
 
-    let mut validated_requests: Vec<Request<Bytes>> = vec![];
-    for req in unvalidated_requests {
+    let mut validated_requests: Vec<(HttpClient, Request<Bytes>)> = vec![];
+    for (req, hub) in unvalidated_requests.into_iter().zip(state.hubs.iter()) {
         match req {
-            Ok(request) => validated_requests.push(request),
+            Ok(request) => {
+                validated_requests.push((state.clients.for_hub(hub.meta.insecure_skip_verify), request))
+            }
             Err(err) => {
                 return Ok(warp::reply::with_status(
                     warp::reply::html(format!("Error building request: {}", err)),
@@ -747,3 +1623,97 @@ async fn aggregate_graphql_responses(
         )),
     }
 }
+
+#[tokio::test]
+async fn test_update_hub() {
+    use std::fs;
+
+    let directory = std::env::temp_dir().join(format!("hub_router_api_{}", Uuid::new_v4()));
+    fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("config.json").to_string_lossy().to_string();
+    let state = Arc::new(HubRouterState::new_persisting_to(&path));
+    let routes = api_routes(state.clone(), Arc::default(), &ApiAuthConfig::default(), &[]);
+    let hub = Hub::new(Url::parse("http://grid-a:4444/").unwrap());
+    let uuid = hub.meta.uuid;
+    state.hubs.insert(uuid, hub);
+    let meta = || state.hubs.get(&uuid).unwrap().meta.clone();
+    let patch = |uuid: Uuid, body: serde_json::Value| {
+        warp::test::request()
+            .method("PATCH")
+            .path(&format!("/api/hubs/{}", uuid))
+            .json(&body)
+            .reply(&routes)
+    };
+
+    let response = patch(uuid, serde_json::json!({"weight": 7, "max_concurrent_sessions": 3, "enabled": false})).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let updated = meta();
+    assert_eq!(
+        (updated.weight, updated.priority, updated.max_concurrent_sessions, updated.enabled),
+        (7, 0, Some(3), false)
+    );
+    assert!(fs::read_to_string(&path).unwrap().contains("\"weight\": 7"));
+
+    // Settings which are left out keep their value, and a zero cap removes it
+    patch(uuid, serde_json::json!({"max_concurrent_sessions": 0, "priority": 2})).await;
+    let updated = meta();
+    assert_eq!(
+        (updated.weight, updated.priority, updated.max_concurrent_sessions),
+        (7, 2, None)
+    );
+
+    // A hub read back from the API keeps its secret when it's sent back redacted
+    patch(uuid, serde_json::json!({"auth": {"type": "bearer", "token": {"value": "hunter2"}}})).await;
+    let redacted = serde_json::to_value(meta().auth.unwrap().redacted()).unwrap();
+    patch(uuid, serde_json::json!({"auth": redacted, "weight": 3})).await;
+    let updated = meta();
+    assert_eq!(updated.weight, 3);
+    assert_eq!(
+        updated.auth,
+        Some(HubAuth::Bearer { token: Secret::Value("hunter2".to_string()) })
+    );
+
+    let missing = patch(Uuid::new_v4(), serde_json::json!({"weight": 1})).await;
+    assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+
+    let _ = fs::remove_dir_all(&directory);
+}
+
+#[tokio::test]
+async fn test_drained_hub_is_deregistered() {
+    use std::{fs, time::SystemTime};
+
+    let directory = std::env::temp_dir().join(format!("hub_router_api_{}", Uuid::new_v4()));
+    fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("config.json").to_string_lossy().to_string();
+    let state = Arc::new(HubRouterState::new_persisting_to(&path));
+    let sessions = Arc::new(RoutingPrecedentMap::default());
+    let routes = api_routes(state.clone(), sessions.clone(), &ApiAuthConfig::default(), &[]);
+
+    // Nothing listens on this port, so closing sessions on the hub fails straight away
+    let url = Url::parse("http://127.0.0.1:9/").unwrap();
+    let hub = Hub::new(url.clone());
+    let uuid = hub.meta.uuid;
+    state.hubs.insert(uuid, hub);
+    sessions.insert("running".to_string(), RoutingDecision::new(uuid, url, SystemTime::now()));
+    let meta = || state.hubs.get(&uuid).map(|hub| hub.meta.clone());
+
+    let drain = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/hubs/{}/drain?deregister=true", uuid))
+        .reply(&routes)
+        .await;
+    let status: DrainStatus = serde_json::from_slice(drain.body()).unwrap();
+    assert!(status.draining && status.deregister_when_drained);
+    assert_eq!(status.session_ids, vec!["running"]);
+    assert!(meta().is_some_and(|meta| !meta.accepts_new_sessions()));
+
+    // The hub is removed once its last session finishes, even if the hub couldn't be told
+    assert!(close_session("running", SessionEvent::Reaped, "test", &sessions, &state)
+        .await
+        .is_err());
+    assert!(meta().is_none());
+    assert!(!fs::read_to_string(&path).unwrap().contains(&uuid.to_string()));
+
+    let _ = fs::remove_dir_all(&directory);
+}
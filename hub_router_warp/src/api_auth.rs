@@ -0,0 +1,465 @@
+//! Authentication and authorization for the management API. Requests are
+//! authenticated by a chain of authenticators (static bearer tokens, and HTTP
+//! basic auth against an htpasswd file), each of which grants a role.
+//! Read-only users can see everything, while changing hubs or configuration
+//! needs the admin role. When no authenticator is configured, the API is open,
+//! as it was before authentication existed.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fs,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use base64::Engine;
+use hyper::StatusCode;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use url::Url;
+use utoipa::ToSchema;
+use warp::{reject::Reject, Filter, Rejection, Reply};
+
+use crate::credentials::Secret;
+
+/// What an authenticated user is allowed to do. Admins can do everything
+/// read-only users can.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiRole {
+    #[default]
+    ReadOnly,
+    Admin,
+}
+
+/// A static token, sent as `Authorization: Bearer <token>`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ApiToken {
+    /// Identifies the token, e.g. the team or system it was issued to.
+    pub name: String,
+    pub token: Secret,
+    #[serde(default)]
+    pub role: ApiRole,
+}
+
+/// How API requests are authenticated. With no tokens and no htpasswd file,
+/// authentication is disabled.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+#[serde(default)]
+pub struct ApiAuthConfig {
+    /// Static bearer tokens, each with their own role.
+    pub tokens: Vec<ApiToken>,
+
+    /// An htpasswd file of users allowed to sign in with HTTP basic auth.
+    /// Only bcrypt (`htpasswd -B`) and SHA-1 (`htpasswd -s`) hashes are supported.
+    pub htpasswd_path: Option<String>,
+
+    /// Users from the htpasswd file who are admins. Everyone else is read-only.
+    pub admin_users: Vec<String>,
+
+    /// The role of requests without credentials, when authentication is enabled.
+    /// By default they are refused.
+    pub anonymous_role: Option<ApiRole>,
+
+    /// Token sent to peer replicas, which must be an admin token on each of them.
+    pub peer_token: Option<Secret>,
+}
+
+impl ApiAuthConfig {
+    /// A copy which is safe to return from the API, with inline secrets hidden.
+    pub fn redacted(&self) -> Self {
+        Self {
+            tokens: self
+                .tokens
+                .iter()
+                .map(|t| ApiToken {
+                    token: t.token.redacted(),
+                    ..t.clone()
+                })
+                .collect(),
+            peer_token: self.peer_token.as_ref().map(Secret::redacted),
+            ..self.clone()
+        }
+    }
+
+    /// Put back secrets which are still redacted, as when a configuration
+    /// read from the API is sent back with other changes.
+    pub fn restore_redacted(&mut self, current: &ApiAuthConfig) {
+        for token in self.tokens.iter_mut().filter(|t| t.token.is_redacted()) {
+            if let Some(existing) = current.tokens.iter().find(|t| t.name == token.name) {
+                token.token = existing.token.clone();
+            }
+        }
+        if self.peer_token.as_ref().is_some_and(Secret::is_redacted) {
+            self.peer_token = current.peer_token.clone();
+        }
+    }
+}
+
+/// Credentials presented in an `Authorization` header.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ApiCredentials {
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+impl ApiCredentials {
+    fn parse(header: &str) -> Option<Self> {
+        let (scheme, value) = header.trim().split_once(' ')?;
+        if scheme.eq_ignore_ascii_case("bearer") {
+            Some(ApiCredentials::Bearer(value.trim().to_string()))
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = base64::engine::general_purpose::STANDARD
+                .decode(value.trim())
+                .ok()?;
+            let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+            Some(ApiCredentials::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// One way of checking credentials.
+pub trait ApiAuthenticator: Send + Sync {
+    /// The role granted to the credentials, or `None` if they aren't recognised.
+    fn authenticate(&self, credentials: &ApiCredentials) -> Option<ApiRole>;
+}
+
+/// Checks bearer tokens against the configured list.
+pub struct TokenAuthenticator {
+    tokens: Vec<ApiToken>,
+}
+
+impl ApiAuthenticator for TokenAuthenticator {
+    fn authenticate(&self, credentials: &ApiCredentials) -> Option<ApiRole> {
+        let presented = match credentials {
+            ApiCredentials::Bearer(token) => token,
+            _ => return None,
+        };
+
+        self.tokens
+            .iter()
+            .filter(|t| match t.token.resolve() {
+                Ok(token) => constant_time_eq(token.as_bytes(), presented.as_bytes()),
+                Err(e) => {
+                    warn!("Unable to read API token {}: {}", t.name, e);
+                    false
+                }
+            })
+            .map(|t| t.role)
+            .max()
+    }
+}
+
+/// Users and password hashes from an htpasswd file, along with a digest of each
+/// user's credentials once they've been checked, since bcrypt is deliberately slow.
+#[derive(Default)]
+struct HtpasswdCache {
+    modified: Option<SystemTime>,
+    hashes: HashMap<String, String>,
+    verified: HashMap<String, Vec<u8>>,
+}
+
+/// A fast digest of a user's credentials, so that checked passwords needn't be kept.
+fn credentials_digest(username: &str, password: &str) -> Vec<u8> {
+    Sha256::digest(format!("{}:{}", username, password)).to_vec()
+}
+
+/// Checks basic auth credentials against an htpasswd file, which is reread when it changes.
+pub struct HtpasswdAuthenticator {
+    path: String,
+    admin_users: Vec<String>,
+    cache: Mutex<HtpasswdCache>,
+}
+
+impl HtpasswdAuthenticator {
+    pub fn new(path: &str, admin_users: &[String]) -> Self {
+        Self {
+            path: path.to_string(),
+            admin_users: admin_users.to_vec(),
+            cache: Mutex::new(HtpasswdCache::default()),
+        }
+    }
+
+    fn reload_if_changed(&self, cache: &mut HtpasswdCache) {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified == cache.modified {
+            return;
+        }
+
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("Unable to read htpasswd file {}: {}", self.path, e);
+                String::new()
+            }
+        };
+        *cache = HtpasswdCache {
+            modified,
+            hashes: contents
+                .lines()
+                .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+                .filter_map(|line| line.split_once(':'))
+                .map(|(user, hash)| (user.to_string(), hash.trim().to_string()))
+                .collect(),
+            verified: HashMap::new(),
+        };
+    }
+}
+
+impl ApiAuthenticator for HtpasswdAuthenticator {
+    fn authenticate(&self, credentials: &ApiCredentials) -> Option<ApiRole> {
+        let (username, password) = match credentials {
+            ApiCredentials::Basic { username, password } => (username, password),
+            _ => return None,
+        };
+
+        let mut cache = match self.cache.lock() {
+            Ok(cache) => cache,
+            Err(e) => e.into_inner(),
+        };
+        self.reload_if_changed(&mut cache);
+
+        let digest = credentials_digest(username, password);
+        let verified = cache
+            .verified
+            .get(username)
+            .is_some_and(|verified| constant_time_eq(verified, &digest))
+            || match cache.hashes.get(username) {
+                Some(hash) => verify_htpasswd_hash(hash, password),
+                None => false,
+            };
+        if !verified {
+            return None;
+        }
+        cache.verified.insert(username.clone(), digest);
+
+        if self.admin_users.contains(username) {
+            Some(ApiRole::Admin)
+        } else {
+            Some(ApiRole::ReadOnly)
+        }
+    }
+}
+
+fn verify_htpasswd_hash(hash: &str, password: &str) -> bool {
+    if let Some(sha) = hash.strip_prefix("{SHA}") {
+        let digest = base64::engine::general_purpose::STANDARD.encode(Sha1::digest(password));
+        constant_time_eq(digest.as_bytes(), sha.as_bytes())
+    } else if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else {
+        warn!("Unsupported htpasswd hash format, only bcrypt and SHA-1 are supported");
+        false
+    }
+}
+
+/// Compare secrets in time which doesn't depend on where they differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Why a request was turned away.
+#[derive(Debug)]
+pub enum ApiAuthRejection {
+    /// No credentials, or credentials which weren't recognised.
+    Unauthenticated,
+    /// Valid credentials, for a role which isn't allowed to make the request.
+    Forbidden(ApiRole),
+}
+
+impl Reject for ApiAuthRejection {}
+
+/// The chain of authenticators built from configuration.
+pub struct ApiAuth {
+    authenticators: Vec<Box<dyn ApiAuthenticator>>,
+    anonymous_role: Option<ApiRole>,
+}
+
+impl ApiAuth {
+    pub fn from_config(config: &ApiAuthConfig) -> Self {
+        let mut authenticators: Vec<Box<dyn ApiAuthenticator>> = vec![];
+        if !config.tokens.is_empty() {
+            authenticators.push(Box::new(TokenAuthenticator {
+                tokens: config.tokens.clone(),
+            }));
+        }
+        if let Some(path) = &config.htpasswd_path {
+            authenticators.push(Box::new(HtpasswdAuthenticator::new(path, &config.admin_users)));
+        }
+
+        Self {
+            authenticators,
+            anonymous_role: config.anonymous_role,
+        }
+    }
+
+    /// Check that the `Authorization` header grants at least the required role.
+    pub fn authorize(&self, header: Option<&str>, required: ApiRole) -> Result<(), ApiAuthRejection> {
+        if self.authenticators.is_empty() {
+            return Ok(());
+        }
+
+        let role = match header {
+            Some(header) => ApiCredentials::parse(header).and_then(|credentials| {
+                self.authenticators
+                    .iter()
+                    .find_map(|a| a.authenticate(&credentials))
+            }),
+            None => self.anonymous_role,
+        };
+
+        match role {
+            Some(role) if role >= required => Ok(()),
+            Some(role) => Err(ApiAuthRejection::Forbidden(role)),
+            None => Err(ApiAuthRejection::Unauthenticated),
+        }
+    }
+}
+
+/// A filter which rejects requests that don't have at least the given role.
+pub fn require_role(
+    auth: Arc<ApiAuth>,
+    required: ApiRole,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let auth = auth.clone();
+            async move {
+                auth.authorize(header.as_deref(), required)
+                    .map_err(warp::reject::custom)
+            }
+        })
+        .untuple_one()
+}
+
+/// Turn authentication failures into 401 and 403 responses. Anything else
+/// didn't match a route, so is a 404.
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let response = match rejection.find::<ApiAuthRejection>() {
+        Some(ApiAuthRejection::Unauthenticated) => warp::reply::with_header(
+            warp::reply::with_status("authentication required".to_string(), StatusCode::UNAUTHORIZED),
+            "WWW-Authenticate",
+            "Basic realm=\"Hub Router\"",
+        )
+        .into_response(),
+        Some(ApiAuthRejection::Forbidden(role)) => warp::reply::with_status(
+            format!("the {:?} role may not make this request", role),
+            StatusCode::FORBIDDEN,
+        )
+        .into_response(),
+        None => warp::reply::with_status(warp::reply::reply(), StatusCode::NOT_FOUND)
+            .into_response(),
+    };
+    Ok(response)
+}
+
+/// Build the CORS policy from the configured origins, where `*` allows any origin.
+pub fn cors_policy(allowed_origins: &[String]) -> warp::cors::Builder {
+    let cors = warp::cors()
+        .allow_methods(vec!["GET", "POST", "PATCH", "DELETE", "OPTIONS"])
+        .allow_headers(vec!["authorization", "content-type"]);
+
+    if allowed_origins.iter().any(|origin| origin == "*") {
+        return cors.allow_any_origin();
+    }
+
+    // warp panics on malformed origins, so only pass along ones which parse
+    let origins: Vec<String> = allowed_origins
+        .iter()
+        .filter_map(|origin| match Url::parse(origin).map(|url| url.origin()) {
+            Ok(origin) if origin.is_tuple() => Some(origin.ascii_serialization()),
+            _ => {
+                warn!("Ignoring invalid CORS origin {}", origin);
+                None
+            }
+        })
+        .collect();
+    cors.allow_origins(origins.iter().map(String::as_str))
+}
+
+#[test]
+fn test_api_auth() {
+    let basic = |username: &str, password: &str| {
+        format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password))
+        )
+    };
+
+    let path = std::env::temp_dir().join(format!("hub_router_htpasswd_{}", uuid::Uuid::new_v4()));
+    fs::write(
+        &path,
+        format!(
+            "# comment\nalice:{}\nbob:{{SHA}}{}\n",
+            bcrypt::hash("wonderland", 4).unwrap(),
+            base64::engine::general_purpose::STANDARD.encode(Sha1::digest("builder"))
+        ),
+    )
+    .unwrap();
+
+    let auth = ApiAuth::from_config(&ApiAuthConfig {
+        tokens: vec![ApiToken {
+            name: "ci".into(),
+            token: Secret::Value("s3cret".into()),
+            role: ApiRole::Admin,
+        }],
+        htpasswd_path: Some(path.to_string_lossy().into()),
+        admin_users: vec!["alice".into()],
+        ..Default::default()
+    });
+
+    assert!(auth.authorize(Some("Bearer s3cret"), ApiRole::Admin).is_ok());
+    assert!(matches!(
+        auth.authorize(Some("Bearer wrong"), ApiRole::ReadOnly),
+        Err(ApiAuthRejection::Unauthenticated)
+    ));
+    assert!(matches!(
+        auth.authorize(None, ApiRole::ReadOnly),
+        Err(ApiAuthRejection::Unauthenticated)
+    ));
+
+    let alice = basic("alice", "wonderland");
+    assert!(auth.authorize(Some(&alice), ApiRole::Admin).is_ok());
+    // The second check is answered from the cache
+    assert!(auth.authorize(Some(&alice), ApiRole::Admin).is_ok());
+    assert!(auth
+        .authorize(Some(&basic("alice", "looking-glass")), ApiRole::ReadOnly)
+        .is_err());
+
+    let bob = basic("bob", "builder");
+    assert!(auth.authorize(Some(&bob), ApiRole::ReadOnly).is_ok());
+    assert!(matches!(
+        auth.authorize(Some(&bob), ApiRole::Admin),
+        Err(ApiAuthRejection::Forbidden(ApiRole::ReadOnly))
+    ));
+    let _ = fs::remove_file(&path);
+
+    // Without any authenticators the API stays open
+    assert!(ApiAuth::from_config(&ApiAuthConfig::default())
+        .authorize(None, ApiRole::Admin)
+        .is_ok());
+
+    // Redacted secrets sent back from the API keep their current value
+    let config = ApiAuthConfig {
+        tokens: vec![ApiToken {
+            name: "ci".into(),
+            token: Secret::Value("s3cret".into()),
+            role: ApiRole::Admin,
+        }],
+        ..Default::default()
+    };
+    let mut round_tripped = config.redacted();
+    assert_ne!(round_tripped, config);
+    round_tripped.restore_redacted(&config);
+    assert_eq!(round_tripped, config);
+}
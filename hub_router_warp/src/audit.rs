@@ -0,0 +1,581 @@
+//! A record of every session's lifecycle: the new session request, the hub
+//! each attempt was routed to, the capabilities the hub negotiated, and how
+//! the session ended. Records are appended as JSON lines to a local file,
+//! which is rotated once it grows too large, and can be queried through
+//! `/api/sessions/history` to find out where a test ran, or why it never started.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use crate::{routing::RoutingDecision, state::HubRouterState};
+
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+/// How many records may wait for the writer thread before new ones are dropped.
+const AUDIT_QUEUE_LENGTH: usize = 4096;
+
+lazy_static! {
+    pub static ref AUDIT_LOG: SessionAuditLog = SessionAuditLog::default();
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[serde(default)]
+pub struct AuditLogConfig {
+    /// File to append session records to. Null disables the audit log.
+    pub path: Option<String>,
+
+    /// Size at which the file is rotated, moving it to `<path>.1`, `<path>.1` to `<path>.2` and so on.
+    pub max_file_bytes: u64,
+
+    /// How many rotated files to keep besides the current one.
+    pub max_rotated_files: usize,
+}
+
+impl Default for AuditLogConfig {
+    fn default() -> Self {
+        Self {
+            path: Some("./session_history.jsonl".into()),
+            max_file_bytes: 10 * 1024 * 1024,
+            max_rotated_files: 5,
+        }
+    }
+}
+
+impl AuditLogConfig {
+    fn rotated_path(path: &str, index: usize) -> String {
+        format!("{}.{}", path, index)
+    }
+
+    /// Every file holding records, from oldest to newest.
+    fn files(&self) -> Vec<String> {
+        match &self.path {
+            Some(path) => (1..=self.max_rotated_files)
+                .rev()
+                .map(|i| Self::rotated_path(path, i))
+                .chain(std::iter::once(path.clone()))
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+/// Something that happened to a session, or to a request for one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionEvent {
+    /// A new session request was received.
+    Requested,
+    /// A new session request was sent to a hub.
+    Routed,
+    /// A hub failed to create a session, so the request may be retried elsewhere.
+    Rejected,
+    /// A hub created the session.
+    Created,
+    /// No session could be created for the request.
+    Failed,
+    /// The session was deleted by its client.
+    Deleted,
+    /// The session was forgotten by the reaper.
+    Reaped,
+    /// The session was forgotten because its hub no longer reported running it.
+    Evicted,
+    /// A session we hadn't routed was found running on a hub, and routed to it from then on.
+    Adopted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionAuditRecord {
+    pub time: SystemTime,
+    pub event: SessionEvent,
+
+    /// Ties together the records for one new session request, before it has a session ID.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hub_uuid: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hub_name: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub browser_name: Option<String>,
+
+    /// The proxy client which requested the session, if it identified itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+
+    /// The capabilities the client asked for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requested_capabilities: Option<Value>,
+
+    /// The capabilities the hub created the session with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Value>,
+
+    /// Why a request was rejected or failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl SessionAuditRecord {
+    pub fn new(event: SessionEvent) -> Self {
+        Self {
+            time: SystemTime::now(),
+            event,
+            request_id: None,
+            session_id: None,
+            hub_uuid: None,
+            hub_name: None,
+            browser_name: None,
+            client: None,
+            requested_capabilities: None,
+            capabilities: None,
+            message: None,
+        }
+    }
+
+    /// A record about a session which has already been routed.
+    pub fn for_session(
+        event: SessionEvent,
+        session_id: &str,
+        decision: &RoutingDecision,
+        state: &HubRouterState,
+    ) -> Self {
+        Self {
+            session_id: Some(session_id.to_string()),
+            hub_uuid: Some(decision.hub_uuid.to_string()),
+            hub_name: state.hubs.get(&decision.hub_uuid).map(|h| h.meta.name.clone()),
+            browser_name: decision.browser_name.clone(),
+            client: decision.client.clone(),
+            ..Self::new(event)
+        }
+    }
+}
+
+/// Filters for querying the session history. Times are in seconds since the Unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct HistoryQuery {
+    /// Name or UUID of the hub.
+    pub hub: Option<String>,
+    pub browser: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub event: Option<SessionEvent>,
+    pub session_id: Option<String>,
+    pub request_id: Option<String>,
+    pub client: Option<String>,
+    /// The most records to return, keeping the most recent. 100 by default.
+    pub limit: Option<usize>,
+}
+
+impl HistoryQuery {
+    fn matches(&self, record: &SessionAuditRecord) -> bool {
+        let secs = record
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let equal = |filter: &Option<String>, value: &Option<String>| {
+            filter.is_none() || filter == value
+        };
+
+        (equal(&self.hub, &record.hub_name) || equal(&self.hub, &record.hub_uuid))
+            && self.browser.as_ref().is_none_or(|browser| {
+                record
+                    .browser_name
+                    .as_ref()
+                    .is_some_and(|b| b.eq_ignore_ascii_case(browser))
+            })
+            && self.since.is_none_or(|since| secs >= since)
+            && self.until.is_none_or(|until| secs <= until)
+            && self.event.is_none_or(|event| record.event == event)
+            && equal(&self.session_id, &record.session_id)
+            && equal(&self.request_id, &record.request_id)
+            && equal(&self.client, &record.client)
+    }
+}
+
+struct AuditFile {
+    file: File,
+    size: u64,
+}
+
+/// What the writer thread is asked to do.
+enum AuditMessage {
+    Configure(AuditLogConfig),
+    Record(Vec<u8>),
+    Flush(SyncSender<()>),
+}
+
+/// Owns the audit file on the writer thread, so that no request waits on the disk.
+#[derive(Default)]
+struct AuditWriter {
+    config: Option<AuditLogConfig>,
+    file: Option<AuditFile>,
+}
+
+impl AuditWriter {
+    fn run(mut self, messages: Receiver<AuditMessage>) {
+        for message in messages {
+            match message {
+                AuditMessage::Configure(config) => {
+                    self.config = Some(config);
+                    self.file = None;
+                }
+                AuditMessage::Record(line) => {
+                    if let Err(e) = self.write(&line) {
+                        // Reopen the file next time, in case it was moved or deleted from under us
+                        self.file = None;
+                        warn!("{}", e);
+                    }
+                }
+                AuditMessage::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    fn open(path: &str) -> Result<AuditFile, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Unable to open session audit log {}: {}", path, e))?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or_default();
+        Ok(AuditFile { file, size })
+    }
+
+    fn rotate(config: &AuditLogConfig, path: &str) -> Result<(), String> {
+        if config.max_rotated_files == 0 {
+            return fs::remove_file(path)
+                .map_err(|e| format!("Unable to truncate session audit log {}: {}", path, e));
+        }
+
+        for index in (1..config.max_rotated_files).rev() {
+            let from = AuditLogConfig::rotated_path(path, index);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, AuditLogConfig::rotated_path(path, index + 1))
+                    .map_err(|e| format!("Unable to rotate session audit log {}: {}", from, e))?;
+            }
+        }
+        fs::rename(path, AuditLogConfig::rotated_path(path, 1))
+            .map_err(|e| format!("Unable to rotate session audit log {}: {}", path, e))
+    }
+
+    fn write(&mut self, line: &[u8]) -> Result<(), String> {
+        let config = match &self.config {
+            Some(config) => config.clone(),
+            None => return Ok(()),
+        };
+        let path = match &config.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let needs_rotation = match &self.file {
+            Some(current) => current.size > 0 && current.size + line.len() as u64 > config.max_file_bytes,
+            None => false,
+        };
+        if needs_rotation {
+            self.file = None;
+            Self::rotate(&config, path)?;
+        }
+
+        if self.file.is_none() {
+            self.file = Some(Self::open(path)?);
+        }
+        if let Some(current) = self.file.as_mut() {
+            current
+                .file
+                .write_all(line)
+                .map_err(|e| format!("Unable to write session audit log {}: {}", path, e))?;
+            current.size += line.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct AuditLogInner {
+    config: Option<AuditLogConfig>,
+    writer: Option<SyncSender<AuditMessage>>,
+}
+
+/// The session audit log. Nothing is recorded until it has been configured.
+/// Records are written by a thread of their own, and dropped with a warning
+/// if it falls too far behind.
+#[derive(Default)]
+pub struct SessionAuditLog {
+    inner: Mutex<AuditLogInner>,
+}
+
+impl SessionAuditLog {
+    fn lock(&self) -> MutexGuard<'_, AuditLogInner> {
+        match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(e) => {
+                warn!("Mutex poisoned using session audit log: {}", e);
+                e.into_inner()
+            }
+        }
+    }
+
+    /// Start writing to the file from the given configuration.
+    pub fn configure(&self, config: &AuditLogConfig) {
+        let mut inner = self.lock();
+        if inner.config.as_ref() == Some(config) {
+            return;
+        }
+        inner.config = Some(config.clone());
+
+        if inner.writer.is_none() {
+            let (sender, messages) = mpsc::sync_channel(AUDIT_QUEUE_LENGTH);
+            let spawned = thread::Builder::new()
+                .name("session-audit-log".into())
+                .spawn(move || AuditWriter::default().run(messages));
+            match spawned {
+                Ok(_) => inner.writer = Some(sender),
+                Err(e) => warn!("Unable to start the session audit log writer: {}", e),
+            }
+        }
+        if let Some(writer) = &inner.writer {
+            // Queued records are written under the old configuration first
+            if writer.send(AuditMessage::Configure(config.clone())).is_err() {
+                warn!("Session audit log writer has stopped");
+            }
+        }
+
+        match &config.path {
+            Some(path) => info!("Recording session history to {}", path),
+            None => info!("Session history is disabled"),
+        }
+    }
+
+    pub fn record(&self, mut record: SessionAuditRecord) {
+        for capabilities in [&mut record.requested_capabilities, &mut record.capabilities]
+            .into_iter()
+            .flatten()
+        {
+            redact_secrets(capabilities);
+        }
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Unable to serialize session audit record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let inner = self.lock();
+        let writer = match &inner.writer {
+            Some(writer) => writer,
+            None => return,
+        };
+        match writer.try_send(AuditMessage::Record(line)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("Session audit log is falling behind, dropping a record")
+            }
+            Err(TrySendError::Disconnected(_)) => warn!("Session audit log writer has stopped"),
+        }
+    }
+
+    /// Wait for every record so far to be written.
+    pub fn flush(&self) {
+        let writer = self.lock().writer.clone();
+        if let Some(writer) = writer {
+            let (done, written) = mpsc::sync_channel(1);
+            if writer.send(AuditMessage::Flush(done)).is_ok() {
+                let _ = written.recv();
+            }
+        }
+    }
+
+    /// The most recent records matching the query, oldest first.
+    pub fn history(&self, query: &HistoryQuery) -> Vec<SessionAuditRecord> {
+        self.flush();
+        match self.lock().config.clone() {
+            Some(config) => read_history(&config, query),
+            None => vec![],
+        }
+    }
+}
+
+/// Capability keys which hold credentials, such as `sauce:options.accessKey`,
+/// compared ignoring case.
+fn is_secret_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    ["accesskey", "apikey", "password", "secret", "token"]
+        .iter()
+        .any(|secret| key.ends_with(secret))
+        || key == "key"
+        || key.ends_with(".key")
+}
+
+/// Replace the values of credentials anywhere in a set of capabilities,
+/// so that they aren't written to the audit log.
+fn redact_secrets(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_secret_key(key) {
+                    *value = Value::String("[redacted]".into());
+                } else {
+                    redact_secrets(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_secrets),
+        _ => {}
+    }
+}
+
+fn read_history(config: &AuditLogConfig, query: &HistoryQuery) -> Vec<SessionAuditRecord> {
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+    let since = query
+        .since
+        .map(|since| UNIX_EPOCH + Duration::from_secs(since));
+    let mut matching = VecDeque::new();
+
+    for path in config.files() {
+        // Rotated files only hold older records, so skip any last written before the range starts
+        let modified = fs::metadata(&path).and_then(|m| m.modified());
+        match (modified, since) {
+            (Err(_), _) => continue,
+            (Ok(modified), Some(since)) if modified < since => continue,
+            _ => {}
+        }
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Unable to read session audit log {}: {}", path, e);
+                continue;
+            }
+        };
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            let record: SessionAuditRecord = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(_) => continue,
+            };
+            if query.matches(&record) {
+                matching.push_back(record);
+                if matching.len() > limit {
+                    matching.pop_front();
+                }
+            }
+        }
+    }
+
+    matching.into()
+}
+
+#[test]
+fn test_audit_log_rotation_and_queries() {
+    let directory = std::env::temp_dir().join(format!("hub_router_audit_{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("history.jsonl").to_string_lossy().to_string();
+    let config = AuditLogConfig {
+        path: Some(path.clone()),
+        max_file_bytes: 400,
+        max_rotated_files: 2,
+    };
+
+    let log = SessionAuditLog::default();
+    log.record(SessionAuditRecord::new(SessionEvent::Requested));
+    assert!(fs::metadata(&path).is_err());
+
+    log.configure(&config);
+    let hub = uuid::Uuid::new_v4();
+    for i in 0..20 {
+        log.record(SessionAuditRecord {
+            session_id: Some(format!("session-{}", i)),
+            hub_uuid: Some(hub.to_string()),
+            hub_name: Some(if i % 2 == 0 { "even" } else { "odd" }.into()),
+            browser_name: Some("chrome".into()),
+            ..SessionAuditRecord::new(if i < 19 {
+                SessionEvent::Created
+            } else {
+                SessionEvent::Failed
+            })
+        });
+    }
+    log.flush();
+
+    // Old records are rotated away, rather than the log growing forever
+    assert!(fs::metadata(AuditLogConfig::rotated_path(&path, 2)).is_ok());
+    assert!(fs::metadata(AuditLogConfig::rotated_path(&path, 3)).is_err());
+    assert!(fs::metadata(&path).unwrap().len() <= 400);
+    let all = log.history(&HistoryQuery {
+        limit: Some(1000),
+        ..Default::default()
+    });
+    assert!(all.len() < 20);
+    assert_eq!(all.last().unwrap().session_id.as_deref(), Some("session-19"));
+
+    let odd = log.history(&HistoryQuery {
+        hub: Some("odd".into()),
+        browser: Some("CHROME".into()),
+        limit: Some(2),
+        ..Default::default()
+    });
+    let ids: Vec<_> = odd.iter().map(|r| r.session_id.clone().unwrap()).collect();
+    assert_eq!(ids, vec!["session-17", "session-19"]);
+
+    let failed = log.history(&HistoryQuery {
+        hub: Some(hub.to_string()),
+        event: Some(SessionEvent::Failed),
+        ..Default::default()
+    });
+    assert_eq!(failed.len(), 1);
+    assert!(log
+        .history(&HistoryQuery {
+            until: Some(0),
+            ..Default::default()
+        })
+        .is_empty());
+
+    let _ = fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn test_redact_secrets() {
+    let mut capabilities = serde_json::json!({
+        "browserName": "chrome",
+        "sauce:options": {"username": "ci", "accessKey": "abc123"},
+        "browserstack.key": "def456",
+        "firstMatch": [{"LT:Options": {"accessKey": "ghi789", "build": "nightly"}}],
+    });
+    redact_secrets(&mut capabilities);
+    assert_eq!(
+        capabilities,
+        serde_json::json!({
+            "browserName": "chrome",
+            "sauce:options": {"username": "ci", "accessKey": "[redacted]"},
+            "browserstack.key": "[redacted]",
+            "firstMatch": [{"LT:Options": {"accessKey": "[redacted]", "build": "nightly"}}],
+        })
+    );
+}
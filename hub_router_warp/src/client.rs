@@ -0,0 +1,599 @@
+//! The shared HTTP(S) client used for every request the Hub Router makes to
+//! hubs and peer replicas. Hubs may be registered with `https://` URLs, which
+//! are verified against the system's trusted CAs plus any extra CA bundles
+//! from configuration. Hubs with self-signed certificates can opt out of
+//! verification individually, and a client certificate can be presented to
+//! hubs which sit behind ingresses requiring mutual TLS.
+//!
+//! Connections are pooled and kept alive between requests, so that WebDriver
+//! commands don't each pay for a new handshake with their hub. The connections
+//! and requests to each host are counted, to show how well the pool is reused.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use dashmap::DashMap;
+use hyper::{
+    client::{
+        connect::{Connected, Connection},
+        HttpConnector, ResponseFuture,
+    },
+    service::Service,
+    Body, Client, Request, Uri,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder, MaybeHttpsStream};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use rustls::{
+    client::{
+        ServerCertVerified, ServerCertVerifier, WantsClientCert,
+        WantsTransparencyPolicyOrClientCert,
+    },
+    Certificate, ClientConfig, ConfigBuilder, RootCertStore, ServerName,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use utoipa::ToSchema;
+
+use crate::tls::{read_certificates, read_private_key};
+
+/// A pooled client able to speak both HTTP and HTTPS.
+#[derive(Debug, Clone)]
+pub struct HttpClient(Client<CountingConnector>);
+
+impl HttpClient {
+    /// Send a request over a pooled connection to its host, counting it in the pool statistics.
+    pub fn request(&self, request: Request<Body>) -> ResponseFuture {
+        POOL_STATS
+            .counters(request.uri())
+            .requests
+            .fetch_add(1, Ordering::Relaxed);
+        self.0.request(request)
+    }
+}
+
+lazy_static! {
+    /// Outlives the clients, so that the statistics carry on when they're rebuilt.
+    static ref POOL_STATS: PoolStats = PoolStats::default();
+}
+
+/// TLS settings for connections to hubs and peer replicas.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+#[serde(default)]
+pub struct OutboundTlsConfig {
+    /// PEM files of CA certificates to trust in addition to the system's,
+    /// for hubs whose certificates are signed by a private CA.
+    pub ca_bundle_paths: Vec<String>,
+
+    /// PEM file holding a certificate chain to present to servers which ask for one.
+    pub client_cert_path: Option<String>,
+
+    /// PEM file holding the client certificate's private key.
+    pub client_key_path: Option<String>,
+}
+
+/// How connections to hubs and peer replicas are made and kept.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[serde(default)]
+pub struct OutboundPoolConfig {
+    /// Idle connections kept open to each host. Zero turns pooling off.
+    pub max_idle_per_host: usize,
+
+    /// Seconds an idle connection is kept open. Zero keeps it until the host closes it.
+    pub idle_timeout: u64,
+
+    /// Seconds to wait for a TCP connection to be established. Zero waits indefinitely.
+    pub connect_timeout: u64,
+
+    /// Seconds a connection is idle before TCP keepalive probes are sent. Zero turns them off.
+    pub tcp_keepalive: u64,
+
+    /// Speak HTTP/2 without negotiating it first, for hubs known to support it.
+    /// Hubs reached over TLS are offered only HTTP/2 during the handshake.
+    pub http2_prior_knowledge: bool,
+}
+
+impl Default for OutboundPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle_per_host: 64,
+            idle_timeout: 90,
+            connect_timeout: 10,
+            tcp_keepalive: 60,
+            http2_prior_knowledge: false,
+        }
+    }
+}
+
+/// One client which verifies server certificates, and one for hubs which
+/// have opted out of verification. Both present the configured client certificate.
+#[derive(Debug, Clone)]
+struct OutboundClients {
+    verified: HttpClient,
+    unverified: HttpClient,
+}
+
+impl Default for OutboundClients {
+    fn default() -> Self {
+        let pool = OutboundPoolConfig::default();
+        Self {
+            verified: client_from_config(verifying(native_roots()).with_no_client_auth(), &pool),
+            unverified: client_from_config(non_verifying().with_no_client_auth(), &pool),
+        }
+    }
+}
+
+impl OutboundClients {
+    fn new(config: &OutboundTlsConfig, pool: &OutboundPoolConfig) -> Result<Self, String> {
+        let mut roots = native_roots();
+        for path in &config.ca_bundle_paths {
+            for certificate in read_certificates(path)? {
+                roots
+                    .add(&certificate)
+                    .map_err(|e| format!("Invalid CA certificate in {}: {}", path, e))?;
+            }
+        }
+
+        let (certificates, key) = match (&config.client_cert_path, &config.client_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                (read_certificates(cert_path)?, read_private_key(key_path)?)
+            }
+            (None, None) => {
+                return Ok(Self {
+                    verified: client_from_config(verifying(roots).with_no_client_auth(), pool),
+                    unverified: client_from_config(non_verifying().with_no_client_auth(), pool),
+                })
+            }
+            _ => {
+                return Err(
+                    "client_cert_path and client_key_path must be configured together".into(),
+                )
+            }
+        };
+
+        let invalid_identity = |e: rustls::Error| format!("Invalid client certificate or key: {}", e);
+        Ok(Self {
+            verified: client_from_config(
+                verifying(roots)
+                    .with_client_auth_cert(certificates.clone(), key.clone())
+                    .map_err(invalid_identity)?,
+                pool,
+            ),
+            unverified: client_from_config(
+                non_verifying()
+                    .with_client_auth_cert(certificates, key)
+                    .map_err(invalid_identity)?,
+                pool,
+            ),
+        })
+    }
+}
+
+fn verifying(
+    roots: RootCertStore,
+) -> ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert> {
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+}
+
+fn non_verifying() -> ConfigBuilder<ClientConfig, WantsClientCert> {
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+}
+
+fn client_from_config(config: ClientConfig, pool: &OutboundPoolConfig) -> HttpClient {
+    let seconds = |secs: u64| Some(Duration::from_secs(secs)).filter(|d| !d.is_zero());
+
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_nodelay(true);
+    http.set_connect_timeout(seconds(pool.connect_timeout));
+    http.set_keepalive(seconds(pool.tcp_keepalive));
+
+    // Hubs spoken to with HTTP/2 prior knowledge are offered only HTTP/2 over TLS as well
+    let https = HttpsConnectorBuilder::new()
+        .with_tls_config(config)
+        .https_or_http();
+    let connector = if pool.http2_prior_knowledge {
+        https.enable_http2().wrap_connector(http)
+    } else {
+        https.enable_http1().wrap_connector(http)
+    };
+
+    HttpClient(
+        Client::builder()
+            .pool_max_idle_per_host(pool.max_idle_per_host)
+            .pool_idle_timeout(seconds(pool.idle_timeout))
+            .http2_only(pool.http2_prior_knowledge)
+            .build(CountingConnector(connector)),
+    )
+}
+
+/// Connects to hosts over HTTP or HTTPS, counting the connections made to each of them.
+#[derive(Debug, Clone)]
+struct CountingConnector(HttpsConnector<HttpConnector>);
+
+impl Service<Uri> for CountingConnector {
+    type Response = CountedConnection;
+    type Error = <HttpsConnector<HttpConnector> as Service<Uri>>::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let counters = POOL_STATS.counters(&uri);
+        let connecting = self.0.call(uri);
+        Box::pin(async move {
+            match connecting.await {
+                Ok(stream) => {
+                    counters.connections_opened.fetch_add(1, Ordering::Relaxed);
+                    counters.open_connections.fetch_add(1, Ordering::Relaxed);
+                    Ok(CountedConnection { stream, counters })
+                }
+                Err(e) => {
+                    counters.connect_failures.fetch_add(1, Ordering::Relaxed);
+                    Err(e)
+                }
+            }
+        })
+    }
+}
+
+/// A connection which is counted as open until it's dropped.
+struct CountedConnection {
+    stream: MaybeHttpsStream<TcpStream>,
+    counters: Arc<HostCounters>,
+}
+
+impl Drop for CountedConnection {
+    fn drop(&mut self) {
+        self.counters.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Connection for CountedConnection {
+    fn connected(&self) -> Connected {
+        self.stream.connected()
+    }
+}
+
+impl AsyncRead for CountedConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CountedConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug, Default)]
+struct HostCounters {
+    requests: AtomicU64,
+    connections_opened: AtomicU64,
+    open_connections: AtomicU64,
+    connect_failures: AtomicU64,
+}
+
+/// Counters for each host the clients have talked to, keyed by scheme, host and port.
+#[derive(Debug, Default)]
+struct PoolStats {
+    hosts: DashMap<String, Arc<HostCounters>>,
+}
+
+impl PoolStats {
+    fn counters(&self, uri: &Uri) -> Arc<HostCounters> {
+        // Built from the parts of the URI rather than its authority, which may hold credentials
+        let host = format!(
+            "{}://{}:{}",
+            uri.scheme_str().unwrap_or("http"),
+            uri.host().unwrap_or_default(),
+            uri.port_u16()
+                .unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 })
+        );
+        self.hosts.entry(host).or_default().clone()
+    }
+}
+
+/// How a host's pooled connections are being used.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct HostPoolStatus {
+    pub host: String,
+    pub requests: u64,
+    /// Requests sent over a connection which was already open.
+    pub reused_connections: u64,
+    pub connections_opened: u64,
+    pub open_connections: u64,
+    pub connect_failures: u64,
+}
+
+/// The pool settings in use, and how each host's pool is being used.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct OutboundPoolStatus {
+    pub config: OutboundPoolConfig,
+    pub hosts: Vec<HostPoolStatus>,
+}
+
+/// Describe the connection pools to every host the clients have talked to.
+pub fn pool_status(config: OutboundPoolConfig) -> OutboundPoolStatus {
+    let mut hosts: Vec<HostPoolStatus> = POOL_STATS
+        .hosts
+        .iter()
+        .map(|entry| {
+            let counters = entry.value();
+            let requests = counters.requests.load(Ordering::Relaxed);
+            let connections_opened = counters.connections_opened.load(Ordering::Relaxed);
+            let connect_failures = counters.connect_failures.load(Ordering::Relaxed);
+            HostPoolStatus {
+                host: entry.key().clone(),
+                requests,
+                reused_connections: requests.saturating_sub(connections_opened + connect_failures),
+                connections_opened,
+                open_connections: counters.open_connections.load(Ordering::Relaxed),
+                connect_failures,
+            }
+        })
+        .collect();
+    hosts.sort_by(|a, b| a.host.cmp(&b.host));
+    OutboundPoolStatus { config, hosts }
+}
+
+/// The CAs trusted by the operating system.
+fn native_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certificates) => {
+            for certificate in certificates {
+                if let Err(e) = roots.add(&Certificate(certificate.0)) {
+                    warn!("Skipping invalid system CA certificate: {}", e);
+                }
+            }
+        }
+        Err(e) => warn!("Unable to load system CA certificates: {}", e),
+    }
+    roots
+}
+
+/// Accepts any server certificate. Only used for hubs with `insecure_skip_verify` set.
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// The clients shared by every request to hubs and peer replicas, which are
+/// replaced whenever their configuration changes.
+#[derive(Debug, Default)]
+pub struct SharedClients(RwLock<Arc<OutboundClients>>);
+
+impl SharedClients {
+    /// Replace the clients with ones built from the given configuration.
+    pub fn configure(&self, config: &OutboundTlsConfig, pool: &OutboundPoolConfig) -> Result<(), String> {
+        let clients = OutboundClients::new(config, pool)?;
+        match self.0.write() {
+            Ok(mut current) => *current = Arc::new(clients),
+            Err(e) => *e.into_inner() = Arc::new(clients),
+        }
+        info!(
+            "Configured outbound TLS with {} extra CA bundles, keeping up to {} idle connections per host",
+            config.ca_bundle_paths.len(),
+            pool.max_idle_per_host
+        );
+        Ok(())
+    }
+
+    fn current(&self) -> Arc<OutboundClients> {
+        match self.0.read() {
+            Ok(clients) => clients.clone(),
+            Err(e) => {
+                warn!("RWLock poisoned reading outbound clients: {}", e);
+                e.into_inner().clone()
+            }
+        }
+    }
+
+    /// The client verifying server certificates, for talking to peer replicas.
+    pub fn outbound(&self) -> HttpClient {
+        self.current().verified.clone()
+    }
+
+    /// The client for talking to a hub, honouring its `insecure_skip_verify` setting.
+    pub fn for_hub(&self, insecure_skip_verify: bool) -> HttpClient {
+        let clients = self.current();
+        if insecure_skip_verify {
+            clients.unverified.clone()
+        } else {
+            clients.verified.clone()
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_outbound_clients_with_private_ca() {
+    use crate::tls::{build_server_config, tls_incoming, ReloadingTlsConfig, TlsConfig};
+    use hyper::{
+        server::{accept, conn::Http},
+        service::{make_service_fn, service_fn},
+        Response, Server,
+    };
+    use std::{convert::Infallible, fs};
+    use tokio::net::TcpListener;
+
+    let directory = std::env::temp_dir().join(format!("hub_router_client_{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&directory).unwrap();
+    let path = |name: &str| directory.join(name).to_string_lossy().to_string();
+
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    fs::write(path("cert.pem"), certificate.serialize_pem().unwrap()).unwrap();
+    fs::write(path("key.pem"), certificate.serialize_private_key_pem()).unwrap();
+
+    // A hub serving HTTPS with a certificate no system CA has signed
+    let tls = ReloadingTlsConfig::load(TlsConfig {
+        cert_path: path("cert.pem"),
+        key_path: path("key.pem"),
+        client_ca_path: None,
+        client_cert_optional: false,
+        reload_interval: 30,
+    })
+    .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let uri: Uri = format!("https://localhost:{}/status", listener.local_addr().unwrap().port())
+        .parse()
+        .unwrap();
+    tokio::task::spawn(
+        Server::builder(accept::from_stream(tls_incoming(listener, tls))).serve(make_service_fn(
+            |_| async {
+                Ok::<_, Infallible>(service_fn(|_| async {
+                    Ok::<_, Infallible>(Response::new(Body::from("ok")))
+                }))
+            },
+        )),
+    );
+
+    let get = |client: &HttpClient| {
+        client.request(hyper::Request::get(uri.clone()).body(Body::empty()).unwrap())
+    };
+    let pool = OutboundPoolConfig::default();
+
+    let defaults = OutboundClients::default();
+    assert!(get(&defaults.verified).await.is_err());
+    assert!(get(&defaults.unverified).await.is_ok());
+
+    let trusting = OutboundClients::new(
+        &OutboundTlsConfig {
+            ca_bundle_paths: vec![path("cert.pem")],
+            ..Default::default()
+        },
+        &pool,
+    )
+    .unwrap();
+    assert!(get(&trusting.verified).await.is_ok());
+
+    // HTTP/2 with prior knowledge is negotiated with hubs serving it over TLS
+    let mut h2_config = build_server_config(&TlsConfig {
+        cert_path: path("cert.pem"),
+        key_path: path("key.pem"),
+        client_ca_path: None,
+        client_cert_optional: false,
+        reload_interval: 30,
+    })
+    .unwrap();
+    h2_config.alpn_protocols = vec![b"h2".to_vec()];
+    let h2_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let h2_uri: Uri = format!("https://localhost:{}/status", h2_listener.local_addr().unwrap().port())
+        .parse()
+        .unwrap();
+    tokio::task::spawn(async move {
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(h2_config));
+        while let Ok((stream, _)) = h2_listener.accept().await {
+            let stream = acceptor.accept(stream).await.unwrap();
+            tokio::task::spawn(Http::new().http2_only(true).serve_connection(
+                stream,
+                service_fn(|_| async { Ok::<_, Infallible>(Response::new(Body::from("ok"))) }),
+            ));
+        }
+    });
+    let http2 = OutboundClients::new(
+        &OutboundTlsConfig {
+            ca_bundle_paths: vec![path("cert.pem")],
+            ..Default::default()
+        },
+        &OutboundPoolConfig {
+            http2_prior_knowledge: true,
+            ..pool.clone()
+        },
+    )
+    .unwrap();
+    let response = http2
+        .verified
+        .request(hyper::Request::get(h2_uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.version(), hyper::Version::HTTP_2);
+
+    // Later requests reuse the pooled connection
+    let host = format!("https://localhost:{}", uri.port_u16().unwrap());
+    let counted = |status: OutboundPoolStatus| {
+        let host = status.hosts.into_iter().find(|h| h.host == host).unwrap();
+        (host.requests, host.connections_opened)
+    };
+    let (requests, opened) = counted(pool_status(pool.clone()));
+    for _ in 0..3 {
+        let response = get(&trusting.verified).await.unwrap();
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+    }
+    let (requests_after, opened_after) = counted(pool_status(pool.clone()));
+    assert_eq!(requests_after - requests, 3);
+    assert!(opened_after - opened <= 1);
+
+    assert!(OutboundClients::new(
+        &OutboundTlsConfig {
+            ca_bundle_paths: vec![path("missing.pem")],
+            ..Default::default()
+        },
+        &pool
+    )
+    .is_err());
+    assert!(OutboundClients::new(
+        &OutboundTlsConfig {
+            client_cert_path: Some(path("cert.pem")),
+            ..Default::default()
+        },
+        &pool
+    )
+    .is_err());
+    assert!(OutboundClients::new(
+        &OutboundTlsConfig {
+            client_cert_path: Some(path("cert.pem")),
+            client_key_path: Some(path("key.pem")),
+            ..Default::default()
+        },
+        &pool
+    )
+    .is_ok());
+    let _ = fs::remove_dir_all(&directory);
+}
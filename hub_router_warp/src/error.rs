@@ -1,8 +1,9 @@
-///! An error wrapper for the Hub Router, so that errors from libraries which
-///! we use can be `into`'d and homogenized to a single error type, which
-///! we can back to a test for nicely formatted errors when things go wrong.
-use std::fmt::Debug;
+//! An error wrapper for the Hub Router, so that errors from libraries which
+//! we use can be `into`'d and homogenized to a single error type, which
+//! we can back to a test for nicely formatted errors when things go wrong.
+use std::fmt::{Debug, Display};
 
+use hyper::{Body, Response, StatusCode};
 
 #[derive(Debug)]
 pub enum RoutingError {
@@ -10,6 +11,13 @@ pub enum RoutingError {
     UnableToSatisfyCapabilities(String),
     MalformedRequestPath(String),
     NoDecision(String),
+    HubsAtCapacity(String),
+    QueueFull(String),
+    QueueTimeout(String),
+    HubsExhausted(String),
+    UnknownSession(String),
+    ClientNotIdentified(String),
+    QuotaExceeded(String),
 }
 
 #[derive(Debug)]
@@ -18,10 +26,164 @@ pub enum HubRouterError {
     HyperError(hyper::Error),
     DeserializationError(serde_json::Error),
     SessionCreationError(String),
+    InvalidArgument(String),
+    Timeout(String),
     Internal(String),
 }
 
+impl RoutingError {
+    /// A stable, snake_case name for the kind of routing error, used for metric labels.
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            RoutingError::NoHealthyNodes(_) => "no_healthy_nodes",
+            RoutingError::UnableToSatisfyCapabilities(_) => "unable_to_satisfy_capabilities",
+            RoutingError::MalformedRequestPath(_) => "malformed_request_path",
+            RoutingError::NoDecision(_) => "no_decision",
+            RoutingError::HubsAtCapacity(_) => "hubs_at_capacity",
+            RoutingError::QueueFull(_) => "queue_full",
+            RoutingError::QueueTimeout(_) => "queue_timeout",
+            RoutingError::HubsExhausted(_) => "hubs_exhausted",
+            RoutingError::UnknownSession(_) => "unknown_session",
+            RoutingError::ClientNotIdentified(_) => "client_not_identified",
+            RoutingError::QuotaExceeded(_) => "quota_exceeded",
+        }
+    }
+}
+
+impl Display for RoutingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoutingError::NoHealthyNodes(msg) => write!(f, "no healthy nodes: {}", msg),
+            RoutingError::UnableToSatisfyCapabilities(msg) => {
+                write!(f, "unable to satisfy capabilities: {}", msg)
+            }
+            RoutingError::MalformedRequestPath(msg) => write!(f, "malformed request path: {}", msg),
+            RoutingError::NoDecision(msg) => write!(f, "no routing decision: {}", msg),
+            RoutingError::HubsAtCapacity(msg) => write!(f, "hubs at capacity: {}", msg),
+            RoutingError::QueueFull(msg) => write!(f, "new session queue full: {}", msg),
+            RoutingError::QueueTimeout(msg) => write!(f, "timed out in new session queue: {}", msg),
+            RoutingError::HubsExhausted(msg) => write!(f, "no hubs left to try: {}", msg),
+            RoutingError::UnknownSession(msg) => write!(f, "unknown session: {}", msg),
+            RoutingError::ClientNotIdentified(msg) => write!(f, "client not identified: {}", msg),
+            RoutingError::QuotaExceeded(msg) => write!(f, "quota exceeded: {}", msg),
+        }
+    }
+}
+
+impl Display for HubRouterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HubRouterError::RoutingError(e) => write!(f, "routing error: {}", e),
+            HubRouterError::HyperError(e) => write!(f, "http error: {}", e),
+            HubRouterError::DeserializationError(e) => write!(f, "deserialization error: {}", e),
+            HubRouterError::SessionCreationError(msg) => write!(f, "session creation error: {}", msg),
+            HubRouterError::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            HubRouterError::Timeout(msg) => write!(f, "timeout: {}", msg),
+            HubRouterError::Internal(msg) => write!(f, "internal error: {}", msg),
+        }
+    }
+}
+
+impl RoutingError {
+    /// The WebDriver error code this routing error is reported to clients as.
+    pub fn webdriver_error_code(&self) -> &'static str {
+        match self {
+            RoutingError::NoHealthyNodes(_)
+            | RoutingError::UnableToSatisfyCapabilities(_)
+            | RoutingError::HubsAtCapacity(_)
+            | RoutingError::QueueFull(_)
+            | RoutingError::QueueTimeout(_)
+            | RoutingError::HubsExhausted(_)
+            | RoutingError::ClientNotIdentified(_)
+            | RoutingError::QuotaExceeded(_) => "session not created",
+            RoutingError::MalformedRequestPath(_) => "unknown command",
+            RoutingError::UnknownSession(_) => "invalid session id",
+            RoutingError::NoDecision(_) => "unknown error",
+        }
+    }
+}
+
+/// The HTTP status the WebDriver specification associates with each error code:
+/// https://www.w3.org/TR/webdriver/#errors
+pub fn webdriver_error_status(error_code: &str) -> StatusCode {
+    match error_code {
+        "element click intercepted"
+        | "element not interactable"
+        | "insecure certificate"
+        | "invalid argument"
+        | "invalid cookie domain"
+        | "invalid element state"
+        | "invalid selector" => StatusCode::BAD_REQUEST,
+        "invalid session id"
+        | "no such alert"
+        | "no such cookie"
+        | "no such element"
+        | "no such frame"
+        | "no such shadow root"
+        | "no such window"
+        | "stale element reference"
+        | "detached shadow root"
+        | "unknown command" => StatusCode::NOT_FOUND,
+        "unknown method" => StatusCode::METHOD_NOT_ALLOWED,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
 impl HubRouterError {
+    /// The WebDriver error code this error is reported to clients as.
+    pub fn webdriver_error_code(&self) -> &'static str {
+        match self {
+            HubRouterError::RoutingError(e) => e.webdriver_error_code(),
+            HubRouterError::HyperError(_) => "unknown error",
+            HubRouterError::DeserializationError(_) => "invalid argument",
+            HubRouterError::SessionCreationError(_) => "session not created",
+            HubRouterError::InvalidArgument(_) => "invalid argument",
+            HubRouterError::Timeout(_) => "timeout",
+            HubRouterError::Internal(_) => "unknown error",
+        }
+    }
+
+    /// What caused the error, one cause per line, for the WebDriver `stacktrace`.
+    /// Errors which the Hub Router raised itself have no cause.
+    fn cause_chain(&self) -> String {
+        let mut source: Option<&(dyn std::error::Error + 'static)> = match self {
+            HubRouterError::HyperError(e) => std::error::Error::source(e),
+            HubRouterError::DeserializationError(e) => std::error::Error::source(e),
+            _ => None,
+        };
+        let mut causes = vec![];
+        while let Some(cause) = source {
+            causes.push(format!("caused by: {}", cause));
+            source = cause.source();
+        }
+        causes.join("\n")
+    }
+
+    /// Build a spec-compliant WebDriver error response for this error,
+    /// so that client bindings can surface the error code and message.
+    pub fn to_webdriver_response(&self) -> Response<Body> {
+        let error_code = self.webdriver_error_code();
+        let body = serde_json::json!({
+            "value": {
+                "error": error_code,
+                "message": format!("Hub Router {}", self),
+                "stacktrace": self.cause_chain(),
+            }
+        });
+
+        let mut response = Response::new(Body::from(body.to_string()));
+        *response.status_mut() = webdriver_error_status(error_code);
+        response.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("application/json; charset=utf-8"),
+        );
+        response.headers_mut().insert(
+            hyper::header::CACHE_CONTROL,
+            hyper::header::HeaderValue::from_static("no-cache"),
+        );
+        response
+    }
+
     pub fn wrap_err<T, E>(result: Result<T, E>) -> Result<T, HubRouterError>
     where
         E: Debug + Into<HubRouterError>,
@@ -50,3 +212,24 @@ impl From<serde_json::Error> for HubRouterError {
         HubRouterError::DeserializationError(value)
     }
 }
+
+#[tokio::test]
+async fn test_webdriver_error_response() {
+    let response = HubRouterError::from(RoutingError::NoHealthyNodes("none".into()))
+        .to_webdriver_response();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["value"]["message"], "Hub Router routing error: no healthy nodes: none");
+    assert_eq!(body["value"]["stacktrace"], "");
+
+    let response = HubRouterError::InvalidArgument("bad".into()).to_webdriver_response();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = HubRouterError::from(RoutingError::MalformedRequestPath("/".into()))
+        .to_webdriver_response();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    assert_eq!(webdriver_error_status("invalid session id"), StatusCode::NOT_FOUND);
+    assert_eq!(webdriver_error_status("timeout"), StatusCode::INTERNAL_SERVER_ERROR);
+}
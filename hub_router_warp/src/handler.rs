@@ -1,16 +1,38 @@
-///! Functions for handling specific Selenium endpoints
+//! Functions for handling specific Selenium endpoints
 
 use crate::{
-    error::HubRouterError,
-    routing::{apply_routing_decision, make_routing_decision, RoutingPrecedentMap},
-    schema::{NewSessionRequestBody, NewSessionRequestCapability, NewSessionResponse},
-    state::HubRouterState,
+    audit::{SessionAuditRecord, SessionEvent, AUDIT_LOG},
+    client::HttpClient,
+    credentials::{apply_hub_auth, without_credentials},
+    error::{HubRouterError, RoutingError},
+    hub::{deregister_drained_hubs, HubMetadata, SlotReservation},
+    metrics::METRICS,
+    peers::resolve_unknown_session,
+    routing::{apply_routing_decision, make_routing_decision, RoutingDecision, RoutingPrecedentMap},
+    schema::{
+        NewSessionRequestBody, NewSessionRequestCapabilities, NewSessionRequestCapability,
+        NewSessionResponse, NewSessionResponseCapabilities,
+    },
+    state::{HubRouterPrimitiveConfigs, HubRouterState},
+    timeouts::is_connect_timeout,
 };
-use hyper::{Body, Client, Method, Request, Response};
+use hyper::{body::Bytes, http::request::Parts, Body, Method, Request, Response, StatusCode};
 use lazy_static::lazy_static;
-use log::warn;
+use log::{info, warn};
 use regex::Regex;
-use std::sync::Arc;
+use serde_json::{Map, Value};
+use std::{
+    collections::HashSet,
+    future::Future,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    task::JoinHandle,
+    time::{timeout, timeout_at, Instant},
+};
+use uuid::Uuid;
 
 
 /// Inspect an HTTP request and parse out a Selenium session ID, if it exists
@@ -20,20 +42,20 @@ fn extract_session_id(req: &Request<Body>) -> Option<String> {
     }
 
     match req.uri().path_and_query() {
-        None => return None,
+        None => None,
         Some(path_and_query) => {
             let path_string = path_and_query.path();
             let first_capture = SESSION_ID_REGEXP.captures_iter(path_string).next();
             match first_capture {
-                None => return None,
+                None => None,
                 Some(captures) => match captures.get(1) {
                     None => {
                         warn!(
                             "SessionID regexp found a match but it didn't include the sessionID"
                         );
-                        return None;
+                        None
                     }
-                    Some(session_id_match) => return Some(session_id_match.as_str().to_string()),
+                    Some(session_id_match) => Some(session_id_match.as_str().to_string()),
                 },
             }
         }
@@ -70,43 +92,399 @@ fn test_extract_session_id() {
 }
 
 
-/// Extract browser/operating system requests from a request, if they exist
+/// Extract browser/operating system requests from a request, if they exist.
+/// The request is returned split into its head and buffered body, so that it can
+/// be rebuilt if the new session has to be attempted on more than one hub.
 pub async fn extract_capabilities_from_new_session_request(
     request: Request<Body>,
-) -> Result<(Vec<NewSessionRequestCapability>, Request<Body>), HubRouterError> {
-    let (parts, body) = request.into_parts();
-    let body_bytes = hyper::body::to_bytes(body).await?;
+) -> Result<(Vec<NewSessionRequestCapability>, Parts, Bytes), HubRouterError> {
+    let (mut parts, body) = request.into_parts();
+    let mut body_bytes = hyper::body::to_bytes(body).await?;
+
+    let mut capability_request: NewSessionRequestBody = match serde_json::from_slice(&body_bytes) {
+        Ok(request) => request,
+        Err(e) => {
+            return Err(HubRouterError::InvalidArgument(format!(
+                "Invalid new session request: {}",
+                e
+            )))
+        }
+    };
+
+    // Legacy clients only send desiredCapabilities, so translate them into the
+    // W3C form and add them to the body we forward, as Selenium 4 hubs only read W3C capabilities.
+    if capability_request.capabilities.is_none() {
+        let desired = match capability_request.desiredCapabilities.take() {
+            Some(desired) => desired,
+            None => {
+                return Err(HubRouterError::InvalidArgument(
+                    "new session request has neither capabilities nor desiredCapabilities".into(),
+                ))
+            }
+        };
+
+        let capabilities = NewSessionRequestCapabilities {
+            alwaysMatch: translate_desired_capabilities(desired),
+            firstMatch: vec![Map::new()],
+        };
+
+        body_bytes = rewrite_new_session_body(&body_bytes, &capabilities)?;
+        parts
+            .headers
+            .insert(hyper::header::CONTENT_LENGTH, body_bytes.len().into());
+        capability_request.capabilities = Some(capabilities);
+    }
+
+    let possible_requests = process_capabilities(capability_request)?;
+
+    Ok((possible_requests, parts, body_bytes))
+}
+
+/// Translate legacy JSON Wire Protocol desiredCapabilities into W3C capabilities,
+/// the same way Selenium's own protocol converter does. Capabilities with no W3C
+/// equivalent are dropped, as hubs would otherwise reject them as invalid.
+fn translate_desired_capabilities(desired: Map<String, Value>) -> Map<String, Value> {
+    let mut translated = Map::new();
+
+    for (original, value) in &desired {
+        let name = match original.as_str() {
+            "version" => "browserVersion",
+            "acceptSslCerts" => "acceptInsecureCerts",
+            "chromeOptions" => "goog:chromeOptions",
+            "platform" => match value.as_str() {
+                // ANY is the JSON Wire way of saying there is no platform preference
+                Some(platform) if !platform.eq_ignore_ascii_case("any") => {
+                    if !desired.contains_key("platformName") {
+                        translated.insert(
+                            "platformName".into(),
+                            Value::String(platform.to_lowercase()),
+                        );
+                    }
+                    continue;
+                }
+                _ => continue,
+            },
+            "browserName" | "browserVersion" | "platformName" | "acceptInsecureCerts"
+            | "pageLoadStrategy" | "proxy" | "setWindowRect" | "strictFileInteractability"
+            | "timeouts" | "unhandledPromptBehavior" | "webSocketUrl" => original.as_str(),
+            extension if extension.contains(':') => original.as_str(),
+            _ => continue,
+        };
+
+        // An explicit W3C capability wins over its legacy spelling
+        if name != original && desired.contains_key(name) {
+            continue;
+        }
+        translated.insert(name.to_string(), value.clone());
+    }
+
+    translated
+}
+
+/// Add a W3C `capabilities` object to a new session request body, leaving the rest of it untouched.
+fn rewrite_new_session_body(
+    body: &Bytes,
+    capabilities: &NewSessionRequestCapabilities,
+) -> Result<Bytes, HubRouterError> {
+    let mut body: Map<String, Value> = match serde_json::from_slice(body) {
+        Ok(body) => body,
+        Err(e) => {
+            return Err(HubRouterError::InvalidArgument(format!(
+                "Invalid new session request: {}",
+                e
+            )))
+        }
+    };
+
+    match serde_json::to_value(capabilities) {
+        Ok(capabilities) => {
+            body.insert("capabilities".into(), capabilities);
+        }
+        Err(e) => return Err(HubRouterError::Internal(e.to_string())),
+    }
+
+    match serde_json::to_vec(&body) {
+        Ok(body) => Ok(Bytes::from(body)),
+        Err(e) => Err(HubRouterError::Internal(e.to_string())),
+    }
+}
+
+/// The registered hub at a routing decision's endpoint. Hubs are found by their
+/// endpoint rather than the decision's UUID, since a decision shared by a peer
+/// may name a hub by the UUID it has on that replica, and a peer isn't trusted
+/// to pair one hub's UUID with another's endpoint.
+fn hub_at_endpoint(state: &HubRouterState, decision: &RoutingDecision) -> Option<HubMetadata> {
+    state
+        .hubs
+        .iter()
+        .find(|hub| without_credentials(&hub.meta.url) == decision.hub_endpoint)
+        .map(|hub| hub.meta.clone())
+}
+
+/// Send a request to the hub a routing decision chose, with that hub's credentials,
+/// returning the client to send it with. Requests to an endpoint which isn't a
+/// registered hub are sent without credentials.
+fn address_to_hub(
+    req: &mut Request<Body>,
+    decision: &RoutingDecision,
+    state: &HubRouterState,
+) -> Result<HttpClient, HubRouterError> {
+    apply_routing_decision(req, &decision.hub_endpoint)?;
+    let hub = hub_at_endpoint(state, decision);
+    if let Some(meta) = &hub {
+        apply_hub_auth(req.headers_mut(), meta);
+    }
+    Ok(state.clients.for_hub(hub.is_some_and(|meta| meta.insecure_skip_verify)))
+}
+
+#[test]
+fn test_address_to_hub() {
+    use crate::{
+        credentials::{HubAuth, Secret},
+        hub::Hub,
+    };
+    use hyper::header::AUTHORIZATION;
+    use url::Url;
 
-    let capability_request: NewSessionRequestBody = serde_json::from_slice(&body_bytes)?;
-    let possible_requests = generate_possible_capabilities(capability_request);
+    let state = HubRouterState::default();
+    let mut hub = Hub::new(Url::parse("http://grid-a:4444/").unwrap());
+    hub.meta.auth = Some(HubAuth::Bearer {
+        token: Secret::Value("grid-a-token".into()),
+    });
+    let hub_uuid = hub.meta.uuid;
+    state.hubs.insert(hub_uuid, hub);
+    let send = |hub_uuid: Uuid, endpoint: &str| {
+        let decision = RoutingDecision::new(hub_uuid, Url::parse(endpoint).unwrap(), SystemTime::now());
+        let mut req = Request::get("/session/abc/url").body(Body::empty()).unwrap();
+        address_to_hub(&mut req, &decision, &state).unwrap();
+        (
+            req.uri().to_string(),
+            req.headers().get(AUTHORIZATION).map(|v| v.to_str().unwrap().to_string()),
+        )
+    };
 
-    let reconstructed_request = hyper::Request::from_parts(parts, hyper::Body::from(body_bytes));
-    return Ok((possible_requests, reconstructed_request));
+    assert_eq!(
+        send(hub_uuid, "http://grid-a:4444/"),
+        (
+            "http://grid-a:4444/session/abc/url".to_string(),
+            Some("Bearer grid-a-token".to_string())
+        )
+    );
+
+    // A peer's decision names the hub by the UUID it has on that replica
+    assert_eq!(
+        send(Uuid::new_v4(), "http://grid-a:4444/").1,
+        Some("Bearer grid-a-token".to_string())
+    );
+
+    // The hub's credentials are never sent to another endpoint
+    assert_eq!(
+        send(hub_uuid, "http://elsewhere:4444/"),
+        ("http://elsewhere:4444/session/abc/url".to_string(), None)
+    );
+}
+
+/// Build a fresh copy of a buffered request
+fn rebuild_request(parts: &Parts, body: &Bytes) -> Request<Body> {
+    let mut req = Request::new(Body::from(body.clone()));
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+    req
 }
 
 
 /// Performs capability processing as specified in the WebDriver specification: https://www.w3.org/TR/webdriver/#processing-capabilities
-/// Only respects browsers/operating systems
-fn generate_possible_capabilities(
+/// alwaysMatch and every firstMatch entry are validated, then alwaysMatch is merged
+/// with each firstMatch entry to produce the list of capability sets to try, in order.
+/// A firstMatch entry which repeats a key from alwaysMatch is an invalid argument.
+fn process_capabilities(
     capability_request: NewSessionRequestBody,
-) -> Vec<NewSessionRequestCapability> {
-    capability_request
-        .capabilities
-        .firstMatch
-        .iter()
-        .map(|first_match| {
-            let mut base = capability_request.capabilities.alwaysMatch.clone();
-            base.browserName = base.browserName.or(first_match.browserName.clone());
-            base.platformName = base.platformName.or(first_match.platformName.clone());
-            base
+) -> Result<Vec<NewSessionRequestCapability>, HubRouterError> {
+    let capabilities = match capability_request.capabilities {
+        Some(capabilities) => capabilities,
+        None => {
+            return Err(HubRouterError::InvalidArgument(
+                "new session request has no capabilities".into(),
+            ))
+        }
+    };
+
+    let always_match = validate_capabilities(capabilities.alwaysMatch)?;
+
+    if capabilities.firstMatch.is_empty() {
+        return Err(HubRouterError::InvalidArgument(
+            "firstMatch must contain at least one entry".into(),
+        ));
+    }
+
+    let mut possible_capabilities = vec![];
+    for first_match in capabilities.firstMatch {
+        let mut merged = always_match.clone();
+        for (name, value) in validate_capabilities(first_match)? {
+            if merged.contains_key(&name) {
+                return Err(HubRouterError::InvalidArgument(format!(
+                    "capability {} is present in both alwaysMatch and firstMatch",
+                    name
+                )));
+            }
+            merged.insert(name, value);
+        }
+
+        match serde_json::from_value(Value::Object(merged)) {
+            Ok(capability) => possible_capabilities.push(capability),
+            Err(e) => {
+                return Err(HubRouterError::InvalidArgument(format!(
+                    "invalid capabilities: {}",
+                    e
+                )))
+            }
+        }
+    }
+
+    Ok(possible_capabilities)
+}
+
+/// Validates a single capabilities object as specified in https://www.w3.org/TR/webdriver/#dfn-validate-capabilities.
+/// Null values are dropped, standard capabilities must have the right type,
+/// and any other capability must be an extension capability (its name contains a `:`).
+fn validate_capabilities(
+    capabilities: Map<String, Value>,
+) -> Result<Map<String, Value>, HubRouterError> {
+    let mut validated = Map::new();
+
+    for (name, value) in capabilities {
+        if value.is_null() {
+            continue;
+        }
+
+        let valid = match name.as_str() {
+            "acceptInsecureCerts" | "setWindowRect" | "strictFileInteractability"
+            | "webSocketUrl" => value.is_boolean(),
+            "browserName" | "browserVersion" | "platformName" => value.is_string(),
+            "pageLoadStrategy" => matches!(value.as_str(), Some("none" | "eager" | "normal")),
+            "proxy" | "timeouts" => value.is_object(),
+            "unhandledPromptBehavior" => value.is_string() || value.is_object(),
+            extension if extension.contains(':') => true,
+            _ => {
+                return Err(HubRouterError::InvalidArgument(format!(
+                    "unrecognized capability: {}",
+                    name
+                )))
+            }
+        };
+
+        if !valid {
+            return Err(HubRouterError::InvalidArgument(format!(
+                "invalid value for capability {}: {}",
+                name, value
+            )));
+        }
+
+        validated.insert(name, value);
+    }
+
+    Ok(validated)
+}
+
+#[test]
+fn test_process_capabilities() {
+    let request = |body: Value| -> NewSessionRequestBody { serde_json::from_value(body).unwrap() };
+
+    let processed = process_capabilities(request(serde_json::json!({
+        "capabilities": {
+            "alwaysMatch": {"platformName": "linux", "acceptInsecureCerts": true},
+            "firstMatch": [
+                {"browserName": "chrome", "browserVersion": "115", "goog:chromeOptions": {}},
+                {"browserName": "firefox", "moz:firefoxOptions": null}
+            ]
+        }
+    })))
+    .unwrap();
+    assert_eq!(processed.len(), 2);
+    assert_eq!(processed[0].browserName.as_deref(), Some("chrome"));
+    assert_eq!(processed[0].browserVersion.as_deref(), Some("115"));
+    assert_eq!(processed[0].platformName.as_deref(), Some("linux"));
+    assert!(processed[0].additional.contains_key("goog:chromeOptions"));
+    assert_eq!(processed[1].browserName.as_deref(), Some("firefox"));
+    assert!(!processed[1].additional.contains_key("moz:firefoxOptions"));
+
+    let conflicting = request(serde_json::json!({
+        "capabilities": {"alwaysMatch": {"browserName": "chrome"}, "firstMatch": [{"browserName": "firefox"}]}
+    }));
+    assert!(matches!(
+        process_capabilities(conflicting),
+        Err(HubRouterError::InvalidArgument(_))
+    ));
+
+    let wrong_type = request(serde_json::json!({
+        "capabilities": {"alwaysMatch": {"acceptInsecureCerts": "yes"}, "firstMatch": [{}]}
+    }));
+    assert!(matches!(
+        process_capabilities(wrong_type),
+        Err(HubRouterError::InvalidArgument(_))
+    ));
+
+    let unknown = request(serde_json::json!({
+        "capabilities": {"alwaysMatch": {"browser": "chrome"}, "firstMatch": [{}]}
+    }));
+    assert!(matches!(
+        process_capabilities(unknown),
+        Err(HubRouterError::InvalidArgument(_))
+    ));
+
+    let always_match_only = request(serde_json::json!({
+        "capabilities": {"alwaysMatch": {"browserName": "chrome"}}
+    }));
+    let processed = process_capabilities(always_match_only).unwrap();
+    assert_eq!(processed.len(), 1);
+    assert_eq!(processed[0].browserName.as_deref(), Some("chrome"));
+
+    let empty = request(serde_json::json!({"capabilities": {}}));
+    assert_eq!(process_capabilities(empty).unwrap().len(), 1);
+
+    let explicitly_empty_first_match = request(serde_json::json!({
+        "capabilities": {"firstMatch": []}
+    }));
+    assert!(matches!(
+        process_capabilities(explicitly_empty_first_match),
+        Err(HubRouterError::InvalidArgument(_))
+    ));
+}
+
+#[test]
+fn test_translate_desired_capabilities() {
+    let desired = serde_json::json!({
+        "browserName": "firefox",
+        "version": "115",
+        "platform": "LINUX",
+        "acceptSslCerts": true,
+        "acceptInsecureCerts": false,
+        "javascriptEnabled": true,
+        "moz:firefoxOptions": {"args": ["-headless"]}
+    });
+    let translated = translate_desired_capabilities(desired.as_object().unwrap().clone());
+    assert_eq!(
+        Value::Object(translated),
+        serde_json::json!({
+            "browserName": "firefox",
+            "browserVersion": "115",
+            "platformName": "linux",
+            "acceptInsecureCerts": false,
+            "moz:firefoxOptions": {"args": ["-headless"]}
         })
-        .collect()
+    );
+
+    let any_platform = serde_json::json!({"browserName": "chrome", "platform": "ANY"});
+    let translated = translate_desired_capabilities(any_platform.as_object().unwrap().clone());
+    assert!(!translated.contains_key("platformName"));
 }
 
 fn is_request_new_session(req: &Request<Body>) -> bool {
-    return req.method() == Method::POST
+    req.method() == Method::POST
         && req.uri().path_and_query().is_some()
-        && req.uri().path_and_query().unwrap().as_str() == "/session";
+        && req.uri().path_and_query().unwrap().as_str() == "/session"
 }
 
 #[test]
@@ -132,15 +510,15 @@ fn test_req_is_new_session() {
     assert_eq!(is_request_new_session(&t4), false);
 }
 
+/// Whether a request deletes the session itself. Deleting a cookie or a window
+/// leaves the session running, so it mustn't free the slot or be audited as a deletion.
 fn is_delete_session(req: &Request<Body>) -> bool {
-    return req.method() == Method::DELETE
-        && req.uri().path_and_query().is_some()
+    req.method() == Method::DELETE
         && req
             .uri()
-            .path_and_query()
-            .unwrap()
-            .as_str()
-            .starts_with("/session/");
+            .path()
+            .strip_prefix("/session/")
+            .is_some_and(|id| !id.is_empty() && !id.contains('/'))
 }
 
 #[test]
@@ -159,61 +537,615 @@ fn test_delete_session() {
         .body(Body::empty())
         .unwrap();
     assert_eq!(is_delete_session(&t3), false);
+
+    let t4 = hyper::Request::delete("https://example.com/session/x/cookie")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(is_delete_session(&t4), false);
+
+    let t5 = hyper::Request::delete("https://example.com/session/x/window")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(is_delete_session(&t5), false);
 }
 
 
+/// Make a routing decision for a new session request. If every capable hub is full,
+/// or there are no healthy hubs, the request waits in the new session queue until
+/// a slot frees up or the configured wait timeout elapses. Requests for the same
+/// capabilities are released in the order they arrived. A retried request also
+/// stops waiting at its retry deadline.
+/// The chosen hub's slot is reserved until the session is created or given up on.
+async fn route_new_session(
+    requests: Vec<NewSessionRequestCapability>,
+    excluded_hubs: &HashSet<Uuid>,
+    retry_deadline: Option<Instant>,
+    routing_map: Arc<RoutingPrecedentMap>,
+    state: Arc<HubRouterState>,
+) -> Result<(RoutingDecision, SlotReservation), HubRouterError> {
+    let (max_queue_size, wait_timeout) = match state.configs.read() {
+        Ok(conf) => (conf.new_session_queue_size, conf.new_session_wait_timeout),
+        Err(e) => {
+            warn!("RwLock was poisoned reading new session queue configs: {}", e);
+            let conf = HubRouterPrimitiveConfigs::default();
+            (conf.new_session_queue_size, conf.new_session_wait_timeout)
+        }
+    };
+
+    if max_queue_size == 0 {
+        let decision = make_routing_decision(
+            None,
+            Some(requests.clone()),
+            excluded_hubs,
+            routing_map,
+            state.clone(),
+        )?;
+        let reservation = SlotReservation::take(state, decision.hub_uuid, &requests);
+        return Ok((decision, reservation));
+    }
+
+    let ticket = match state
+        .new_session_queue
+        .enqueue(requests.clone(), max_queue_size as usize)
+    {
+        Some(ticket) => ticket,
+        None => {
+            return Err(RoutingError::QueueFull(format!(
+                "{} requests are already waiting for {:?}",
+                max_queue_size, requests
+            ))
+            .into())
+        }
+    };
+
+    let queue_deadline = Instant::now() + Duration::from_secs(wait_timeout);
+    let deadline = retry_deadline.map_or(queue_deadline, |retry| retry.min(queue_deadline));
+    loop {
+        let slot_freed = state.new_session_queue.slot_freed();
+
+        if ticket.is_head() {
+            match make_routing_decision(
+                None,
+                Some(requests.clone()),
+                excluded_hubs,
+                routing_map.clone(),
+                state.clone(),
+            ) {
+                Ok(decision) => {
+                    let reservation = SlotReservation::take(state.clone(), decision.hub_uuid, &requests);
+                    return Ok((decision, reservation));
+                }
+                Err(RoutingError::NoHealthyNodes(_) | RoutingError::HubsAtCapacity(_)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if timeout_at(deadline, slot_freed).await.is_err() {
+            if deadline < queue_deadline {
+                return Err(RoutingError::QueueTimeout(format!(
+                    "No hub had a free slot for {:?} before the retry deadline",
+                    requests
+                ))
+                .into());
+            }
+            warn!(
+                "New session request for {:?} timed out after waiting {} seconds for a free slot",
+                requests, wait_timeout
+            );
+            return Err(RoutingError::QueueTimeout(format!(
+                "No hub had a free slot for {:?} within {} seconds",
+                requests, wait_timeout
+            ))
+            .into());
+        }
+    }
+}
+
+/// The outcome of sending a new session request to a single hub
+enum NewSessionAttempt {
+    /// The hub created the session, and responded with this session ID, capabilities and response
+    Created(String, Box<NewSessionResponseCapabilities>, Response<Body>),
+
+    /// The hub could not create the session, for the given reason
+    Rejected(String),
+}
+
+/// Send a new session request to the hub chosen by the routing decision.
+/// Hubs which answer with something other than a new session response, or
+/// which can't be connected to, are treated as having rejected the request,
+/// so that it can be retried elsewhere. A hub which is connected to but doesn't
+/// respond in time may still be creating the session, so it isn't retried, and
+/// if the hub does go on to create the session it is deleted again.
+async fn attempt_new_session(
+    parts: &Parts,
+    body: &Bytes,
+    routing_decision: &RoutingDecision,
+    state: &HubRouterState,
+) -> Result<NewSessionAttempt, HubRouterError> {
+    let mut req = rebuild_request(parts, body);
+    let client = address_to_hub(&mut req, routing_decision, state)?;
+    let limit = match state.configs.read() {
+        Ok(conf) => conf.proxy_timeouts.for_new_session(),
+        Err(e) => {
+            warn!("RwLock was poisoned reading new session timeout: {}", e);
+            HubRouterPrimitiveConfigs::default().proxy_timeouts.for_new_session()
+        }
+    };
+
+    // Run the exchange as a task of its own, so that it can be seen through after we stop waiting
+    let mut exchange = tokio::task::spawn({
+        let client = client.clone();
+        async move {
+            let response = client.request(req).await?;
+            let (parts, body) = response.into_parts();
+            let bytes = hyper::body::to_bytes(body).await?;
+            Ok::<_, hyper::Error>((parts, bytes))
+        }
+    });
+    let (parts, bytes) = match within(limit, &mut exchange).await {
+        Some(Err(e)) => return Err(HubRouterError::Internal(e.to_string())),
+        Some(Ok(Ok(exchanged))) => exchanged,
+        Some(Ok(Err(e))) if is_connect_timeout(&e) => {
+            METRICS.record_hub_timeout(routing_decision.hub_uuid, "connect");
+            return Ok(NewSessionAttempt::Rejected(format!("timed out connecting: {}", e)));
+        }
+        Some(Ok(Err(e))) if e.is_connect() => {
+            return Ok(NewSessionAttempt::Rejected(e.to_string()))
+        }
+        Some(Ok(Err(e))) => return Err(e.into()),
+        None => {
+            delete_late_session(exchange, routing_decision, state, client);
+            return Err(hub_timeout(
+                routing_decision,
+                "new_session",
+                state,
+                format!("didn't create a session within {:?}", limit.unwrap_or_default()),
+            ))
+        }
+    };
+
+    match serde_json::from_slice::<NewSessionResponse>(&bytes) {
+        Ok(new_session_response) => Ok(NewSessionAttempt::Created(
+            new_session_response.value.sessionId,
+            Box::new(new_session_response.value.capabilities),
+            Response::from_parts(parts, Body::from(bytes)),
+        )),
+        Err(_) => Ok(NewSessionAttempt::Rejected(
+            String::from_utf8_lossy(&bytes).to_string(),
+        )),
+    }
+}
+
+/// Delete the session a hub creates after we've given up waiting for it, so that
+/// its browser isn't left running until the hub's own session timeout reaps it.
+fn delete_late_session(
+    exchange: JoinHandle<Result<(hyper::http::response::Parts, Bytes), hyper::Error>>,
+    routing_decision: &RoutingDecision,
+    state: &HubRouterState,
+    client: HttpClient,
+) {
+    let decision = routing_decision.clone();
+    let hub = hub_at_endpoint(state, routing_decision);
+    let limit = state.read_configs(|conf| conf.proxy_timeouts.for_command(&Method::DELETE, "/session/"));
+    tokio::task::spawn(async move {
+        let session_id = match exchange.await {
+            Ok(Ok((_, bytes))) => match serde_json::from_slice::<NewSessionResponse>(&bytes) {
+                Ok(response) => response.value.sessionId,
+                Err(_) => return,
+            },
+            _ => return,
+        };
+
+        let mut req = Request::new(Body::empty());
+        *req.method_mut() = Method::DELETE;
+        let addressed = format!("/session/{}", session_id)
+            .parse()
+            .map_err(|e: hyper::http::uri::InvalidUri| e.to_string())
+            .and_then(|uri| {
+                *req.uri_mut() = uri;
+                apply_routing_decision(&mut req, &decision.hub_endpoint).map_err(|e| e.to_string())
+            });
+        if let Err(e) = addressed {
+            warn!("Unable to delete abandoned session {}: {}", session_id, e);
+            return;
+        }
+        if let Some(meta) = &hub {
+            apply_hub_auth(req.headers_mut(), meta);
+        }
+
+        match within(limit, client.request(req)).await {
+            Some(Ok(response)) if response.status().is_success() => info!(
+                "Deleted session {} which {} created after the new session request timed out",
+                session_id, decision.hub_endpoint
+            ),
+            Some(Ok(response)) => warn!(
+                "Unable to delete abandoned session {}: hub responded with {}",
+                session_id,
+                response.status()
+            ),
+            Some(Err(e)) => warn!("Unable to delete abandoned session {}: {}", session_id, e),
+            None => warn!("Unable to delete abandoned session {}: hub didn't respond", session_id),
+        }
+    });
+}
+
+/// Wait for a future to complete, or give up with `None` once the limit, if any, has passed.
+async fn within<F: Future>(limit: Option<Duration>, future: F) -> Option<F::Output> {
+    match limit {
+        Some(limit) => timeout(limit, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// The name of the hub a routing decision was made for, or its endpoint if it's no longer registered.
+fn hub_name(state: &HubRouterState, routing_decision: &RoutingDecision) -> String {
+    match state.hubs.get(&routing_decision.hub_uuid) {
+        Some(hub) => hub.meta.name.clone(),
+        None => routing_decision.hub_endpoint.to_string(),
+    }
+}
+
+/// Count a timeout against a hub, and report it as a WebDriver `timeout` error naming the hub.
+fn hub_timeout(
+    routing_decision: &RoutingDecision,
+    stage: &'static str,
+    state: &HubRouterState,
+    what: String,
+) -> HubRouterError {
+    METRICS.record_hub_timeout(routing_decision.hub_uuid, stage);
+    HubRouterError::Timeout(format!("hub {} {}", hub_name(state, routing_decision), what))
+}
+
 /// Handle a new session request.
 /// Requires special logic as this is when a Selenium session is assigned an ID.
 /// A response to a new session request contains the ID, which we need to assign
 /// to the same hub which we sent the new session request to, so we must hold
-/// on to the response object and parse it before sending it back to the test
+/// on to the response object and parse it before sending it back to the test.
+///
+/// If the chosen hub fails to create the session, the request is routed again
+/// amongst the hubs which haven't been tried yet, up to the configured number
+/// of attempts. No new attempt is started once the retry deadline has passed.
 async fn handle_new_session_request(
-    mut req: Request<Body>,
+    req: Request<Body>,
+    client_address: Option<SocketAddr>,
     routing_map: Arc<RoutingPrecedentMap>,
     state: Arc<HubRouterState>,
 ) -> Result<Response<Body>, HubRouterError> {
-    let (requests, reconstructed_request) =
-        extract_capabilities_from_new_session_request(req).await?;
-    req = reconstructed_request;
+    let (requests, mut parts, body) = extract_capabilities_from_new_session_request(req).await?;
+
+    let (max_attempts, retry_deadline, proxy_clients) = match state.configs.read() {
+        Ok(conf) => (
+            conf.new_session_max_attempts,
+            conf.new_session_retry_deadline,
+            conf.proxy_clients.clone(),
+        ),
+        Err(e) => {
+            warn!("RwLock was poisoned reading new session retry configs: {}", e);
+            let conf = HubRouterPrimitiveConfigs::default();
+            (
+                conf.new_session_max_attempts,
+                conf.new_session_retry_deadline,
+                conf.proxy_clients,
+            )
+        }
+    };
 
-    let routing_decision =
-        make_routing_decision(None, Some(requests), routing_map.clone(), state.clone())?;
+    // Every record in the session history about this request shares its request ID
+    let mut audit = SessionAuditRecord {
+        request_id: Some(Uuid::new_v4().to_string()),
+        browser_name: requests.iter().find_map(|r| r.browserName.clone()),
+        requested_capabilities: serde_json::to_value(&requests).ok(),
+        ..SessionAuditRecord::new(SessionEvent::Requested)
+    };
+
+    // Held until the request finishes, so that it counts against the client's quota while it's starting
+    let client = proxy_clients
+        .identify(&parts.headers, &requests)
+        .map_err(|e| audit_failure(&audit, e.into()))?;
+    audit.client = client.as_ref().map(|(name, _)| name.clone());
+    proxy_clients.strip_credentials(&mut parts.headers);
+    AUDIT_LOG.record(audit.clone());
+    let _admission = match &client {
+        Some((name, quota)) => Some(
+            state
+                .client_usage
+                .admit(name, quota, &routing_map)
+                .map_err(|e| audit_failure(&audit, e.into()))?,
+        ),
+        None => None,
+    };
+    let max_attempts = u64::max(max_attempts, 1);
+    let retry_deadline = Instant::now() + Duration::from_secs(retry_deadline);
+
+    let mut excluded_hubs: HashSet<Uuid> = HashSet::new();
+    let mut failures: Vec<String> = vec![];
+
+    for attempt in 1..=max_attempts {
+        let (mut routing_decision, reservation) = match route_new_session(
+            requests.clone(),
+            &excluded_hubs,
+            (attempt > 1).then_some(retry_deadline),
+            routing_map.clone(),
+            state.clone(),
+        )
+        .await
+        {
+            Ok(decision) => decision,
+            Err(e) if failures.is_empty() => return Err(audit_failure(&audit, e)),
+            Err(e) => {
+                failures.push(e.to_string());
+                break;
+            }
+        };
+
+        let hub_name = hub_name(&state, &routing_decision);
+        info!(
+            "New session attempt {}/{} for {:?} routed to hub {}",
+            attempt, max_attempts, requests, hub_name
+        );
+        let routed = SessionAuditRecord {
+            time: SystemTime::now(),
+            hub_uuid: Some(routing_decision.hub_uuid.to_string()),
+            hub_name: Some(hub_name.clone()),
+            requested_capabilities: None,
+            ..audit.clone()
+        };
+        AUDIT_LOG.record(SessionAuditRecord {
+            event: SessionEvent::Routed,
+            message: Some(format!("attempt {}/{}", attempt, max_attempts)),
+            ..routed.clone()
+        });
+
+        match attempt_new_session(&parts, &body, &routing_decision, &state)
+            .await
+            .map_err(|e| audit_failure(&routed, e))?
+        {
+            NewSessionAttempt::Created(session_id, capabilities, response) => {
+                reservation.created();
+                routing_decision.client = client.map(|(name, _)| name);
+                routing_decision.client_address = client_address;
+                routing_decision.browser_name =
+                    capabilities.browserName.clone().or(routed.browser_name.clone());
+                routing_decision.browser_version = capabilities.browserVersion.clone();
+                routing_decision.platform_name = capabilities.platformName.clone();
+                AUDIT_LOG.record(SessionAuditRecord {
+                    time: SystemTime::now(),
+                    event: SessionEvent::Created,
+                    session_id: Some(session_id.clone()),
+                    browser_name: routing_decision.browser_name.clone(),
+                    capabilities: serde_json::to_value(&capabilities).ok(),
+                    ..routed
+                });
+                routing_map.insert(session_id, routing_decision);
+                return Ok(response);
+            }
+            NewSessionAttempt::Rejected(reason) => {
+                warn!(
+                    "Hub {} rejected new session attempt {}/{}: {}",
+                    hub_name, attempt, max_attempts, reason
+                );
+                AUDIT_LOG.record(SessionAuditRecord {
+                    time: SystemTime::now(),
+                    event: SessionEvent::Rejected,
+                    message: Some(reason.clone()),
+                    ..routed
+                });
+                failures.push(format!("{}: {}", hub_name, reason));
+                excluded_hubs.insert(routing_decision.hub_uuid);
+            }
+        }
+
+        if Instant::now() >= retry_deadline {
+            break;
+        }
+    }
 
-    apply_routing_decision(&mut req, &routing_decision.hub_endpoint)?;
+    Err(audit_failure(
+        &audit,
+        HubRouterError::SessionCreationError(format!(
+            "Could not create session after {} attempt(s) (this is likely because the hubs are overloaded, increasing the hubs' resource limits may be helpful): {}",
+            failures.len(),
+            failures.join(" | ")
+        )),
+    ))
+}
 
-    let client = Client::new();
-    let response = client.request(req).await?;
+/// Record in the session history that a new session request failed, and why.
+fn audit_failure(audit: &SessionAuditRecord, error: HubRouterError) -> HubRouterError {
+    AUDIT_LOG.record(SessionAuditRecord {
+        time: SystemTime::now(),
+        event: SessionEvent::Failed,
+        requested_capabilities: None,
+        message: Some(error.to_string()),
+        ..audit.clone()
+    });
+    error
+}
 
-    let (parts, body) = response.into_parts();
-    let bytes = hyper::body::to_bytes(body).await?;
+#[tokio::test]
+async fn test_new_session_retries() {
+    use crate::{
+        hub::{Hub, HubReadiness},
+        schema::HubStatusStereotypeJSONSchema,
+        strategy::RoutingStrategyKind,
+    };
+    use std::{
+        collections::BTreeMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use url::Url;
+    use warp::Filter;
 
-    let maybe_new_session_response: Result<NewSessionResponse, serde_json::Error> =
-        serde_json::from_slice(&bytes);
-    let new_session_response = match maybe_new_session_response {
-        Ok(res) => res,
-        Err(_) => {
-            return Err(HubRouterError::SessionCreationError(format!("Could not create session (this is likely because the hub is overloaded, increasing the hub's resource limits may be helpful): {}", String::from_utf8_lossy(&bytes))))
+    // A hub which either creates every session it's asked for, or rejects them all
+    let fake_hub = |creates: bool| {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let route = warp::post().and(warp::path("session")).map(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let (status, body) = if creates {
+                (
+                    StatusCode::OK,
+                    serde_json::json!({"value": {"sessionId": Uuid::new_v4().to_string(), "capabilities": {"browserName": "chrome"}}}),
+                )
+            } else {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    serde_json::json!({"value": {"error": "session not created", "message": "no room"}}),
+                )
+            };
+            warp::reply::with_status(warp::reply::json(&body), status)
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::task::spawn(server);
+        (Url::parse(&format!("http://{}/", addr)).unwrap(), requests)
+    };
+    let chrome = HubStatusStereotypeJSONSchema {
+        browserName: String::from("chrome"),
+        platformName: String::from("linux"),
+        browserVersion: None,
+        additional: BTreeMap::new(),
+    };
+    let router = |max_attempts: u64, retry_deadline: u64, queue_size: u64| {
+        let state = Arc::new(HubRouterState::default());
+        if let Ok(mut conf) = state.configs.write() {
+            conf.routing_strategy = RoutingStrategyKind::Priority;
+            conf.new_session_max_attempts = max_attempts;
+            conf.new_session_retry_deadline = retry_deadline;
+            conf.new_session_queue_size = queue_size;
+            conf.new_session_wait_timeout = 300;
         }
+        state
     };
-    let session_id = new_session_response.value.sessionId;
-    routing_map.insert(session_id.to_string(), routing_decision);
-    Ok(hyper::Response::from_parts(parts, hyper::Body::from(bytes)))
+    let register = |state: &HubRouterState, url: Url, priority: u32, active: u8| {
+        let mut hub = Hub::new(url);
+        hub.meta.priority = priority;
+        hub.state.readiness = HubReadiness::Ready;
+        hub.state.stereotypes.insert(chrome.clone());
+        hub.state.fullness.insert(chrome.clone(), (active, 2));
+        let uuid = hub.meta.uuid;
+        state.hubs.insert(uuid, hub);
+        uuid
+    };
+    let new_session = |state: Arc<HubRouterState>, sessions: Arc<RoutingPrecedentMap>| async move {
+        let req = Request::post("/session")
+            .body(Body::from(r#"{"capabilities": {"alwaysMatch": {"browserName": "chrome"}}}"#))
+            .unwrap();
+        handle(req, None, sessions, state).await.unwrap().status()
+    };
+    let hits = |requests: &Arc<AtomicUsize>| requests.swap(0, Ordering::SeqCst);
+    let (rejecting, rejected) = fake_hub(false);
+    let (creating, created) = fake_hub(true);
+
+    // A rejected request is retried on a hub which hasn't been tried yet
+    let state = router(3, 300, 0);
+    register(&state, rejecting.clone(), 0, 0);
+    let second = register(&state, creating.clone(), 1, 0);
+    let sessions = Arc::new(RoutingPrecedentMap::default());
+    assert!(new_session(state, sessions.clone()).await.is_success());
+    assert_eq!((hits(&rejected), hits(&created)), (1, 1));
+    assert_eq!(sessions.sessions_for_hub(&second).len(), 1);
+
+    // Each hub is only tried once, however many attempts are allowed
+    let state = router(3, 300, 0);
+    register(&state, rejecting.clone(), 0, 0);
+    register(&state, rejecting.clone(), 1, 0);
+    assert!(!new_session(state, Arc::default()).await.is_success());
+    assert_eq!(hits(&rejected), 2);
+
+    // No more attempts are made than allowed
+    let state = router(1, 300, 0);
+    register(&state, rejecting.clone(), 0, 0);
+    register(&state, creating.clone(), 1, 0);
+    assert!(!new_session(state, Arc::default()).await.is_success());
+    assert_eq!((hits(&rejected), hits(&created)), (1, 0));
+
+    // Nor once the retry deadline has passed
+    let state = router(3, 0, 0);
+    register(&state, rejecting.clone(), 0, 0);
+    register(&state, creating.clone(), 1, 0);
+    assert!(!new_session(state, Arc::default()).await.is_success());
+    assert_eq!((hits(&rejected), hits(&created)), (1, 0));
+
+    // A retry waiting for a free slot gives up at the retry deadline, not the queue's wait timeout
+    let state = router(3, 1, 10);
+    register(&state, rejecting.clone(), 0, 0);
+    register(&state, creating.clone(), 1, 2);
+    let started = Instant::now();
+    let status = timeout(Duration::from_secs(10), new_session(state, Arc::default())).await;
+    assert!(!status.unwrap().is_success());
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!((hits(&rejected), hits(&created)), (1, 0));
 }
 
 
 /// A general handler for all other endpoints, simply forwards a test to the Hub which its session ID
-/// is associated with, or a random one if no precedent exists.
+/// is associated with, or a random one if the request isn't for a session.
+/// Sessions with no precedent (such as those created through another replica)
+/// are looked up from our peers and hubs, and are invalid if nobody knows of them.
 async fn forward_request(
     mut req: Request<Body>,
-    _routing_map: Arc<RoutingPrecedentMap>,
+    routing_map: Arc<RoutingPrecedentMap>,
     state: Arc<HubRouterState>,
 ) -> Result<Response<Body>, HubRouterError> {
     let maybe_session_id = extract_session_id(&req);
-    let routing_decision = make_routing_decision(maybe_session_id, None, _routing_map, state)?;
-    apply_routing_decision(&mut req, &routing_decision.hub_endpoint)?;
+    // Held until the hub answers, so that the session isn't reaped as idle while it waits
+    let _command = match &maybe_session_id {
+        Some(session_id) => {
+            if routing_map.get(session_id).is_none()
+                && resolve_unknown_session(session_id, routing_map.clone(), state.clone())
+                    .await
+                    .is_none()
+            {
+                return Err(RoutingError::UnknownSession(format!(
+                    "No hub is running session {}",
+                    session_id
+                ))
+                .into());
+            }
+            Some(routing_map.start_command(session_id))
+        }
+        None => None,
+    };
 
-    let client = Client::new();
-    HubRouterError::wrap_err(client.request(req).await)
+    let routing_decision = make_routing_decision(
+        maybe_session_id,
+        None,
+        &HashSet::new(),
+        routing_map,
+        state.clone(),
+    )?;
+    state.read_configs(|conf| conf.proxy_clients.strip_credentials(req.headers_mut()));
+    let client = address_to_hub(&mut req, &routing_decision, &state)?;
+    let limit = match state.configs.read() {
+        Ok(conf) => conf.proxy_timeouts.for_command(req.method(), req.uri().path()),
+        Err(e) => {
+            warn!("RwLock was poisoned reading command timeouts: {}", e);
+            HubRouterPrimitiveConfigs::default()
+                .proxy_timeouts
+                .for_command(req.method(), req.uri().path())
+        }
+    };
+
+    let command = format!("{} {}", req.method(), req.uri().path());
+    match within(limit, client.request(req)).await {
+        Some(Err(e)) if is_connect_timeout(&e) => Err(hub_timeout(
+            &routing_decision,
+            "connect",
+            &state,
+            format!("couldn't be connected to for {}: {}", command, e),
+        )),
+        Some(response) => HubRouterError::wrap_err(response),
+        None => Err(hub_timeout(
+            &routing_decision,
+            "command",
+            &state,
+            format!(
+                "didn't respond to {} within {:?}",
+                command,
+                limit.unwrap_or_default()
+            ),
+        )),
+    }
 }
 
 
@@ -224,12 +1156,87 @@ async fn handle_delete_session_request(
     routing_map: Arc<RoutingPrecedentMap>,
     state: Arc<HubRouterState>,
 ) -> Result<Response<Body>, HubRouterError> {
-    let session_id = extract_session_id(&req);
-    let result = forward_request(req, routing_map.clone(), state).await;
-    routing_map.remove(&session_id.unwrap());
+    let session_id = extract_session_id(&req).unwrap();
+    let decision = routing_map.get(&session_id);
+    let result = forward_request(req, routing_map.clone(), state.clone()).await;
+    if let Some(decision) = decision.or_else(|| routing_map.get(&session_id)) {
+        if result.as_ref().is_ok_and(|r| r.status().is_success()) {
+            if let Some(mut hub) = state.hubs.get_mut(&decision.hub_uuid) {
+                hub.state.free_slot(&decision);
+            }
+        }
+        AUDIT_LOG.record(SessionAuditRecord {
+            message: result.as_ref().err().map(|e| e.to_string()),
+            ..SessionAuditRecord::for_session(SessionEvent::Deleted, &session_id, &decision, &state)
+        });
+    }
+    routing_map.remove(&session_id);
+    state.new_session_queue.notify_slot_freed();
+    deregister_drained_hubs(&state, &routing_map);
     result
 }
 
+/// Close a session on its hub and forget it, for sessions which their client
+/// won't delete itself. The session is forgotten even if the hub can't be told
+/// to close it, in which case the error says why.
+pub async fn close_session(
+    session_id: &str,
+    event: SessionEvent,
+    reason: &str,
+    routing_map: &RoutingPrecedentMap,
+    state: &HubRouterState,
+) -> Result<(), String> {
+    let decision = match routing_map.get(session_id) {
+        Some(decision) => decision,
+        None => return Err(format!("No hub is running session {}", session_id)),
+    };
+    let path = format!("/session/{}", session_id);
+    let limit = state.read_configs(|conf| conf.proxy_timeouts.for_command(&Method::DELETE, &path));
+
+    let mut req = Request::new(Body::empty());
+    *req.method_mut() = Method::DELETE;
+    // Whether the hub closed the session, rather than having already ended it itself
+    let result = match path.parse() {
+        Ok(uri) => {
+            *req.uri_mut() = uri;
+            match address_to_hub(&mut req, &decision, state) {
+                Ok(client) => {
+                    match within(limit, client.request(req)).await {
+                        Some(Ok(response)) if response.status().is_success() => Ok(true),
+                        Some(Ok(response)) if response.status() == StatusCode::NOT_FOUND => Ok(false),
+                        Some(Ok(response)) => Err(format!("hub responded with {}", response.status())),
+                        Some(Err(e)) => Err(e.to_string()),
+                        None => Err(format!(
+                            "hub didn't respond within {:?}",
+                            limit.unwrap_or_default()
+                        )),
+                    }
+                }
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        Err(e) => Err(format!("invalid session ID {}: {}", session_id, e)),
+    };
+
+    // A hub which had already ended the session stops counting its slot by the next healthcheck
+    if result == Ok(true) {
+        if let Some(mut hub) = state.hubs.get_mut(&decision.hub_uuid) {
+            hub.state.free_slot(&decision);
+        }
+    }
+    AUDIT_LOG.record(SessionAuditRecord {
+        message: Some(match &result {
+            Ok(_) => reason.to_string(),
+            Err(e) => format!("{} (unable to close it on the hub: {})", reason, e),
+        }),
+        ..SessionAuditRecord::for_session(event, session_id, &decision, state)
+    });
+    routing_map.remove(session_id);
+    state.new_session_queue.notify_slot_freed();
+    deregister_drained_hubs(state, routing_map);
+    result.map(|_| ())
+}
+
 /// Primary handler function for forwarding requests onto downstream Hubs.
 /// This function will be called by the primary listener for forwarding requests.
 /// 
@@ -239,6 +1246,7 @@ async fn handle_delete_session_request(
 /// if no association exists in the routing precedent map
 pub async fn handle(
     req: Request<Body>,
+    client_address: Option<SocketAddr>,
     routing_map: Arc<RoutingPrecedentMap>,
     state: Arc<HubRouterState>,
 ) -> Result<Response<Body>, hyper::Error> {
@@ -246,19 +1254,20 @@ pub async fn handle(
 
     let response: Result<Response<Body>, HubRouterError> = async {
         if is_request_new_session(&req) {
-            return handle_new_session_request(req, routing_map, state).await;
+            return handle_new_session_request(req, client_address, routing_map, state).await;
         } else if is_delete_session(&req) && maybe_session_id.is_some() {
             return handle_delete_session_request(req, routing_map, state).await;
         }
-        return forward_request(req, routing_map, state).await;
+        forward_request(req, routing_map, state).await
     }
     .await;
 
+    if let Err(HubRouterError::RoutingError(e)) = &response {
+        METRICS.record_routing_error(e);
+    }
+
     match response {
         Ok(response) => Ok(response),
-        Err(e) => Ok(Response::builder()
-            .status(500)
-            .body(Body::from(format!("Hub Router error: {:#?}", e)))
-            .unwrap()),
+        Err(e) => Ok(e.to_webdriver_response()),
     }
 }
@@ -3,47 +3,51 @@
 
 
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
     hash::{Hash, Hasher},
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
 };
 
 use base64::Engine;
-use hyper::{Body, Client, Method, Request};
+use hyper::{Body, Method, Request};
 use serde::{Deserialize, Serialize};
-use tokio::{task::JoinSet, time::timeout};
+use tokio::{
+    task::JoinSet,
+    time::{timeout, Instant},
+};
 use utoipa::ToSchema;
 
 use crate::{
-    routing::Endpoint,
+    audit::{SessionAuditRecord, SessionEvent, AUDIT_LOG},
+    credentials::{apply_hub_auth, redact_url, without_credentials, HubAuth},
+    metrics::METRICS,
+    routing::{RoutingDecision, RoutingPrecedentMap},
     schema::{
         HubStatusJSONSchema, HubStatusNodeJSONSchema, HubStatusNodeSlotIDJSONSchema,
         HubStatusNodeSlotJSONSchema, HubStatusNodeSlotSessionJSONSchema, HubStatusOSInfoJSONSchema,
         HubStatusStereotypeJSONSchema, HubStatusValueJSONSchema, NewSessionRequestCapability,
     },
-    state::{HubRouterPrimitiveConfigs, HubRouterState},
+    state::{ConfigInterval, HubRouterPrimitiveConfigs, HubRouterState},
 };
 use log::{info, warn};
 use url::Url;
 use uuid::Uuid;
 
 /// HubReadiness represents the current status of a Hub as a enum.
-#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
 pub enum HubReadiness {
     /// not responding to /status requests, or responds to /status but has no nodes
+    #[default]
     Unhealthy,
 
     /// response to /status with at least a single healthy node
     Ready,
 }
 
-impl Default for HubReadiness {
-    fn default() -> Self {
-        Self::Unhealthy
-    }
-}
-
 /// Hub is an internal representation for a remote Selenium Hub instance
 /// we wish to forward tests to. This will be serialized to configuration files.
 /// To view runtime statistics and information about a Hub, this type can be cast
@@ -71,45 +75,266 @@ pub struct HubMetadata {
     #[serde(serialize_with = "crate::utils::serialize_uuid")]
     #[serde(deserialize_with = "crate::utils::deserialize_uuid")]
     pub uuid: uuid::Uuid,
+
+    /// Relative share of new sessions this hub receives under the weighted random
+    /// routing strategy, e.g. 7 and 3 for a 70/30 split between two hubs. Zero means
+    /// the hub is only used when no other hub is available.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+
+    /// Lower values are preferred by the priority routing strategy,
+    /// so overflow-only hubs should be given a higher value.
+    #[serde(default)]
+    pub priority: u32,
+
+    /// The most sessions the hub may be running before it stops receiving new ones.
+    #[serde(default)]
+    pub max_concurrent_sessions: Option<u32>,
+
+    /// Disabled hubs receive no new sessions.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+
+    /// Draining hubs receive no new sessions, but keep serving the sessions
+    /// they are already running.
+    #[serde(default)]
+    pub draining: bool,
+
+    /// Remove the hub once it has finished draining, and no sessions are routed to it.
+    #[serde(default)]
+    pub deregister_when_drained: bool,
+
+    /// Accept any certificate from an `https://` hub, such as a self-signed one.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+
+    /// Credentials sent with every request to the hub. Without these, any
+    /// username and password in the hub's URL are used instead.
+    #[serde(default)]
+    pub auth: Option<HubAuth>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl HubMetadata {
+    /// Whether new sessions may be routed to this hub.
+    pub fn accepts_new_sessions(&self) -> bool {
+        self.enabled && !self.draining
+    }
+
+    /// A copy which is safe to return from the API, with secrets hidden.
+    pub fn redacted(&self) -> Self {
+        Self {
+            url: redact_url(&self.url),
+            auth: self.auth.as_ref().map(HubAuth::redacted),
+            ..self.clone()
+        }
+    }
 }
 
 /// Transient state associated with a hub at runtime.
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, ToSchema)]
 pub struct HubState {
+    /// The fullness of each stereotype, as the hub last reported it
     #[serde(skip)] // Skip for now, serde doesn't like a struct being the key
-    pub fullness: HashMap<NewSessionRequestCapability, (u8, u8)>,
+    pub fullness: HashMap<HubStatusStereotypeJSONSchema, (u8, u8)>,
     pub stereotypes: HashSet<HubStatusStereotypeJSONSchema>,
     pub readiness: HubReadiness,
     pub consecutive_healthcheck_failures: u8,
+
+    /// Slots we've taken or freed which the hub hasn't reported yet
+    #[serde(skip)]
+    adjustments: Vec<FullnessAdjustment>,
+
+    /// Sessions on which the hub and the routing map disagreed at the last healthcheck
+    #[serde(skip)]
+    unreconciled_sessions: HashSet<String>,
+
+    /// The hub's nodes, as it last reported them
+    #[serde(skip)]
+    pub nodes: Vec<HubNode>,
+}
+
+/// A node behind a hub, as the hub last reported it.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct HubNode {
+    pub id: String,
+    pub uri: String,
+    pub version: String,
+    pub os_name: String,
+    pub os_arch: String,
+    pub os_version: String,
+    /// `UP`, `DRAINING` or `DOWN`. Only nodes which are up count towards the hub's capacity.
+    pub availability: String,
+    /// The most sessions the node runs at once, whatever its slots.
+    pub max_sessions: u32,
+    pub slots: usize,
+    pub active_sessions: usize,
+    /// How many sessions the node counts for in the hub's capacity.
+    pub capacity: usize,
+}
+
+impl From<&HubStatusNodeJSONSchema> for HubNode {
+    fn from(node: &HubStatusNodeJSONSchema) -> Self {
+        HubNode {
+            id: node.id.clone(),
+            uri: node.uri.clone(),
+            version: node.version.clone(),
+            os_name: node.osInfo.name.clone(),
+            os_arch: node.osInfo.arch.clone(),
+            os_version: node.osInfo.version.clone(),
+            availability: node.availability.clone(),
+            max_sessions: node.maxSessions,
+            slots: node.slots.len(),
+            active_sessions: node.slots.iter().filter(|slot| slot.session.is_some()).count(),
+            capacity: if node_is_up(node) {
+                node.slots.len().min(node.maxSessions as usize)
+            } else {
+                0
+            },
+        }
+    }
+}
+
+/// Whether a node is accepting new sessions, rather than draining or down.
+fn node_is_up(node: &HubStatusNodeJSONSchema) -> bool {
+    node.availability.eq_ignore_ascii_case("UP")
+}
+
+/// A slot taken by a new session, or freed by a deleted one, since the hub last reported its fullness.
+#[derive(Debug, Clone)]
+struct FullnessAdjustment {
+    id: u64,
+    stereotype: HubStatusStereotypeJSONSchema,
+    taken: bool,
+
+    /// When the change happened on the hub, or `None` for a new session
+    /// which it's still creating. Changes are reported by any `/status`
+    /// requested after they happened.
+    settled: Option<Instant>,
 }
 
+static NEXT_ADJUSTMENT_ID: AtomicU64 = AtomicU64::new(0);
+
 impl HubState {
 
     /// Compute a tuple of (currently running sessions, maximum capacity)
     /// for a particular browser/OS request.
     /// Dividing these numbers gives the percent fullness for running 
     /// a particular browser/OS test on this hub.
+    /// Sessions we've started or deleted since the hub last reported its
+    /// fullness are accounted for, so that a burst of new sessions doesn't
+    /// all see the same free slots. Nodes share their `maxSessions` between
+    /// stereotypes, so the capacity of several stereotypes together is at
+    /// most what the hub's nodes can run at once.
     pub fn get_stereotype_fullness(
         &self,
         maybe_capability: Option<NewSessionRequestCapability>,
     ) -> (u8, u8) {
         let (mut active_sessions, mut max_sessions) = (0, 0);
-        let capability = match maybe_capability {
-            Some(c) => c,
-            None => NewSessionRequestCapability {
-                browserName: None,
-                platformName: None,
-            },
-        };
-        for (hub_capability, (active, max)) in &self.fullness {
-            if capability.satisfied_by(hub_capability) {
+        let capability = maybe_capability.unwrap_or_default();
+        for stereotype in self.fullness.keys() {
+            if capability.satisfied_by(stereotype) {
+                let (active, max) = self.effective_fullness(stereotype);
                 active_sessions += active;
                 max_sessions += max;
             }
         }
+        if !self.nodes.is_empty() {
+            let capacity: usize = self.nodes.iter().map(|node| node.capacity).sum();
+            max_sessions = max_sessions.min(u8::try_from(capacity).unwrap_or(u8::MAX));
+            active_sessions = active_sessions.min(max_sessions);
+        }
         (active_sessions, max_sessions)
     }
 
+    /// The reported fullness of a stereotype, adjusted by the slots taken and freed since.
+    fn effective_fullness(&self, stereotype: &HubStatusStereotypeJSONSchema) -> (u8, u8) {
+        let (active, max) = self.fullness.get(stereotype).copied().unwrap_or_default();
+        let adjusted = self
+            .adjustments
+            .iter()
+            .filter(|a| a.stereotype == *stereotype)
+            .fold(active as i32, |active, a| active + if a.taken { 1 } else { -1 });
+        (adjusted.clamp(0, max as i32) as u8, max)
+    }
+
+    /// Take a free slot satisfying the first possible capability, until the new
+    /// session is either created or given up on, so that other new sessions routed
+    /// before the next healthcheck don't pile onto the same slot.
+    fn take_slot(&mut self, capabilities: &[NewSessionRequestCapability]) -> Option<u64> {
+        let stereotype = capabilities.iter().find_map(|capability| {
+            self.fullness.keys().find(|stereotype| {
+                let (active, max) = self.effective_fullness(stereotype);
+                capability.satisfied_by(stereotype) && active < max
+            })
+        })?;
+        let id = NEXT_ADJUSTMENT_ID.fetch_add(1, Ordering::Relaxed);
+        self.adjustments.push(FullnessAdjustment {
+            id,
+            stereotype: stereotype.clone(),
+            taken: true,
+            settled: None,
+        });
+        Some(id)
+    }
+
+    /// Settle a slot taken for a new session, keeping it if the session was created.
+    fn settle_slot(&mut self, id: u64, created: bool) {
+        if created {
+            if let Some(adjustment) = self.adjustments.iter_mut().find(|a| a.id == id) {
+                adjustment.settled = Some(Instant::now());
+            }
+        } else {
+            self.adjustments.retain(|a| a.id != id);
+        }
+    }
+
+    /// Free the slot of a session which has been deleted from the hub.
+    pub fn free_slot(&mut self, decision: &RoutingDecision) {
+        let capability = NewSessionRequestCapability {
+            browserName: decision.browser_name.clone(),
+            platformName: decision.platform_name.clone(),
+            ..Default::default()
+        };
+        let stereotype = self.fullness.keys().find(|stereotype| {
+            capability.satisfied_by(stereotype) && self.effective_fullness(stereotype).0 > 0
+        });
+        if let Some(stereotype) = stereotype {
+            self.adjustments.push(FullnessAdjustment {
+                id: NEXT_ADJUSTMENT_ID.fetch_add(1, Ordering::Relaxed),
+                stereotype: stereotype.clone(),
+                taken: false,
+                settled: Some(Instant::now()),
+            });
+        }
+    }
+
+    /// Replace the fullness with what the hub reported in a `/status` requested
+    /// at the given time, keeping only the changes it couldn't have known about.
+    pub fn reconcile_fullness(
+        &mut self,
+        fullness: HashMap<HubStatusStereotypeJSONSchema, (u8, u8)>,
+        requested_at: Instant,
+    ) {
+        self.fullness = fullness;
+        self.adjustments
+            .retain(|a| a.settled.is_none_or(|settled| settled >= requested_at));
+    }
+
+    /// Whether this hub has at least one free slot for the given capability.
+    /// A hub which hasn't reported any slots yet is assumed to have room.
+    pub fn has_free_slot(&self, capability: &NewSessionRequestCapability) -> bool {
+        let (active, max) = self.get_stereotype_fullness(Some(capability.clone()));
+        max == 0 || active < max
+    }
+
     pub fn get_readiness(&self) -> HubReadiness {
         self.readiness
     }
@@ -122,6 +347,57 @@ impl Default for HubState {
             stereotypes: HashSet::new(),
             readiness: HubReadiness::Unhealthy,
             consecutive_healthcheck_failures: 0,
+            adjustments: vec![],
+            unreconciled_sessions: HashSet::new(),
+            nodes: vec![],
+        }
+    }
+}
+
+/// A slot on a hub taken for a new session while the hub creates it. The slot
+/// is freed again when this is dropped, unless the session was created.
+pub struct SlotReservation {
+    state: Arc<HubRouterState>,
+    hub_uuid: Uuid,
+    id: Option<u64>,
+}
+
+impl SlotReservation {
+    /// Take a slot on the hub for the first of the capabilities it has room for.
+    /// Hubs which haven't reported any slots have nothing to take, but may still be routed to.
+    pub fn take(
+        state: Arc<HubRouterState>,
+        hub_uuid: Uuid,
+        capabilities: &[NewSessionRequestCapability],
+    ) -> Self {
+        let id = state
+            .hubs
+            .get_mut(&hub_uuid)
+            .and_then(|mut hub| hub.state.take_slot(capabilities));
+        Self {
+            state,
+            hub_uuid,
+            id,
+        }
+    }
+
+    /// The session was created, so its slot stays taken until the hub reports it.
+    pub fn created(mut self) {
+        if let Some(id) = self.id.take() {
+            if let Some(mut hub) = self.state.hubs.get_mut(&self.hub_uuid) {
+                hub.state.settle_slot(id, true);
+            }
+        }
+    }
+}
+
+impl Drop for SlotReservation {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            if let Some(mut hub) = self.state.hubs.get_mut(&self.hub_uuid) {
+                hub.state.settle_slot(id, false);
+            }
+            self.state.new_session_queue.notify_slot_freed();
         }
     }
 }
@@ -149,24 +425,33 @@ impl Hub {
                 name: name.into(),
                 url,
                 uuid: uuid::Uuid::new_v4(),
+                weight: default_weight(),
+                priority: 0,
+                max_concurrent_sessions: None,
+                enabled: default_enabled(),
+                draining: false,
+                deregister_when_drained: false,
+                insecure_skip_verify: false,
+                auth: None,
             },
             state: HubState::default(),
         }
     }
 
+    /// Whether the hub is running fewer sessions than its configured cap.
+    pub fn under_session_cap(&self) -> bool {
+        match self.meta.max_concurrent_sessions {
+            Some(cap) => (self.state.get_stereotype_fullness(None).0 as u32) < cap,
+            None => true,
+        }
+    }
+
     /// Check to make sure that the current Hub will support the desired capability.
     pub fn can_satisfy_capability(&self, capability: &NewSessionRequestCapability) -> bool {
-        self.state.stereotypes.iter().any(|stereotype| {
-            let satisfies_browser = capability.browserName.is_none()
-                || (&stereotype.browserName)
-                    .eq_ignore_ascii_case(capability.browserName.as_ref().unwrap());
-
-            let satisfies_platform_name = capability.platformName.is_none()
-                || (&stereotype.platformName)
-                    .eq_ignore_ascii_case(capability.platformName.as_ref().unwrap());
-
-            satisfies_browser && satisfies_platform_name
-        })
+        self.state
+            .stereotypes
+            .iter()
+            .any(|stereotype| capability.satisfied_by(stereotype))
     }
 
     pub fn clone_from_meta(&self) -> Self {
@@ -176,6 +461,14 @@ impl Hub {
         }
     }
 
+    /// A copy which is safe to return from the API, with secrets hidden.
+    pub fn redacted(&self) -> Self {
+        Self {
+            meta: self.meta.redacted(),
+            state: self.state.clone(),
+        }
+    }
+
     /// Check to make sure a Hub is both ready and available, and satisfies the
     /// desired capability.
     #[allow(unused)]
@@ -186,13 +479,12 @@ impl Hub {
     /// Called when a hub fails a healthcheck.
     /// If a hub fails 3 consecutive healthchecks, it will be marked unhealthy
     pub fn fail_healthcheck(&mut self) -> HubReadiness {
-        if self.state.consecutive_healthcheck_failures < u8::MAX {
-            self.state.consecutive_healthcheck_failures += 1;
-        }
+        self.state.consecutive_healthcheck_failures =
+            self.state.consecutive_healthcheck_failures.saturating_add(1);
         if self.state.consecutive_healthcheck_failures >= 3 {
             self.state.readiness = HubReadiness::Unhealthy;
         }
-        return self.state.readiness;
+        self.state.readiness
     }
 
     /// Called when a hub succeeds a healthcheck.
@@ -200,34 +492,44 @@ impl Hub {
     pub fn succeed_healthcheck(&mut self) -> HubReadiness {
         self.state.consecutive_healthcheck_failures = 0;
         self.state.readiness = HubReadiness::Ready;
-        return self.state.readiness;
+        self.state.readiness
     }
 }
 
 /// Primary function to calculate the percentage of fullness of a hub based on its
-/// returned status API schema. Returns a map from capabilities (browser/OS pairs) to a
+/// returned status API schema. Returns a map from slot stereotypes to a
 /// tuple of (running sessions, session capacity).
+///
+/// Only nodes which are up count, and a node's free slots only count as far as
+/// its `maxSessions` allows, since it won't run more sessions than that at once
+/// however many slots it has. Each stereotype may use all of a node's spare
+/// sessions, so capacities of different stereotypes overlap and don't add up.
 pub fn compute_hub_fullness(
     status: &HubStatusJSONSchema,
-) -> HashMap<NewSessionRequestCapability, (u8, u8)> {
-    let mut map: HashMap<NewSessionRequestCapability, (u8, u8)> = HashMap::new();
+) -> HashMap<HubStatusStereotypeJSONSchema, (u8, u8)> {
+    let mut map: HashMap<HubStatusStereotypeJSONSchema, (u8, u8)> = HashMap::new();
+
+    for node in status.value.nodes.iter().filter(|node| node_is_up(node)) {
+        let running = node.slots.iter().filter(|slot| slot.session.is_some()).count();
+        let spare = (node.maxSessions as usize).saturating_sub(running);
 
-    for node in &status.value.nodes {
+        // Running sessions and free slots of each stereotype on this node
+        let mut slots: HashMap<&HubStatusStereotypeJSONSchema, (usize, usize)> = HashMap::new();
         for slot in &node.slots {
-            let key: NewSessionRequestCapability = slot.stereotype.clone().into();
-            if !map.contains_key(&key) {
-                map.insert(key.clone(), (0, 0));
-            }
-            match map.get(&key) {
-                Some((active_slots, total_slots)) => {
-                    let active = if slot.session.is_some() { 1 } else { 0 };
-                    map.insert(key, (active_slots + active, total_slots + 1));
-                }
-                None => {
-                    warn!("Map was corrupted - slot which we just inserted is not available");
-                }
+            let (active, free) = slots.entry(&slot.stereotype).or_default();
+            if slot.session.is_some() {
+                *active += 1;
+            } else {
+                *free += 1;
             }
         }
+
+        for (stereotype, (active, free)) in slots {
+            let capacity = active + free.min(spare);
+            let (active_slots, total_slots) = map.entry(stereotype.clone()).or_default();
+            *active_slots = active_slots.saturating_add(u8::try_from(active).unwrap_or(u8::MAX));
+            *total_slots = total_slots.saturating_add(u8::try_from(capacity).unwrap_or(u8::MAX));
+        }
     }
     map
 }
@@ -275,11 +577,15 @@ fn mock_status_schema(max_sessions: u32, num_nodes: u32, num_running: u32) -> Hu
                     stereotype: HubStatusStereotypeJSONSchema {
                         browserName: String::from("nil"),
                         platformName: String::from("nil"),
+                        browserVersion: None,
+                        additional: BTreeMap::new(),
                     },
                 }),
                 stereotype: HubStatusStereotypeJSONSchema {
                     browserName: String::from("nil"),
                     platformName: String::from("nil"),
+                    browserVersion: None,
+                    additional: BTreeMap::new(),
                 },
             };
 
@@ -302,45 +608,77 @@ enum HealthcheckErr {
     Timeout(String),
 }
 
+/// A hub's parsed `/status`, and when it was requested, or why it couldn't be had
+type HealthcheckResult = Result<(HubStatusJSONSchema, Instant), HealthcheckErr>;
+
+impl std::fmt::Display for HealthcheckErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthcheckErr::DeserializError(e) => write!(f, "unable to parse /status: {}", e),
+            HealthcheckErr::HyperError(e) => write!(f, "request failed: {}", e),
+            HealthcheckErr::Timeout(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// Deregister every hub which was set to be removed once drained, and which
+/// no longer has any sessions routed to it.
+pub fn deregister_drained_hubs(state: &HubRouterState, sessions: &RoutingPrecedentMap) {
+    let drained: Vec<Uuid> = state
+        .hubs
+        .iter()
+        .filter(|h| h.meta.draining && h.meta.deregister_when_drained)
+        .filter(|h| sessions.sessions_at_endpoint(&h.meta.url).is_empty())
+        .map(|h| *h.key())
+        .collect();
+
+    if drained.is_empty() {
+        return;
+    }
+
+    for uuid in drained {
+        if let Some((_, hub)) = state.hubs.remove(&uuid) {
+            info!("Hub {} has finished draining, deregistering it", hub.meta.name);
+        }
+    }
+
+    if let Err(e) = state.persist() {
+        warn!("Unable to persist deregistration of drained hubs: {}", e);
+    }
+}
 
 /// The long-running thread which polls hubs for their healthiness and fullness.
-pub async fn hub_healthcheck_thread(state: Arc<HubRouterState>) {
+pub async fn hub_healthcheck_thread(state: Arc<HubRouterState>, sessions: Arc<RoutingPrecedentMap>) {
     info!("starting healthcheck thread");
 
-    // Extract the healthcheck interval from config, and turn it into a tokio interval
-    let mut healthcheck_interval = match state.configs.read() {
-        Ok(conf) => {
-            tokio::time::interval(Duration::from_secs(conf.healthcheck_thread_interval))
-        },
-        Err(e) => {
-            warn!("Unable to acquire read lock for configs: {} - defaulting to 1 second healthcheck interval", e);
-            tokio::time::interval(Duration::from_secs(1))
-        },
-    };
+    // Follow the healthcheck interval in config, so that changes to it apply straight away
+    let mut healthcheck_interval =
+        ConfigInterval::new(state.clone(), |conf| conf.healthcheck_thread_interval);
 
     loop {
 
-        // Make a request to /status on each hub
-        let mut request_futures: JoinSet<(Uuid, Result<HubStatusJSONSchema, HealthcheckErr>)> = {
-            let mut join_set: JoinSet<(Uuid, Result<HubStatusJSONSchema, HealthcheckErr>)> =
-                JoinSet::new();
-            let endpoints: Vec<(Uuid, Endpoint)> = state
+        // Make a request to /status on each hub, noting when each was requested
+        let mut request_futures: JoinSet<(Uuid, HealthcheckResult)> = {
+            let mut join_set: JoinSet<(Uuid, HealthcheckResult)> = JoinSet::new();
+            let hubs: Vec<HubMetadata> = state
                 .clone()
                 .hubs
                 .iter()
-                .map(|h| (h.meta.uuid, h.meta.url.clone()))
+                .map(|h| h.meta.clone())
                 .collect();
 
-            for (hub_uuid, _url) in endpoints {
-                let client = Client::new();
-                let mut request_url = _url.clone();
+            for meta in hubs {
+                let hub_uuid = meta.uuid;
+                let client = state.clients.for_hub(meta.insecure_skip_verify);
+                let mut request_url = without_credentials(&meta.url);
                 request_url.set_path("/status");
 
-                let request: Request<Body> = Request::builder()
+                let mut request: Request<Body> = Request::builder()
                     .uri(request_url.to_string())
                     .method(Method::GET)
                     .body(hyper::body::Body::empty())
                     .unwrap();
+                apply_hub_auth(request.headers_mut(), &meta);
 
                 let state_clone = state.clone();
                 join_set.spawn(async move {
@@ -351,8 +689,10 @@ pub async fn hub_healthcheck_thread(state: Arc<HubRouterState>) {
                             HubRouterPrimitiveConfigs::default().healthcheck_thread_interval
                         }
                     };
+                    let request_start = Instant::now();
                     let response_result_with_timeout =
                         timeout(Duration::from_secs(interval), client.request(request)).await;
+                    METRICS.observe_healthcheck_latency(hub_uuid, request_start.elapsed());
                     match response_result_with_timeout {
                         Ok(response_result) => match response_result {
                             Ok(response) => {
@@ -363,7 +703,7 @@ pub async fn hub_healthcheck_thread(state: Arc<HubRouterState>) {
                                     serde_json::Error,
                                 > = serde_json::from_slice(&body_bytes);
                                 match parsed_struct_result {
-                                    Ok(parsed) => (hub_uuid, Ok(parsed)),
+                                    Ok(parsed) => (hub_uuid, Ok((parsed, request_start))),
                                     Err(e) => (hub_uuid, Err(HealthcheckErr::DeserializError(e))),
                                 }
                             }
@@ -373,7 +713,7 @@ pub async fn hub_healthcheck_thread(state: Arc<HubRouterState>) {
                             hub_uuid,
                             Err(HealthcheckErr::Timeout(format!(
                                 "Healthcheck to {} timed out",
-                                _url
+                                request_url
                             ))),
                         ),
                     }
@@ -384,14 +724,19 @@ pub async fn hub_healthcheck_thread(state: Arc<HubRouterState>) {
 
         // For each response to /status (or timeout), inspect the request to determine if the hub is healthy
         // and if so, update its fullness metrics
+        let mut evicted = 0;
         while let Some(res) = request_futures.join_next().await {
             match res {
                 Ok((url, status_result)) => match status_result {
-                    Ok(parsed_status) => {
-                        let is_ready = parsed_status.value.nodes.len() > 0;
+                    Ok((parsed_status, requested_at)) => {
+                        let is_ready = parsed_status.value.nodes.iter().any(node_is_up);
+                        evicted += reconcile_sessions(url, &parsed_status, &state, &sessions);
                         match state.hubs.get_mut(&url) {
                             Some(mut hub) => {
-                                hub.state.fullness = compute_hub_fullness(&parsed_status);
+                                hub.state
+                                    .reconcile_fullness(compute_hub_fullness(&parsed_status), requested_at);
+                                hub.state.nodes =
+                                    parsed_status.value.nodes.iter().map(HubNode::from).collect();
                                 hub.state.readiness = if is_ready {
                                     hub.succeed_healthcheck()
                                 } else {
@@ -411,7 +756,7 @@ pub async fn hub_healthcheck_thread(state: Arc<HubRouterState>) {
                         }
                     }
                     Err(healthcheck_err) => {
-                        warn!("Got healthcheck err: {:#?}", healthcheck_err);
+                        warn!("Got healthcheck err: {}", healthcheck_err);
                         match state.hubs.get_mut(&url) {
                             Some(mut hub) => {
                                 hub.fail_healthcheck();
@@ -428,6 +773,270 @@ pub async fn hub_healthcheck_thread(state: Arc<HubRouterState>) {
             }
         }
 
+        // Hubs being drained may have been waiting on the sessions just evicted
+        if evicted > 0 {
+            deregister_drained_hubs(&state, &sessions);
+        }
+
+        // Fullness has been refreshed, so queued new session requests may now fit somewhere
+        state.new_session_queue.notify_slot_freed();
+
         healthcheck_interval.tick().await;
     }
 }
+
+/// Bring the routing map in line with the sessions a hub reported running. Sessions
+/// routed to the hub which it no longer runs are evicted, and sessions it runs which
+/// we know nothing about are adopted with the hub as their owner. Either only happens
+/// once two healthchecks in a row agree, so that a session being created or deleted
+/// while a `/status` is in flight is left alone. Returns how many sessions were evicted.
+fn reconcile_sessions(
+    hub_uuid: Uuid,
+    status: &HubStatusJSONSchema,
+    state: &HubRouterState,
+    sessions: &RoutingPrecedentMap,
+) -> usize {
+    // A hub without any slots can't be running sessions, but nor can it be trusted that they're gone
+    let slots = || status.value.nodes.iter().flat_map(|node| &node.slots);
+    if slots().next().is_none() {
+        return 0;
+    }
+    let reported: HashMap<&str, &HubStatusNodeSlotJSONSchema> = slots()
+        .filter_map(|slot| slot.session.as_ref().map(|s| (s.sessionId.as_str(), slot)))
+        .collect();
+
+    let missing: HashSet<String> = sessions
+        .sessions_for_hub(&hub_uuid)
+        .into_iter()
+        .filter(|id| !reported.contains_key(id.as_str()))
+        .collect();
+    let unknown: HashSet<String> = reported
+        .keys()
+        .filter(|id| sessions.get(id).is_none())
+        .map(|id| id.to_string())
+        .collect();
+
+    // Only act on disagreements which the previous healthcheck saw too
+    let confirmed: HashSet<String> = match state.hubs.get_mut(&hub_uuid) {
+        Some(mut hub) => {
+            let previous = std::mem::take(&mut hub.state.unreconciled_sessions);
+            let (confirmed, pending) = missing
+                .iter()
+                .chain(&unknown)
+                .cloned()
+                .partition(|id| previous.contains(id));
+            hub.state.unreconciled_sessions = pending;
+            confirmed
+        }
+        None => return 0,
+    };
+
+    let mut evicted = 0;
+    for session_id in confirmed.iter().filter(|id| missing.contains(*id)) {
+        if let Some(decision) = sessions.remove(session_id) {
+            info!(
+                "Evicting session {}, which hub {} no longer reports running",
+                session_id, hub_uuid
+            );
+            AUDIT_LOG.record(SessionAuditRecord {
+                message: Some(String::from("the hub no longer reports running the session")),
+                ..SessionAuditRecord::for_session(SessionEvent::Evicted, session_id, &decision, state)
+            });
+            METRICS.record_reconciled_session(hub_uuid, "evicted");
+            evicted += 1;
+        }
+    }
+
+    let hub_endpoint = match state.hubs.get(&hub_uuid) {
+        Some(hub) => hub.meta.url.clone(),
+        None => return evicted,
+    };
+    for session_id in confirmed.iter().filter(|id| unknown.contains(*id)) {
+        let (Some(slot), None) = (reported.get(session_id.as_str()), sessions.get(session_id)) else {
+            continue;
+        };
+        let capabilities = slot.session.as_ref().and_then(|s| s.capabilities.as_ref());
+        let mut decision = RoutingDecision::new(hub_uuid, hub_endpoint.clone(), SystemTime::now());
+        decision.browser_name = capabilities
+            .and_then(|c| c.browserName.clone())
+            .or(Some(slot.stereotype.browserName.clone()));
+        decision.browser_version = capabilities
+            .and_then(|c| c.browserVersion.clone())
+            .or(slot.stereotype.browserVersion.clone());
+        decision.platform_name = Some(slot.stereotype.platformName.clone());
+
+        info!(
+            "Adopting session {}, which hub {} reports running but wasn't routed here",
+            session_id, hub_uuid
+        );
+        AUDIT_LOG.record(SessionAuditRecord {
+            message: Some(String::from("the hub reports running the session")),
+            ..SessionAuditRecord::for_session(SessionEvent::Adopted, session_id, &decision, state)
+        });
+        sessions.insert(session_id.clone(), decision);
+        METRICS.record_reconciled_session(hub_uuid, "adopted");
+    }
+
+    evicted
+}
+
+#[test]
+fn test_slot_reservations() {
+    let chrome = HubStatusStereotypeJSONSchema {
+        browserName: String::from("chrome"),
+        platformName: String::from("linux"),
+        browserVersion: None,
+        additional: BTreeMap::new(),
+    };
+    let request = NewSessionRequestCapability {
+        browserName: Some(String::from("chrome")),
+        ..Default::default()
+    };
+    let state = Arc::new(HubRouterState::default());
+    let mut hub = Hub::new(Url::parse("http://localhost:4444/").unwrap());
+    let hub_uuid = hub.meta.uuid;
+    hub.state.fullness = HashMap::from([(chrome.clone(), (0, 2))]);
+    state.hubs.insert(hub_uuid, hub);
+    let fullness = || state.hubs.get(&hub_uuid).unwrap().state.get_stereotype_fullness(None);
+
+    // A burst of new sessions takes every free slot before the next healthcheck
+    let requests = vec![request.clone()];
+    let created = SlotReservation::take(state.clone(), hub_uuid, &requests);
+    let rejected = SlotReservation::take(state.clone(), hub_uuid, &requests);
+    assert_eq!(fullness(), (2, 2));
+    assert!(!state.hubs.get(&hub_uuid).unwrap().state.has_free_slot(&request));
+
+    // A slot is freed when its session isn't created, and kept when it is
+    drop(rejected);
+    assert_eq!(fullness(), (1, 2));
+    let requested_before = Instant::now();
+    created.created();
+    assert_eq!(fullness(), (1, 2));
+
+    // A /status requested before the session was created doesn't report it yet
+    state.hubs.get_mut(&hub_uuid).unwrap().state
+        .reconcile_fullness(HashMap::from([(chrome.clone(), (0, 2))]), requested_before);
+    assert_eq!(fullness(), (1, 2));
+    state.hubs.get_mut(&hub_uuid).unwrap().state
+        .reconcile_fullness(HashMap::from([(chrome.clone(), (1, 2))]), Instant::now());
+    assert_eq!(fullness(), (1, 2));
+
+    // Deleting the session frees its slot straight away
+    let mut decision = RoutingDecision::new(
+        hub_uuid,
+        Url::parse("http://localhost:4444/").unwrap(),
+        std::time::SystemTime::now(),
+    );
+    decision.browser_name = Some(String::from("chrome"));
+    decision.platform_name = Some(String::from("LINUX"));
+    state.hubs.get_mut(&hub_uuid).unwrap().state.free_slot(&decision);
+    assert_eq!(fullness(), (0, 2));
+    state.hubs.get_mut(&hub_uuid).unwrap().state
+        .reconcile_fullness(HashMap::from([(chrome, (0, 2))]), Instant::now());
+    assert_eq!(fullness(), (0, 2));
+}
+
+#[test]
+fn test_reconcile_sessions() {
+    let state = HubRouterState::default();
+    let sessions = RoutingPrecedentMap::default();
+    let hub = Hub::new(Url::parse("http://localhost:4444/").unwrap());
+    let hub_uuid = hub.meta.uuid;
+    state.hubs.insert(hub_uuid, hub);
+    let routed = || {
+        RoutingDecision::new(
+            hub_uuid,
+            Url::parse("http://localhost:4444/").unwrap(),
+            SystemTime::now(),
+        )
+    };
+    sessions.insert(String::from("live"), routed());
+    sessions.insert(String::from("dead"), routed());
+
+    let mut status = mock_status_schema(2, 1, 2);
+    let slots = &mut status.value.nodes[0].slots;
+    slots[0].session.as_mut().unwrap().sessionId = String::from("live");
+    slots[1].session.as_mut().unwrap().sessionId = String::from("stranger");
+    slots[1].session.as_mut().unwrap().capabilities =
+        Some(crate::schema::HubStatusNodeSlotSessionCapabilitiesJSONSchema {
+            acceptInsecureCerts: None,
+            browserName: Some(String::from("firefox")),
+            browserVersion: Some(String::from("118.0")),
+        });
+
+    // Nothing changes until a second healthcheck agrees with the first
+    assert_eq!(reconcile_sessions(hub_uuid, &status, &state, &sessions), 0);
+    assert!(sessions.get("dead").is_some());
+    assert!(sessions.get("stranger").is_none());
+
+    assert_eq!(reconcile_sessions(hub_uuid, &status, &state, &sessions), 1);
+    assert!(sessions.get("dead").is_none());
+    assert!(sessions.get("live").is_some());
+    let adopted = sessions.get("stranger").unwrap();
+    assert_eq!(adopted.hub_uuid, hub_uuid);
+    assert_eq!(adopted.browser_name.as_deref(), Some("firefox"));
+    assert_eq!(adopted.browser_version.as_deref(), Some("118.0"));
+    assert_eq!(adopted.platform_name.as_deref(), Some("nil"));
+
+    // A disagreement which resolves itself before it's confirmed is forgotten
+    sessions.insert(String::from("new"), routed());
+    assert_eq!(reconcile_sessions(hub_uuid, &status, &state, &sessions), 0);
+    status.value.nodes[0].slots[0].session.as_mut().unwrap().sessionId = String::from("new");
+    sessions.remove("live");
+    assert_eq!(reconcile_sessions(hub_uuid, &status, &state, &sessions), 0);
+    assert!(sessions.get("new").is_some());
+
+    // A hub reporting no slots at all isn't trusted to have no sessions
+    let empty = mock_status_schema(0, 0, 0);
+    assert_eq!(reconcile_sessions(hub_uuid, &empty, &state, &sessions), 0);
+    assert_eq!(reconcile_sessions(hub_uuid, &empty, &state, &sessions), 0);
+    assert!(sessions.get("new").is_some());
+}
+
+#[test]
+fn test_compute_hub_fullness() {
+    let mut status = mock_status_schema(4, 3, 1);
+    let free_slot = |slot: &HubStatusNodeSlotJSONSchema| HubStatusNodeSlotJSONSchema {
+        session: None,
+        ..slot.clone()
+    };
+    for node in status.value.nodes.iter_mut() {
+        let slot = free_slot(&node.slots[0]);
+        node.slots.extend(std::iter::repeat_n(slot, 5));
+    }
+    let nil = status.value.nodes[0].slots[0].stereotype.clone();
+
+    // Each node has 6 slots, but runs at most 4 sessions at once
+    assert_eq!(compute_hub_fullness(&status).get(&nil), Some(&(3, 12)));
+    let node = HubNode::from(&status.value.nodes[0]);
+    assert_eq!((node.slots, node.active_sessions, node.capacity), (6, 1, 4));
+
+    // Nodes which aren't up don't count, even for their running sessions
+    status.value.nodes[1].availability = String::from("DRAINING");
+    status.value.nodes[2].availability = String::from("DOWN");
+    assert_eq!(compute_hub_fullness(&status).get(&nil), Some(&(1, 4)));
+    assert_eq!(HubNode::from(&status.value.nodes[2]).capacity, 0);
+
+    status.value.nodes[0].availability = String::from("DOWN");
+    assert_eq!(compute_hub_fullness(&status).get(&nil), None);
+    assert!(!status.value.nodes.iter().any(node_is_up));
+
+    // A node running one session at a time offers it to each of its browsers, but only once in total
+    let mut status = mock_status_schema(1, 1, 1);
+    let running = status.value.nodes[0].slots[0].clone();
+    let slot = |browser: &str| {
+        let mut slot = free_slot(&running);
+        slot.stereotype.browserName = browser.into();
+        slot
+    };
+    status.value.nodes[0].slots = vec![slot("chrome"), slot("firefox"), slot("edge")];
+    let mut state = HubState::default();
+    state.reconcile_fullness(compute_hub_fullness(&status), Instant::now());
+    state.nodes = status.value.nodes.iter().map(HubNode::from).collect();
+    let firefox = NewSessionRequestCapability {
+        browserName: Some(String::from("firefox")),
+        ..Default::default()
+    };
+    assert_eq!(state.get_stereotype_fullness(Some(firefox)), (0, 1));
+    assert_eq!(state.get_stereotype_fullness(None), (0, 1));
+}
@@ -0,0 +1,297 @@
+//! Listeners for the proxy and API servers, which follow the configuration.
+//! When a listener's address or TLS settings change, a new listener is bound
+//! and the old one stops accepting connections, while the connections it
+//! already accepted are allowed to finish.
+
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_stream::{wrappers::TcpListenerStream, Stream, StreamExt};
+
+use crate::{
+    state::{HubRouterPrimitiveConfigs, HubRouterState},
+    tls::{tls_incoming, ReloadingTlsConfig, TlsConfig},
+};
+
+/// How long to keep trying to bind an address which the previous listener is still letting go of.
+const REBIND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where a listener binds, and whether it terminates TLS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerConfig {
+    pub addr: SocketAddr,
+    pub tls: Option<TlsConfig>,
+}
+
+/// An accepted connection, either in cleartext or over TLS.
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection {
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Connection::Plain(stream) => stream.peer_addr().ok(),
+            Connection::Tls(stream) => stream.get_ref().0.peer_addr().ok(),
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The connections accepted by a listener. The listener is closed when this is dropped.
+pub type Incoming = Pin<Box<dyn Stream<Item = io::Result<Connection>> + Send>>;
+
+/// Resolves when a server should stop accepting connections.
+pub type Shutdown = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Bind a listener, loading its TLS certificates if it has any.
+pub async fn listen(config: &ListenerConfig) -> Result<Incoming, String> {
+    let tls = match &config.tls {
+        Some(tls_config) => Some(
+            ReloadingTlsConfig::load(tls_config.clone())
+                .map_err(|e| format!("Unable to load TLS configuration: {}", e))?,
+        ),
+        None => None,
+    };
+    let listener = TcpListener::bind(config.addr)
+        .await
+        .map_err(|e| format!("Unable to bind {}: {}", config.addr, e))?;
+
+    Ok(match tls {
+        Some(tls) => Box::pin(
+            tls_incoming(listener, tls)
+                .map(|accepted| accepted.map(|stream| Connection::Tls(Box::new(stream)))),
+        ),
+        None => Box::pin(TcpListenerStream::new(listener).map(|accepted| accepted.map(Connection::Plain))),
+    })
+}
+
+/// Serve connections with `serve`, on a listener built from the settings which
+/// `settings` picks out of the configuration. Whenever the configuration changes
+/// and those settings are different, the server is rebuilt on a new listener.
+/// Besides the listener's own, the settings may include anything else the server
+/// is built from.
+///
+/// The process exits if the first listener can't be bound, rather than running
+/// without it. Later, a listener which can't be bound is logged, and the
+/// previous one stays in use if it can.
+pub async fn serve_following_config<T, S, F, Fut>(
+    name: &'static str,
+    state: Arc<HubRouterState>,
+    settings: S,
+    serve: F,
+) where
+    T: PartialEq,
+    S: Fn(&HubRouterPrimitiveConfigs) -> (ListenerConfig, T),
+    F: Fn(&T, Incoming, Shutdown) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut changes = state.watch_configs();
+    let mut current: Option<((ListenerConfig, T), oneshot::Sender<()>)> = None;
+
+    loop {
+        let wanted = state.read_configs(&settings);
+        let unchanged = current
+            .as_ref()
+            .is_some_and(|(running, _)| *running == wanted);
+
+        if !unchanged {
+            // The old listener has to let go of the address before it can be bound again
+            let same_addr = current
+                .as_ref()
+                .is_some_and(|((running, _), _)| running.addr == wanted.0.addr);
+            if same_addr {
+                if let Some((_, stop)) = current.take() {
+                    let _ = stop.send(());
+                }
+            }
+
+            match bind(&wanted.0, same_addr).await {
+                Ok(incoming) => {
+                    let (stop, stopped) = oneshot::channel();
+                    let shutdown: Shutdown = Box::pin(async {
+                        let _ = stopped.await;
+                    });
+                    tokio::task::spawn(serve(&wanted.1, incoming, shutdown));
+                    info!("{} listening on {}", name, wanted.0.addr);
+
+                    if let Some((_, stop)) = current.replace((wanted, stop)) {
+                        let _ = stop.send(());
+                    }
+                }
+                Err(e) if current.is_none() && !same_addr => {
+                    warn!("Unable to start {} listener: {}", name, e);
+                    std::process::exit(1);
+                }
+                Err(e) => warn!(
+                    "Unable to rebind {} listener, {}: {}",
+                    name,
+                    if current.is_some() {
+                        "continuing with the previous one"
+                    } else {
+                        "it will be down until its configuration is fixed"
+                    },
+                    e
+                ),
+            }
+        }
+
+        if changes.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Bind a listener, retrying for a while if its address may still be held by the previous one.
+async fn bind(config: &ListenerConfig, retry: bool) -> Result<Incoming, String> {
+    let deadline = tokio::time::Instant::now() + REBIND_TIMEOUT;
+    loop {
+        match listen(config).await {
+            Ok(incoming) => return Ok(incoming),
+            Err(e) if !retry || tokio::time::Instant::now() >= deadline => return Err(e),
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_listener_follows_config() {
+    use hyper::{
+        server::accept,
+        service::{make_service_fn, service_fn},
+        Body, Response, Server,
+    };
+    use std::convert::Infallible;
+
+    let state = Arc::new(HubRouterState::default());
+    let port = |addr: SocketAddr| addr.port();
+    let free_port = || {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    };
+    let first = free_port();
+    let second = free_port();
+    if let Ok(mut conf) = state.configs.write() {
+        conf.api_bind_port = port(first);
+    }
+
+    tokio::task::spawn(serve_following_config(
+        "test",
+        state.clone(),
+        |conf: &HubRouterPrimitiveConfigs| {
+            (
+                ListenerConfig {
+                    addr: SocketAddr::from(([127, 0, 0, 1], conf.api_bind_port)),
+                    tls: None,
+                },
+                conf.api_cors_origins.clone(),
+            )
+        },
+        |origins: &Vec<String>, incoming: Incoming, shutdown: Shutdown| {
+            let body = origins.join(",");
+            let server = Server::builder(accept::from_stream(incoming))
+                .serve(make_service_fn(move |_: &Connection| {
+                    let body = body.clone();
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |_| {
+                            let body = body.clone();
+                            async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+                        }))
+                    }
+                }))
+                .with_graceful_shutdown(shutdown);
+            async move {
+                let _ = server.await;
+            }
+        },
+    ));
+
+    let get = |addr: SocketAddr| async move {
+        let response = hyper::Client::new()
+            .get(format!("http://{}/", addr).parse().unwrap())
+            .await?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok::<_, hyper::Error>(String::from_utf8_lossy(&body).to_string())
+    };
+    let eventually = |addr: SocketAddr, expected: &'static str| async move {
+        for _ in 0..50 {
+            if matches!(get(addr).await, Ok(body) if body == expected) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    };
+    assert!(eventually(first, "*").await);
+
+    // Settings besides the address rebuild the server on the same address
+    if let Ok(mut conf) = state.configs.write() {
+        conf.api_cors_origins = vec!["https://a.example.com".into()];
+    }
+    state.notify_config_changed();
+    assert!(eventually(first, "https://a.example.com").await);
+
+    // A new address is bound, and the old one let go of
+    if let Ok(mut conf) = state.configs.write() {
+        conf.api_bind_port = port(second);
+    }
+    state.notify_config_changed();
+    assert!(eventually(second, "https://a.example.com").await);
+    assert!(get(first).await.is_err());
+}
@@ -1,34 +1,56 @@
-///! The Hub Router is a WebDriver spec-compliant intermediate node to route
-///! Selenium tests between multiple grids.
+//! The Hub Router is a WebDriver spec-compliant intermediate node to route
+//! Selenium tests between multiple grids.
+
+// The existing tests compare booleans with `assert_eq!`
+#![cfg_attr(test, allow(clippy::bool_assert_comparison))]
 
 use crate::api::hub_api_thread;
-use crate::hub::{hub_healthcheck_thread, Hub};
+use crate::audit::{SessionEvent, AUDIT_LOG};
+use crate::hub::{deregister_drained_hubs, hub_healthcheck_thread, Hub};
 use crate::logger::HubRouterLogger;
-use crate::state::{HubRouterPrimitiveConfigs, HubRouterState};
+use crate::metrics::METRICS;
+use crate::peers::{parse_peers, sync_from_peers};
+use crate::session_store::session_store_from_config;
+use crate::listener::{serve_following_config, Connection, ListenerConfig};
+use crate::state::{ConfigInterval, HubRouterPrimitiveConfigs, HubRouterState};
 use clap::Parser;
 use dashmap::DashMap;
-use handler::handle;
+use handler::{close_session, handle};
 use hyper::service::{make_service_fn, service_fn};
+use hyper::server::accept;
 use hyper::Server;
-use log::warn;
+use log::{info, warn};
 use routing::RoutingPrecedentMap;
 use std::convert::Infallible;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::time::{self, Instant};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinSet;
 use uuid::Uuid;
 
 mod api;
+mod api_auth;
+mod audit;
+mod client;
+mod credentials;
 mod error;
 mod handler;
 mod hub;
+mod listener;
 mod routing;
 mod schema;
+mod session_store;
 mod state;
+mod strategy;
+mod timeouts;
+mod tls;
 mod ui;
 mod utils;
 mod logger;
+mod metrics;
+mod peers;
+mod proxy_clients;
+mod queue;
 
 #[derive(clap::Parser, Debug)]
 
@@ -56,17 +78,104 @@ async fn main() {
     let args = Args::parse();
     let state: Arc<HubRouterState> = Arc::new(HubRouterState::new_from_disk(&args.config_location));
 
+    // Build the client used to reach hubs and peers, trusting any extra CAs from config.
+    // A broken CA bundle or client certificate would fail every request to an
+    // `https://` hub, so refuse to start rather than run degraded.
+    let outbound_config = |conf: &HubRouterPrimitiveConfigs| {
+        (conf.outbound_tls.clone(), conf.outbound_pool.clone())
+    };
+    let mut outbound = state.read_configs(outbound_config);
+    if let Err(e) = state.clients.configure(&outbound.0, &outbound.1) {
+        warn!("Unable to configure outbound TLS: {}", e);
+        std::process::exit(1);
+    }
+
+    // Rebuild the clients when their configuration changes. Once running, a broken
+    // configuration is only logged, and the previous clients stay in use.
+    tokio::task::spawn({
+        let state_clone = state.clone();
+        let mut changes = state.watch_configs();
+        async move {
+            while changes.changed().await.is_ok() {
+                let wanted = state_clone.read_configs(outbound_config);
+                if wanted == outbound {
+                    continue;
+                }
+                match state_clone.clients.configure(&wanted.0, &wanted.1) {
+                    Ok(()) => outbound = wanted,
+                    Err(e) => warn!("Unable to reconfigure outbound clients: {}", e),
+                }
+            }
+        }
+    });
+
+    // Start recording session lifecycles to the audit log
+    match state.configs.read() {
+        Ok(conf) => AUDIT_LOG.configure(&conf.audit_log),
+        Err(e) => {
+            warn!("RWLock poisoned reading audit log config: {}", e);
+            AUDIT_LOG.configure(&HubRouterPrimitiveConfigs::default().audit_log)
+        }
+    }
+
+    // Move the audit log when its configuration changes
+    tokio::task::spawn({
+        let state_clone = state.clone();
+        let mut changes = state.watch_configs();
+        async move {
+            while changes.changed().await.is_ok() {
+                AUDIT_LOG.configure(&state_clone.read_configs(|conf| conf.audit_log.clone()));
+            }
+        }
+    });
+
     // We store routing decisions in this globally shared hashmap
-    // from Selenium session IDs to URLs.
-    let sessions: Arc<RoutingPrecedentMap> = Arc::new(DashMap::new());
+    // from Selenium session IDs to URLs, reloading any decisions which
+    // were persisted before the last restart.
+    let (session_store, peers, peer_token, peer_timeout) = match state.configs.read() {
+        Ok(conf) => (
+            session_store_from_config(
+                &conf.session_store,
+                &conf.peers,
+                conf.api_auth.peer_token.clone(),
+                state.clients.clone(),
+            ),
+            parse_peers(&conf.peers),
+            conf.api_auth.peer_token.clone(),
+            Duration::from_secs(conf.healthcheck_timeout),
+        ),
+        Err(e) => {
+            warn!("RWLock poisoned reading session store config: {}", e);
+            let conf = HubRouterPrimitiveConfigs::default();
+            (
+                session_store_from_config(&conf.session_store, &conf.peers, None, state.clients.clone()),
+                parse_peers(&conf.peers),
+                None,
+                Duration::from_secs(conf.healthcheck_timeout),
+            )
+        }
+    };
+    let sessions: Arc<RoutingPrecedentMap> = Arc::new(RoutingPrecedentMap::new(session_store));
+
+    // When running as one of several replicas, catch up on the sessions
+    // the other replicas already know about.
+    if !peers.is_empty() {
+        tokio::task::spawn({
+            let sessions_clone = sessions.clone();
+            let client = state.clients.outbound();
+            async move { sync_from_peers(sessions_clone, client, peers, peer_token, peer_timeout).await }
+        });
+    }
 
     // Spawn the healthcheck thread, which polls each registered Selenium hub
     // for its fullness for each browser and operating system,
     // so that we can calculate routing weights, and ensure that we only
-    // route tests to healthy hubs.
+    // route tests to healthy hubs. The sessions each hub reports running
+    // are reconciled with the routing map as well.
     tokio::task::spawn({
         let state_clone = state.clone();
-        async move { hub_healthcheck_thread(state_clone).await }
+        let sessions_clone = sessions.clone();
+        async move { hub_healthcheck_thread(state_clone, sessions_clone).await }
     });
 
     // Spawn the API thread, which serves configuration endpoints and the UI 
@@ -80,76 +189,99 @@ async fn main() {
     // any threads which error during execution and don't perform cleanup
     // steps will be left in our RoutingPrecedentMap until the reaper
     // thread cleans them up.
-    // The reaper will clean up any tests which have been in the map
-    // for longer than the configurable timeout (30 minutes by default)
+    // The reaper will clean up any tests which have been in the map for longer
+    // than the configurable maximum (30 minutes by default), or which haven't
+    // sent a command for the configurable idle time, if one is set.
+    // Reaped sessions are closed on their hubs, so that their slots are freed.
     tokio::task::spawn({
         // Clone the Arcs so that the thread has its own local copy
         let map_clone = sessions.clone();
         let state_clone = state.clone();
 
-        // Pull the reap interval and maximum session length from configuration,
-        // and turn the integer seconds into a tokio interval and a duration 
-        let (mut reap_interval, max_session_lifetime) = match state_clone.configs.read() {
-            Ok(conf) => (
-                time::interval(Duration::from_secs(conf.reaper_thread_interval.into())),
-                Duration::from_secs(60 * conf.reaper_thread_duration_max),
-            ),
-            Err(e) => {
-                warn!("Config Rwlock was poisoned during reaper spawn: {}", e);
-                let conf = HubRouterPrimitiveConfigs::default();
-                (
-                    time::interval(Duration::from_secs(conf.reaper_thread_interval.into())),
-                    Duration::from_secs(60 * conf.reaper_thread_duration_max),
-                )
-            }
-        };
+        // Follow the reap interval in configuration, so that changes to it apply straight away
+        let mut reap_interval =
+            ConfigInterval::new(state_clone.clone(), |conf| conf.reaper_thread_interval);
 
-
-        // Core reaper loop - wait for the interval, then remove all sessions
-        // whose age is greater than the max session lifetime
+        // Core reaper loop - wait for the interval, then close all sessions
+        // which have been idle for too long, or whose age is greater than the max session lifetime
         async move {
             loop {
                 reap_interval.tick().await;
 
-                let dead_session_ids: Vec<String> = map_clone
-                    .iter()
-                    .filter(|entry| {
-                        Instant::now()
-                            .duration_since(entry.value().decision_time)
-                            .ge(&max_session_lifetime)
-                    })
-                    .map(|e| e.key().clone())
-                    .collect();
-                dead_session_ids.iter().for_each(|key| {
-                    map_clone.remove(key);
-                });
-            }
-        }
-    });
+                let (max_session_lifetime, max_session_idle) = match state_clone.configs.read() {
+                    Ok(conf) => (conf.reaper_thread_duration_max, conf.reaper_thread_idle_max),
+                    Err(e) => {
+                        warn!("Config Rwlock was poisoned reading reaper limits: {}", e);
+                        let conf = HubRouterPrimitiveConfigs::default();
+                        (conf.reaper_thread_duration_max, conf.reaper_thread_idle_max)
+                    }
+                };
 
-    // Extract the IP and port specified in config, and turn them into a SocketAddr ready for binding
-    let bind_addr = match state.configs.read() {
-        Ok(conf) => SocketAddr::new(IpAddr::V4(conf.bind_ip), conf.bind_port),
-        Err(e) => {
-            warn!("RWLock poisoned generating proxy bind addr: {}", e);
-            let conf = HubRouterPrimitiveConfigs::default();
-            SocketAddr::new(IpAddr::V4(conf.bind_ip), conf.bind_port)
-        }
-    };
+                let dead_sessions =
+                    map_clone.dead_sessions(SystemTime::now(), max_session_lifetime, max_session_idle);
 
-    // Bind the request router on that SocketAddr
-    let server = Server::bind(&bind_addr).serve(make_service_fn(move |_con| {
-        let map = sessions.clone();
-        let state_clone = state.clone();
-        async {
-            Ok::<_, Infallible>(service_fn(move |_conn| {
-                handle(_conn, map.clone(), state_clone.clone())
-            }))
+                let mut closing = JoinSet::new();
+                for (session_id, reason) in dead_sessions {
+                    let map = map_clone.clone();
+                    let state = state_clone.clone();
+                    closing.spawn(async move {
+                        info!("Reaping session {}: {}", session_id, reason);
+                        if let Err(e) =
+                            close_session(&session_id, SessionEvent::Reaped, &reason, &map, &state)
+                                .await
+                        {
+                            warn!("Unable to close reaped session {} on its hub: {}", session_id, e);
+                        }
+                    });
+                }
+                let mut reaped = 0;
+                while closing.join_next().await.is_some() {
+                    reaped += 1;
+                }
+                METRICS.record_reaped_sessions(reaped);
+                map_clone.compact();
+                deregister_drained_hubs(&state_clone, &map_clone);
+            }
         }
-    }));
+    });
 
-    // And run forever...
-    if let Err(e) = server.await {
-        warn!("server error: {}", e);
-    }
+    // Bind the request router on the IP and port specified in config, with TLS if it's
+    // configured, and run forever... rebinding whenever those settings change.
+    serve_following_config(
+        "proxy",
+        state.clone(),
+        |conf| {
+            (
+                ListenerConfig {
+                    addr: SocketAddr::new(IpAddr::V4(conf.bind_ip), conf.bind_port),
+                    tls: conf.proxy_tls.clone(),
+                },
+                (),
+            )
+        },
+        |_, incoming, shutdown| {
+            let server = Server::builder(accept::from_stream(incoming))
+                .serve(make_service_fn({
+                    let sessions = sessions.clone();
+                    let state = state.clone();
+                    move |con: &Connection| {
+                        let map = sessions.clone();
+                        let state_clone = state.clone();
+                        let client_address = con.peer_addr();
+                        async move {
+                            Ok::<_, Infallible>(service_fn(move |_conn| {
+                                handle(_conn, client_address, map.clone(), state_clone.clone())
+                            }))
+                        }
+                    }
+                }))
+                .with_graceful_shutdown(shutdown);
+            async move {
+                if let Err(e) = server.await {
+                    warn!("server error: {}", e);
+                }
+            }
+        },
+    )
+    .await
 }
@@ -0,0 +1,417 @@
+//! Prometheus metrics for the Hub Router. Counters and histograms are
+//! recorded as requests flow through the router, while gauges describing
+//! hub fullness and readiness are computed from the live state at scrape time.
+//! Everything is rendered in the Prometheus text exposition format.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use uuid::Uuid;
+
+use crate::{
+    error::RoutingError, hub::HubReadiness, routing::RoutingPrecedentMap,
+    schema::HubStatusStereotypeJSONSchema, state::HubRouterState,
+};
+
+/// Upper bounds (in seconds) of the healthcheck latency histogram buckets.
+const HEALTHCHECK_LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+lazy_static! {
+    pub static ref METRICS: HubRouterMetrics = HubRouterMetrics::default();
+}
+
+/// A cumulative histogram with fixed buckets, safe to update from many tasks at once.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Vec<(f64, AtomicU64)>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Self {
+            buckets: bounds.iter().map(|b| (*b, AtomicU64::new(0))).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, count) in &self.buckets {
+            if secs <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bound, count) in &self.buckets {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name,
+                labels,
+                bound,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(
+            out,
+            "{}_sum{{{}}} {}",
+            name,
+            labels,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// All metrics which are recorded as events happen, rather than computed at scrape time.
+#[derive(Debug, Default)]
+pub struct HubRouterMetrics {
+    routing_decisions: DashMap<Uuid, AtomicU64>,
+    routing_errors: DashMap<&'static str, AtomicU64>,
+    healthcheck_latency: DashMap<Uuid, Histogram>,
+    hub_timeouts: DashMap<(Uuid, &'static str), AtomicU64>,
+    reconciled_sessions: DashMap<(Uuid, &'static str), AtomicU64>,
+    reaped_sessions: AtomicU64,
+}
+
+impl HubRouterMetrics {
+    /// Record that a new routing decision was made to send a session to the given hub.
+    pub fn record_routing_decision(&self, hub_uuid: Uuid) {
+        self.routing_decisions
+            .entry(hub_uuid)
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that a request could not be routed.
+    pub fn record_routing_error(&self, error: &RoutingError) {
+        self.routing_errors
+            .entry(error.variant_name())
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record how long a /status request to a hub took, whether or not it succeeded.
+    pub fn observe_healthcheck_latency(&self, hub_uuid: Uuid, duration: Duration) {
+        self.healthcheck_latency
+            .entry(hub_uuid)
+            .or_insert_with(|| Histogram::new(&HEALTHCHECK_LATENCY_BUCKETS))
+            .observe(duration);
+    }
+
+    /// Record that a hub didn't respond in time while connecting, creating a session, or running a command.
+    pub fn record_hub_timeout(&self, hub_uuid: Uuid, stage: &'static str) {
+        self.hub_timeouts
+            .entry((hub_uuid, stage))
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that a healthcheck evicted or adopted a session, so that the routing map matches its hub.
+    pub fn record_reconciled_session(&self, hub_uuid: Uuid, action: &'static str) {
+        self.reconciled_sessions
+            .entry((hub_uuid, action))
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that the reaper evicted a number of sessions from the routing map.
+    pub fn record_reaped_sessions(&self, count: usize) {
+        self.reaped_sessions
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Render every metric in the Prometheus text exposition format.
+    pub fn render(&self, state: &HubRouterState, sessions: &RoutingPrecedentMap) -> String {
+        let mut out = String::new();
+
+        // Hubs which have been de-registered are still reported under their UUID,
+        // so that counters never go backwards.
+        let hub_labels = |uuid: &Uuid| -> String {
+            let name = state
+                .hubs
+                .get(uuid)
+                .map(|h| h.meta.name.clone())
+                .unwrap_or_default();
+            format!("hub=\"{}\",hub_uuid=\"{}\"", escape_label(&name), uuid)
+        };
+
+        write_header(
+            &mut out,
+            "hub_router_hub_ready",
+            "gauge",
+            "Whether a registered hub is currently considered ready (1) or unhealthy (0).",
+        );
+        for hub in state.hubs.iter() {
+            let ready = u8::from(hub.state.get_readiness() == HubReadiness::Ready);
+            let _ = writeln!(
+                out,
+                "hub_router_hub_ready{{{}}} {}",
+                hub_labels(hub.key()),
+                ready
+            );
+        }
+
+        write_header(
+            &mut out,
+            "hub_router_hub_consecutive_healthcheck_failures",
+            "gauge",
+            "Number of consecutive healthchecks a hub has failed.",
+        );
+        for hub in state.hubs.iter() {
+            let _ = writeln!(
+                out,
+                "hub_router_hub_consecutive_healthcheck_failures{{{}}} {}",
+                hub_labels(hub.key()),
+                hub.state.consecutive_healthcheck_failures
+            );
+        }
+
+        write_header(
+            &mut out,
+            "hub_router_hub_slots_active",
+            "gauge",
+            "Number of slots running a session on a hub, per browser, version and platform.",
+        );
+        for hub in state.hubs.iter() {
+            for ((browser, version, platform), (active, _)) in slots_by_labels(&hub.state.fullness) {
+                let _ = writeln!(
+                    out,
+                    "hub_router_hub_slots_active{{{},browser=\"{}\",browser_version=\"{}\",platform=\"{}\"}} {}",
+                    hub_labels(hub.key()),
+                    escape_label(&browser),
+                    escape_label(&version),
+                    escape_label(&platform),
+                    active
+                );
+            }
+        }
+
+        write_header(
+            &mut out,
+            "hub_router_hub_slots_max",
+            "gauge",
+            "Sessions a hub could run of each browser, version and platform. Nodes share their maxSessions between browsers, so these overlap.",
+        );
+        for hub in state.hubs.iter() {
+            for ((browser, version, platform), (_, max)) in slots_by_labels(&hub.state.fullness) {
+                let _ = writeln!(
+                    out,
+                    "hub_router_hub_slots_max{{{},browser=\"{}\",browser_version=\"{}\",platform=\"{}\"}} {}",
+                    hub_labels(hub.key()),
+                    escape_label(&browser),
+                    escape_label(&version),
+                    escape_label(&platform),
+                    max
+                );
+            }
+        }
+
+        write_header(
+            &mut out,
+            "hub_router_sessions",
+            "gauge",
+            "Number of sessions currently held in the routing precedent map.",
+        );
+        let _ = writeln!(out, "hub_router_sessions {}", sessions.len());
+
+        write_header(
+            &mut out,
+            "hub_router_new_session_queue_depth",
+            "gauge",
+            "Number of new session requests waiting for a hub to have a free slot.",
+        );
+        let _ = writeln!(
+            out,
+            "hub_router_new_session_queue_depth {}",
+            state.new_session_queue.total_depth()
+        );
+
+        write_header(
+            &mut out,
+            "hub_router_routing_decisions_total",
+            "counter",
+            "Number of routing decisions which selected a hub.",
+        );
+        for entry in self.routing_decisions.iter() {
+            let _ = writeln!(
+                out,
+                "hub_router_routing_decisions_total{{{}}} {}",
+                hub_labels(entry.key()),
+                entry.value().load(Ordering::Relaxed)
+            );
+        }
+
+        write_header(
+            &mut out,
+            "hub_router_routing_errors_total",
+            "counter",
+            "Number of requests which could not be routed, by error.",
+        );
+        for entry in self.routing_errors.iter() {
+            let _ = writeln!(
+                out,
+                "hub_router_routing_errors_total{{error=\"{}\"}} {}",
+                entry.key(),
+                entry.value().load(Ordering::Relaxed)
+            );
+        }
+
+        write_header(
+            &mut out,
+            "hub_router_healthcheck_duration_seconds",
+            "histogram",
+            "Latency of /status requests made to each hub.",
+        );
+        for entry in self.healthcheck_latency.iter() {
+            entry.value().render(
+                &mut out,
+                "hub_router_healthcheck_duration_seconds",
+                &hub_labels(entry.key()),
+            );
+        }
+
+        write_header(
+            &mut out,
+            "hub_router_hub_timeouts_total",
+            "counter",
+            "Number of requests proxied to a hub which timed out, by stage (connect, new_session or command).",
+        );
+        for entry in self.hub_timeouts.iter() {
+            let (uuid, stage) = entry.key();
+            let _ = writeln!(
+                out,
+                "hub_router_hub_timeouts_total{{{},stage=\"{}\"}} {}",
+                hub_labels(uuid),
+                stage,
+                entry.value().load(Ordering::Relaxed)
+            );
+        }
+
+        write_header(
+            &mut out,
+            "hub_router_reconciled_sessions_total",
+            "counter",
+            "Number of sessions evicted from or adopted into the routing precedent map because of what their hub reported, by action.",
+        );
+        for entry in self.reconciled_sessions.iter() {
+            let (uuid, action) = entry.key();
+            let _ = writeln!(
+                out,
+                "hub_router_reconciled_sessions_total{{{},action=\"{}\"}} {}",
+                hub_labels(uuid),
+                action,
+                entry.value().load(Ordering::Relaxed)
+            );
+        }
+
+        write_header(
+            &mut out,
+            "hub_router_reaped_sessions_total",
+            "counter",
+            "Number of sessions evicted from the routing precedent map by the reaper.",
+        );
+        let _ = writeln!(
+            out,
+            "hub_router_reaped_sessions_total {}",
+            self.reaped_sessions.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Sum the slots of stereotypes which differ only in their extra capabilities,
+/// since the slot gauges are labelled by browser, version and platform alone.
+fn slots_by_labels(
+    fullness: &HashMap<HubStatusStereotypeJSONSchema, (u8, u8)>,
+) -> BTreeMap<(String, String, String), (u32, u32)> {
+    let mut slots = BTreeMap::new();
+    for (stereotype, (active, max)) in fullness {
+        let key = (
+            stereotype.browserName.clone(),
+            stereotype.browserVersion.clone().unwrap_or_default(),
+            stereotype.platformName.clone(),
+        );
+        let entry: &mut (u32, u32) = slots.entry(key).or_default();
+        entry.0 += u32::from(*active);
+        entry.1 += u32::from(*max);
+    }
+    slots
+}
+
+/// Escape a label value as required by the exposition format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[test]
+fn test_histogram_render() {
+    let histogram = Histogram::new(&[0.1, 1.0]);
+    histogram.observe(Duration::from_millis(50));
+    histogram.observe(Duration::from_millis(500));
+    histogram.observe(Duration::from_secs(3));
+
+    let mut out = String::new();
+    histogram.render(&mut out, "latency", "hub=\"a\"");
+    assert_eq!(
+        out,
+        "latency_bucket{hub=\"a\",le=\"0.1\"} 1\n\
+         latency_bucket{hub=\"a\",le=\"1\"} 2\n\
+         latency_bucket{hub=\"a\",le=\"+Inf\"} 3\n\
+         latency_sum{hub=\"a\"} 3.55\n\
+         latency_count{hub=\"a\"} 3\n"
+    );
+}
+
+#[test]
+fn test_escape_label() {
+    assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+}
+
+#[test]
+fn test_slots_by_labels() {
+    let stereotype = |container: &str| HubStatusStereotypeJSONSchema {
+        browserName: "chrome".to_string(),
+        platformName: "linux".to_string(),
+        browserVersion: Some("120".to_string()),
+        additional: BTreeMap::from([(
+            "se:containerName".to_string(),
+            serde_json::Value::String(container.to_string()),
+        )]),
+    };
+    let fullness = HashMap::from([(stereotype("a"), (1, 2)), (stereotype("b"), (0, 3))]);
+
+    let slots = slots_by_labels(&fullness);
+    assert_eq!(slots.len(), 1);
+    assert_eq!(
+        slots.get(&("chrome".to_string(), "120".to_string(), "linux".to_string())),
+        Some(&(1, 5))
+    );
+}
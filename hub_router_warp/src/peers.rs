@@ -0,0 +1,506 @@
+//! Sharing the session table between multiple Hub Router replicas.
+//! Each replica is configured with the API address of its peers. Routing
+//! decisions are pushed to every peer as they are made and forgotten, and a
+//! replica which receives a command for a session it has never heard of
+//! asks its peers, and then every hub's `/se/grid/session/{id}` endpoint,
+//! which replica (or hub) owns the session before giving up on it.
+
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    header::{HeaderValue, AUTHORIZATION},
+    Body, HeaderMap, Method, Request, StatusCode, Uri,
+};
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{task::JoinSet, time::timeout};
+use url::Url;
+
+use crate::{
+    client::{HttpClient, SharedClients},
+    credentials::{apply_hub_auth, without_credentials, Secret},
+    routing::{RoutingDecision, RoutingPrecedentMap},
+    session_store::SessionStore,
+    state::{HubRouterPrimitiveConfigs, HubRouterState},
+};
+
+/// How long a session which nobody owns is remembered, so that clients retrying commands
+/// for a dead session don't have every peer and hub asked about it each time.
+const UNRESOLVED_SESSION_TTL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref UNRESOLVED_SESSIONS: DashMap<String, Instant> = DashMap::new();
+}
+
+/// A routing decision as exchanged between replicas.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerSession {
+    pub session_id: String,
+    pub decision: RoutingDecision,
+}
+
+/// A session store which persists to another store, and pushes every change
+/// to the other Hub Router replicas so that they can route the session too.
+#[derive(Debug)]
+pub struct PeerSessionStore {
+    inner: Box<dyn SessionStore>,
+    peers: Vec<Url>,
+    token: Option<Secret>,
+    clients: Arc<SharedClients>,
+}
+
+impl PeerSessionStore {
+    pub fn new(
+        inner: Box<dyn SessionStore>,
+        peers: Vec<Url>,
+        token: Option<Secret>,
+        clients: Arc<SharedClients>,
+    ) -> Self {
+        Self {
+            inner,
+            peers,
+            token,
+            clients,
+        }
+    }
+
+    /// Send a change to every peer in the background. Peers which are down
+    /// will catch up by asking for the session when they are sent a command for it.
+    fn broadcast(&self, method: Method, session_id: &str, body: Option<String>) {
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+
+        let headers = peer_headers(self.token.as_ref());
+        for peer in &self.peers {
+            let uri = match peer_session_uri(peer, Some(session_id)) {
+                Some(uri) => uri,
+                None => continue,
+            };
+            let request = Request::builder()
+                .method(method.clone())
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .body(body.clone().map(Body::from).unwrap_or_else(Body::empty))
+                .map(|mut request| {
+                    request.headers_mut().extend(headers.clone());
+                    request
+                });
+
+            let peer = peer.clone();
+            let client = self.clients.outbound();
+            handle.spawn(async move {
+                let request = match request {
+                    Ok(request) => request,
+                    Err(e) => {
+                        warn!("Unable to build replication request for {}: {}", peer, e);
+                        return;
+                    }
+                };
+                match client.request(request).await {
+                    Ok(response) if response.status().is_success() => {}
+                    Ok(response) => warn!(
+                        "Peer {} rejected session replication with status {}",
+                        peer,
+                        response.status()
+                    ),
+                    Err(e) => warn!("Unable to replicate session to peer {}: {}", peer, e),
+                }
+            });
+        }
+    }
+}
+
+impl SessionStore for PeerSessionStore {
+    fn load(&self) -> Result<Vec<(String, RoutingDecision)>, String> {
+        self.inner.load()
+    }
+
+    fn record(&self, session_id: &str, decision: &RoutingDecision) -> Result<(), String> {
+        let result = self.inner.record(session_id, decision);
+        match serde_json::to_string(decision) {
+            Ok(body) => self.broadcast(Method::PUT, session_id, Some(body)),
+            Err(e) => warn!("Unable to serialize session {} for peers: {}", session_id, e),
+        }
+        result
+    }
+
+    fn forget(&self, session_id: &str) -> Result<(), String> {
+        let result = self.inner.forget(session_id);
+        self.broadcast(Method::DELETE, session_id, None);
+        result
+    }
+
+    fn record_replica(&self, session_id: &str, decision: &RoutingDecision) -> Result<(), String> {
+        self.inner.record(session_id, decision)
+    }
+
+    fn forget_replica(&self, session_id: &str) -> Result<(), String> {
+        self.inner.forget(session_id)
+    }
+
+    fn compact(&self, live: &[(String, RoutingDecision)]) -> Result<(), String> {
+        self.inner.compact(live)
+    }
+}
+
+/// Parse the configured peer addresses, skipping any which aren't valid URLs.
+pub fn parse_peers(peers: &[String]) -> Vec<Url> {
+    peers
+        .iter()
+        .filter_map(|peer| match Url::parse(peer) {
+            Ok(url) => Some(url),
+            Err(e) => {
+                warn!("Ignoring invalid peer address {}: {}", peer, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Headers authenticating this replica to its peers' APIs.
+fn peer_headers(token: Option<&Secret>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let token = match token.map(Secret::resolve) {
+        Some(Ok(token)) => token,
+        Some(Err(e)) => {
+            warn!("Unable to read peer token: {}", e);
+            return headers;
+        }
+        None => return headers,
+    };
+    match HeaderValue::from_str(&format!("Bearer {}", token)) {
+        Ok(mut value) => {
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        Err(e) => warn!("Invalid peer token: {}", e),
+    }
+    headers
+}
+
+/// The peer API endpoint for one session, or for the whole session table.
+fn peer_session_uri(peer: &Url, session_id: Option<&str>) -> Option<Uri> {
+    let mut url = peer.clone();
+    match url.path_segments_mut() {
+        Ok(mut segments) => {
+            segments.pop_if_empty().extend(["api", "peer", "sessions"]);
+            if let Some(session_id) = session_id {
+                segments.push(session_id);
+            }
+        }
+        Err(_) => {
+            warn!("Peer address {} cannot have a path", peer);
+            return None;
+        }
+    }
+    Uri::from_str(url.as_str()).ok()
+}
+
+/// GET a JSON document, giving up after the timeout.
+async fn get_json<T: for<'de> Deserialize<'de>>(
+    client: HttpClient,
+    uri: Uri,
+    headers: HeaderMap,
+    limit: Duration,
+) -> Option<T> {
+    let mut request = match Request::get(uri.clone()).body(Body::empty()) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Unable to build request for {}: {}", uri, e);
+            return None;
+        }
+    };
+    *request.headers_mut() = headers;
+
+    let response = match timeout(limit, client.request(request)).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            warn!("Error requesting {}: {}", uri, e);
+            return None;
+        }
+        Err(_) => {
+            warn!("Timed out requesting {}", uri);
+            return None;
+        }
+    };
+
+    if response.status() != StatusCode::OK {
+        return None;
+    }
+
+    match timeout(limit, hyper::body::to_bytes(response.into_body())).await {
+        Ok(Ok(body)) => serde_json::from_slice(&body).ok(),
+        _ => None,
+    }
+}
+
+/// Ask every peer whether it holds a routing decision for the session.
+async fn resolve_from_peers(
+    session_id: &str,
+    client: HttpClient,
+    peers: &[Url],
+    token: Option<&Secret>,
+    limit: Duration,
+) -> Option<RoutingDecision> {
+    let headers = peer_headers(token);
+    let mut lookups: JoinSet<Option<RoutingDecision>> = JoinSet::new();
+    for peer in peers {
+        if let Some(uri) = peer_session_uri(peer, Some(session_id)) {
+            lookups.spawn(get_json(client.clone(), uri, headers.clone(), limit));
+        }
+    }
+
+    while let Some(result) = lookups.join_next().await {
+        if let Ok(Some(decision)) = result {
+            return Some(decision);
+        }
+    }
+    None
+}
+
+/// Ask every registered hub whether it is running the session.
+async fn resolve_from_hubs(
+    session_id: &str,
+    state: &HubRouterState,
+    limit: Duration,
+) -> Option<RoutingDecision> {
+    let mut lookups: JoinSet<Option<RoutingDecision>> = JoinSet::new();
+    for hub in state.hubs.iter() {
+        let mut endpoint = without_credentials(&hub.meta.url);
+        endpoint.set_path("/se/grid/session/");
+        if let Ok(mut segments) = endpoint.path_segments_mut() {
+            segments.pop_if_empty().push(session_id);
+        }
+        let uri = match Uri::from_str(endpoint.as_str()) {
+            Ok(uri) => uri,
+            Err(_) => continue,
+        };
+
+        let decision = RoutingDecision::new(
+            hub.meta.uuid,
+            hub.meta.url.clone(),
+            std::time::SystemTime::now(),
+        );
+        let client = state.clients.for_hub(hub.meta.insecure_skip_verify);
+        let mut headers = HeaderMap::new();
+        apply_hub_auth(&mut headers, &hub.meta);
+        lookups.spawn(async move {
+            get_json::<serde_json::Value>(client, uri, headers, limit)
+                .await
+                .map(|_| decision)
+        });
+    }
+
+    while let Some(result) = lookups.join_next().await {
+        if let Ok(Some(decision)) = result {
+            return Some(decision);
+        }
+    }
+    None
+}
+
+/// Find the hub running a session this replica has no routing decision for,
+/// by asking the other replicas first and then the hubs themselves.
+/// Whatever is found is remembered, so this only happens once per session,
+/// and sessions which nobody owns aren't looked up again for a few seconds.
+pub async fn resolve_unknown_session(
+    session_id: &str,
+    routing_map: Arc<RoutingPrecedentMap>,
+    state: Arc<HubRouterState>,
+) -> Option<RoutingDecision> {
+    let now = Instant::now();
+    if UNRESOLVED_SESSIONS
+        .get(session_id)
+        .is_some_and(|since| now.duration_since(*since) < UNRESOLVED_SESSION_TTL)
+    {
+        return None;
+    }
+
+    let (peers, token, limit) = match state.configs.read() {
+        Ok(conf) => (
+            parse_peers(&conf.peers),
+            conf.api_auth.peer_token.clone(),
+            Duration::from_secs(conf.healthcheck_timeout),
+        ),
+        Err(e) => {
+            warn!("RWLock poisoned reading peer configs: {}", e);
+            let conf = HubRouterPrimitiveConfigs::default();
+            (
+                parse_peers(&conf.peers),
+                conf.api_auth.peer_token,
+                Duration::from_secs(conf.healthcheck_timeout),
+            )
+        }
+    };
+
+    if let Some(decision) = resolve_from_peers(session_id, state.clients.outbound(), &peers, token.as_ref(), limit).await {
+        info!("Resolved session {} from a peer replica", session_id);
+        routing_map.insert_replica(session_id.to_string(), decision.clone());
+        return Some(decision);
+    }
+
+    if let Some(decision) = resolve_from_hubs(session_id, &state, limit).await {
+        info!(
+            "Resolved session {} to hub {}",
+            session_id, decision.hub_endpoint
+        );
+        routing_map.insert(session_id.to_string(), decision.clone());
+        return Some(decision);
+    }
+
+    UNRESOLVED_SESSIONS.retain(|_, since| now.duration_since(*since) < UNRESOLVED_SESSION_TTL);
+    UNRESOLVED_SESSIONS.insert(session_id.to_string(), now);
+    None
+}
+
+/// Copy the session table from the first peer which answers, so that a
+/// freshly started replica doesn't need to resolve every session one by one.
+pub async fn sync_from_peers(
+    routing_map: Arc<RoutingPrecedentMap>,
+    client: HttpClient,
+    peers: Vec<Url>,
+    token: Option<Secret>,
+    limit: Duration,
+) {
+    let headers = peer_headers(token.as_ref());
+    for peer in &peers {
+        let uri = match peer_session_uri(peer, None) {
+            Some(uri) => uri,
+            None => continue,
+        };
+
+        if let Some(sessions) = get_json::<Vec<PeerSession>>(client.clone(), uri, headers.clone(), limit).await {
+            info!("Synchronized {} sessions from peer {}", sessions.len(), peer);
+            for session in sessions {
+                if routing_map.get(&session.session_id).is_none() {
+                    routing_map.insert_replica(session.session_id, session.decision);
+                }
+            }
+            return;
+        }
+    }
+}
+
+#[test]
+fn test_peer_session_uri() {
+    let peer = Url::parse("http://router-1.example.com:8080").unwrap();
+    assert_eq!(
+        peer_session_uri(&peer, Some("abc123")).unwrap(),
+        "http://router-1.example.com:8080/api/peer/sessions/abc123"
+    );
+    assert_eq!(
+        peer_session_uri(&peer, None).unwrap(),
+        "http://router-1.example.com:8080/api/peer/sessions"
+    );
+
+    let prefixed = Url::parse("http://router-2/prefix/").unwrap();
+    assert_eq!(
+        peer_session_uri(&prefixed, Some("a/b")).unwrap(),
+        "http://router-2/prefix/api/peer/sessions/a%2Fb"
+    );
+}
+
+#[tokio::test]
+async fn test_replicas_share_sessions() {
+    use crate::{api::api_routes, api_auth::ApiAuthConfig, hub::Hub, session_store::MemorySessionStore};
+    use std::{net::SocketAddr, time::SystemTime};
+    use warp::Filter;
+
+    let free_addr = || {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    };
+    let replica = |peer: SocketAddr| {
+        let store = PeerSessionStore::new(
+            Box::new(MemorySessionStore),
+            vec![Url::parse(&format!("http://{}/", peer)).unwrap()],
+            None,
+            Arc::new(SharedClients::default()),
+        );
+        Arc::new(RoutingPrecedentMap::new(Box::new(store)))
+    };
+    let serve = |addr: SocketAddr, state: Arc<HubRouterState>, sessions: Arc<RoutingPrecedentMap>| {
+        let routes = api_routes(state, sessions, &ApiAuthConfig::default(), &[]);
+        let (_, server) = warp::serve(routes).try_bind_ephemeral(addr).unwrap();
+        tokio::task::spawn(server);
+    };
+    let eventually = |sessions: Arc<RoutingPrecedentMap>, session_id: &'static str, present: bool| async move {
+        for _ in 0..100 {
+            if sessions.get(session_id).is_some() == present {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    };
+    let decision = |endpoint: &str| {
+        RoutingDecision::new(uuid::Uuid::new_v4(), Url::parse(endpoint).unwrap(), SystemTime::now())
+    };
+
+    let (addr_a, addr_b) = (free_addr(), free_addr());
+    let (sessions_a, sessions_b) = (replica(addr_b), replica(addr_a));
+    let state_b = Arc::new(HubRouterState::default());
+    if let Ok(mut conf) = state_b.configs.write() {
+        conf.peers = vec![format!("http://{}/", addr_a)];
+    }
+    serve(addr_a, Arc::new(HubRouterState::default()), sessions_a.clone());
+    serve(addr_b, state_b.clone(), sessions_b.clone());
+
+    // A hub which is running sessions "s3" and "s4"
+    let hub_route = warp::path!("se" / "grid" / "session" / String).map(|session_id: String| {
+        let status = match session_id.as_str() {
+            "s3" | "s4" => warp::http::StatusCode::OK,
+            _ => warp::http::StatusCode::NOT_FOUND,
+        };
+        warp::reply::with_status(warp::reply::json(&session_id), status)
+    });
+    let (hub_addr, hub_server) = warp::serve(hub_route).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::task::spawn(hub_server);
+    let hub = Hub::new(Url::parse(&format!("http://{}/", hub_addr)).unwrap());
+    let hub_uuid = hub.meta.uuid;
+    state_b.hubs.insert(hub_uuid, hub);
+
+    // Decisions and deletions are pushed to the other replica
+    sessions_a.insert("s1".to_string(), decision("http://grid-a:4444/"));
+    assert!(eventually(sessions_b.clone(), "s1", true).await);
+    sessions_a.remove("s1");
+    assert!(eventually(sessions_b.clone(), "s1", false).await);
+
+    // A new replica copies the whole table
+    sessions_a.insert_replica("s2".to_string(), decision("http://grid-a:4444/"));
+    let sessions_c = replica(addr_a);
+    sync_from_peers(
+        sessions_c.clone(),
+        state_b.clients.outbound(),
+        vec![Url::parse(&format!("http://{}/", addr_a)).unwrap()],
+        None,
+        Duration::from_secs(2),
+    )
+    .await;
+    assert!(sessions_c.get("s2").is_some());
+
+    // Peers are asked before the hubs, which are asked before giving up
+    sessions_a.insert_replica("s3".to_string(), decision("http://grid-a:4444/"));
+    let resolved = resolve_unknown_session("s3", sessions_b.clone(), state_b.clone()).await;
+    assert_eq!(resolved.unwrap().hub_endpoint.as_str(), "http://grid-a:4444/");
+    assert!(sessions_b.get("s3").is_some());
+
+    let resolved = resolve_unknown_session("s4", sessions_b.clone(), state_b.clone()).await;
+    assert_eq!(resolved.unwrap().hub_uuid, hub_uuid);
+    assert!(sessions_b.get("s4").is_some());
+
+    // A session nobody owns isn't looked up again straight away
+    assert!(resolve_unknown_session("s5", sessions_b.clone(), state_b.clone()).await.is_none());
+    sessions_a.insert_replica("s5".to_string(), decision("http://grid-a:4444/"));
+    assert!(resolve_unknown_session("s5", sessions_b.clone(), state_b.clone()).await.is_none());
+}
@@ -0,0 +1,448 @@
+//! Identifying the clients of the WebDriver proxy, and enforcing per-client
+//! quotas on new sessions, so that one team's runaway suite can't starve
+//! everyone else. Clients identify themselves with basic auth or a bearer
+//! token, or by naming their team in the `hubrouter:team` capability.
+//! Only new session requests are checked; the session ID is what grants
+//! access to a session after that.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use base64::Engine;
+use dashmap::DashMap;
+use hyper::{header::AUTHORIZATION, HeaderMap};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    api_auth::constant_time_eq, credentials::Secret, error::RoutingError,
+    routing::RoutingPrecedentMap, schema::NewSessionRequestCapability,
+};
+
+/// The capability clients may name their team with.
+pub const TEAM_CAPABILITY: &str = "hubrouter:team";
+
+/// Limits on the sessions a single client may start.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+#[serde(default)]
+pub struct ClientQuota {
+    /// The most sessions the client may be running, or waiting to start, at once.
+    pub max_concurrent_sessions: Option<u32>,
+
+    /// The most new sessions the client may request in any one minute.
+    pub max_sessions_per_minute: Option<u32>,
+}
+
+/// A known client of the proxy.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ProxyClient {
+    /// The client's identity, which is also its basic auth username and team name.
+    pub name: String,
+
+    /// Bearer token identifying the client.
+    #[serde(default)]
+    pub token: Option<Secret>,
+
+    /// Basic auth password identifying the client. When either this or a token
+    /// is set, naming the client in the `hubrouter:team` capability isn't enough.
+    #[serde(default)]
+    pub password: Option<Secret>,
+
+    /// The client's own quota, instead of the default one.
+    #[serde(default)]
+    pub quota: Option<ClientQuota>,
+}
+
+impl ProxyClient {
+    fn has_credentials(&self) -> bool {
+        self.token.is_some() || self.password.is_some()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+#[serde(default)]
+pub struct ProxyClientsConfig {
+    pub clients: Vec<ProxyClient>,
+
+    /// Refuse new sessions from clients which don't identify themselves.
+    pub require_identity: bool,
+
+    /// The quota for identified clients which don't have their own.
+    pub default_quota: ClientQuota,
+}
+
+impl ProxyClientsConfig {
+    /// A copy which is safe to return from the API, with inline secrets hidden.
+    pub fn redacted(&self) -> Self {
+        Self {
+            clients: self
+                .clients
+                .iter()
+                .map(|c| ProxyClient {
+                    token: c.token.as_ref().map(Secret::redacted),
+                    password: c.password.as_ref().map(Secret::redacted),
+                    ..c.clone()
+                })
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Put back secrets which are still redacted, as when a configuration
+    /// read from the API is sent back with other changes.
+    pub fn restore_redacted(&mut self, current: &ProxyClientsConfig) {
+        for client in self.clients.iter_mut() {
+            let existing = match current.clients.iter().find(|c| c.name == client.name) {
+                Some(existing) => existing,
+                None => continue,
+            };
+            if client.token.as_ref().is_some_and(Secret::is_redacted) {
+                client.token = existing.token.clone();
+            }
+            if client.password.as_ref().is_some_and(Secret::is_redacted) {
+                client.password = existing.password.clone();
+            }
+        }
+    }
+
+    fn quota_for(&self, name: &str) -> ClientQuota {
+        self.clients
+            .iter()
+            .find(|c| c.name == name)
+            .and_then(|c| c.quota.clone())
+            .unwrap_or_else(|| self.default_quota.clone())
+    }
+
+    /// The client which the credentials in these headers belong to, if any.
+    fn authenticate(&self, headers: &HeaderMap) -> Option<&ProxyClient> {
+        let header = headers.get(AUTHORIZATION)?.to_str().ok()?.trim();
+        let (scheme, value) = header.split_once(' ')?;
+        let matches = |secret: &Option<Secret>, presented: &str| match secret.as_ref().map(Secret::resolve) {
+            Some(Ok(secret)) => constant_time_eq(secret.as_bytes(), presented.as_bytes()),
+            Some(Err(e)) => {
+                warn!("Unable to read proxy client secret: {}", e);
+                false
+            }
+            None => false,
+        };
+
+        if scheme.eq_ignore_ascii_case("bearer") {
+            self.clients.iter().find(|c| matches(&c.token, value.trim()))
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = base64::engine::general_purpose::STANDARD
+                .decode(value.trim())
+                .ok()?;
+            let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+            self.clients
+                .iter()
+                .find(|c| c.name == username && matches(&c.password, password))
+        } else {
+            None
+        }
+    }
+
+    /// Remove a client's credentials from a request, so that they aren't passed on
+    /// to hubs. Credentials which don't belong to any client are left for the hub.
+    pub fn strip_credentials(&self, headers: &mut HeaderMap) {
+        if self.authenticate(headers).is_some() {
+            headers.remove(AUTHORIZATION);
+        }
+    }
+
+    /// Work out who is making a new session request, and the quota which applies to them.
+    /// Credentials which don't belong to any client are ignored, as they may be meant for the hub.
+    pub fn identify(
+        &self,
+        headers: &HeaderMap,
+        requests: &[NewSessionRequestCapability],
+    ) -> Result<Option<(String, ClientQuota)>, RoutingError> {
+        if let Some(client) = self.authenticate(headers) {
+            return Ok(Some((client.name.clone(), self.quota_for(&client.name))));
+        }
+
+        let team = requests
+            .iter()
+            .find_map(|r| r.additional.get(TEAM_CAPABILITY).and_then(|v| v.as_str()));
+        match team {
+            Some(team) if self.clients.iter().any(|c| c.name == team && c.has_credentials()) => {
+                Err(RoutingError::ClientNotIdentified(format!(
+                    "client {} must authenticate to start sessions",
+                    team
+                )))
+            }
+            Some(team) => Ok(Some((team.to_string(), self.quota_for(team)))),
+            None if self.require_identity => Err(RoutingError::ClientNotIdentified(format!(
+                "identify with basic auth, a bearer token or the {} capability to start sessions",
+                TEAM_CAPABILITY
+            ))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ClientCounters {
+    /// New session requests which were admitted and haven't finished yet.
+    pending: u32,
+
+    /// When each new session request in the last minute was admitted.
+    admitted: VecDeque<Instant>,
+}
+
+impl ClientCounters {
+    fn prune(&mut self, now: Instant) {
+        while let Some(oldest) = self.admitted.front() {
+            if now.duration_since(*oldest) < Duration::from_secs(60) {
+                break;
+            }
+            self.admitted.pop_front();
+        }
+    }
+
+    /// Whether there's nothing left to count, so the client needn't be tracked.
+    fn is_idle(&self) -> bool {
+        self.pending == 0 && self.admitted.is_empty()
+    }
+}
+
+/// Each client's usage of its quota. Clients are only tracked while they have
+/// requests starting or counted against the last minute, as anyone can name a team.
+#[derive(Debug, Default)]
+pub struct ClientUsage {
+    counters: DashMap<String, ClientCounters>,
+}
+
+/// Current usage of one client, as reported by the API.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ClientUsageStatus {
+    pub name: String,
+    pub active_sessions: usize,
+    pub pending_sessions: u32,
+    pub sessions_last_minute: usize,
+    pub quota: ClientQuota,
+}
+
+/// A new session request counted against a client's quota. The request stops
+/// counting as pending when this is dropped, by which time a created session
+/// is counted from the routing precedent map instead.
+pub struct Admission<'a> {
+    usage: &'a ClientUsage,
+    client: String,
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        if let Some(mut counters) = self.usage.counters.get_mut(&self.client) {
+            counters.pending = counters.pending.saturating_sub(1);
+            counters.prune(Instant::now());
+        }
+        self.usage
+            .counters
+            .remove_if(&self.client, |_, counters| counters.is_idle());
+    }
+}
+
+impl ClientUsage {
+    /// Count a new session request against the client's quota, or reject it
+    /// if that would take the client over its quota.
+    pub fn admit(
+        &self,
+        client: &str,
+        quota: &ClientQuota,
+        sessions: &RoutingPrecedentMap,
+    ) -> Result<Admission<'_>, RoutingError> {
+        let active = sessions.sessions_for_client(client);
+        let now = Instant::now();
+        self.forget_idle(now);
+        let mut counters = self.counters.entry(client.to_string()).or_default();
+        counters.prune(now);
+
+        if let Some(max) = quota.max_concurrent_sessions {
+            if active + counters.pending as usize >= max as usize {
+                return Err(RoutingError::QuotaExceeded(format!(
+                    "client {} already has {} sessions running or starting, of its {} allowed",
+                    client,
+                    active + counters.pending as usize,
+                    max
+                )));
+            }
+        }
+        if let Some(max) = quota.max_sessions_per_minute {
+            if counters.admitted.len() >= max as usize {
+                return Err(RoutingError::QuotaExceeded(format!(
+                    "client {} has already requested {} sessions in the last minute, of its {} allowed",
+                    client,
+                    counters.admitted.len(),
+                    max
+                )));
+            }
+        }
+
+        counters.pending += 1;
+        counters.admitted.push_back(now);
+        Ok(Admission {
+            usage: self,
+            client: client.to_string(),
+        })
+    }
+
+    /// Stop tracking clients with nothing counting against their quota.
+    fn forget_idle(&self, now: Instant) {
+        self.counters.retain(|_, counters| {
+            counters.prune(now);
+            !counters.is_idle()
+        });
+    }
+
+    /// Usage of every client which has requested a session recently or is configured.
+    pub fn status(
+        &self,
+        config: &ProxyClientsConfig,
+        sessions: &RoutingPrecedentMap,
+    ) -> Vec<ClientUsageStatus> {
+        let now = Instant::now();
+        self.forget_idle(now);
+        let mut names: Vec<String> = self.counters.iter().map(|c| c.key().clone()).collect();
+        for client in &config.clients {
+            if !names.contains(&client.name) {
+                names.push(client.name.clone());
+            }
+        }
+        names.sort();
+
+        names
+            .into_iter()
+            .map(|name| {
+                let (pending_sessions, sessions_last_minute) = match self.counters.get_mut(&name)
+                {
+                    Some(mut counters) => {
+                        counters.prune(now);
+                        (counters.pending, counters.admitted.len())
+                    }
+                    None => (0, 0),
+                };
+                ClientUsageStatus {
+                    active_sessions: sessions.sessions_for_client(&name),
+                    pending_sessions,
+                    sessions_last_minute,
+                    quota: config.quota_for(&name),
+                    name,
+                }
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_client_identity_and_quotas() {
+    use crate::routing::RoutingDecision;
+    use std::time::SystemTime;
+
+    let config = ProxyClientsConfig {
+        clients: vec![
+            ProxyClient {
+                name: "checkout".into(),
+                token: Some(Secret::Value("checkout-token".into())),
+                password: None,
+                quota: Some(ClientQuota {
+                    max_concurrent_sessions: Some(2),
+                    max_sessions_per_minute: None,
+                }),
+            },
+            ProxyClient {
+                name: "search".into(),
+                token: None,
+                password: Some(Secret::Value("hunter2".into())),
+                quota: None,
+            },
+        ],
+        require_identity: false,
+        default_quota: ClientQuota {
+            max_concurrent_sessions: None,
+            max_sessions_per_minute: Some(1),
+        },
+    };
+    let team = |name: &str| {
+        let mut capability = NewSessionRequestCapability::default();
+        capability
+            .additional
+            .insert(TEAM_CAPABILITY.into(), serde_json::Value::String(name.into()));
+        vec![capability]
+    };
+    let headers = |value: String| {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, value.parse().unwrap());
+        headers
+    };
+
+    let bearer = headers("Bearer checkout-token".into());
+    assert_eq!(
+        config.identify(&bearer, &[]).unwrap().unwrap().0,
+        "checkout"
+    );
+    let basic = headers(format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode("search:hunter2")
+    ));
+    assert_eq!(config.identify(&basic, &[]).unwrap().unwrap().0, "search");
+
+    // A client's credentials aren't passed on to hubs, but anyone else's are
+    let mut stripped = basic.clone();
+    config.strip_credentials(&mut stripped);
+    assert!(stripped.get(AUTHORIZATION).is_none());
+    let mut kept = headers("Bearer grid-token".into());
+    config.strip_credentials(&mut kept);
+    assert!(kept.get(AUTHORIZATION).is_some());
+
+    // A client with credentials can't be impersonated by capability
+    assert!(config.identify(&HeaderMap::new(), &team("checkout")).is_err());
+    assert_eq!(
+        config.identify(&HeaderMap::new(), &team("mobile")).unwrap(),
+        Some(("mobile".to_string(), config.default_quota.clone()))
+    );
+    assert_eq!(config.identify(&HeaderMap::new(), &[]).unwrap(), None);
+    assert!(ProxyClientsConfig {
+        require_identity: true,
+        ..config.clone()
+    }
+    .identify(&HeaderMap::new(), &[])
+    .is_err());
+
+    // Concurrent sessions count both running sessions and requests still starting
+    let usage = ClientUsage::default();
+    let sessions = RoutingPrecedentMap::default();
+    let checkout_quota = config.quota_for("checkout");
+    let mut decision = RoutingDecision::new(
+        uuid::Uuid::new_v4(),
+        url::Url::parse("http://hub:4444").unwrap(),
+        SystemTime::now(),
+    );
+    decision.client = Some("checkout".into());
+    sessions.insert("running".into(), decision);
+    let starting = usage.admit("checkout", &checkout_quota, &sessions).unwrap();
+    assert!(usage.admit("checkout", &checkout_quota, &sessions).is_err());
+    drop(starting);
+    let _starting = usage.admit("checkout", &checkout_quota, &sessions).unwrap();
+
+    // The per minute quota counts requests, whether or not they finished
+    let mobile_quota = config.quota_for("mobile");
+    drop(usage.admit("mobile", &mobile_quota, &sessions).unwrap());
+    assert!(usage.admit("mobile", &mobile_quota, &sessions).is_err());
+
+    // Clients are forgotten once nothing counts against their quota
+    let closed = ClientQuota {
+        max_concurrent_sessions: Some(0),
+        max_sessions_per_minute: None,
+    };
+    assert!(usage.admit("turned-away", &closed, &sessions).is_err());
+
+    let status = usage.status(&config, &sessions);
+    let names: Vec<&str> = status.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, vec!["checkout", "mobile", "search"]);
+    assert_eq!(status[0].active_sessions, 1);
+    assert_eq!(status[0].pending_sessions, 1);
+    assert_eq!(status[1].sessions_last_minute, 1);
+}
@@ -0,0 +1,157 @@
+//! A bounded, FIFO queue for new session requests which cannot be routed
+//! immediately because every capable hub is full (or no hub is healthy).
+//! Requests are queued per set of requested capabilities, and are woken up
+//! whenever the healthcheck thread reports fresh fullness information or a
+//! session is deleted, at which point the request at the head of each queue
+//! retries its routing decision.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{futures::Notified, Notify};
+
+use crate::schema::NewSessionRequestCapability;
+
+/// The key a new session request is queued under: the list of capabilities it asked for.
+pub type QueueKey = Vec<NewSessionRequestCapability>;
+
+/// Holds all new session requests that are waiting for a free slot.
+#[derive(Debug, Default)]
+pub struct NewSessionQueue {
+    queues: Mutex<HashMap<QueueKey, VecDeque<u64>>>,
+    next_ticket: AtomicU64,
+    slot_freed: Notify,
+}
+
+/// A place in the new session queue. The request is removed from the queue
+/// when its ticket is dropped, whether it was routed, timed out, or the client went away.
+pub struct QueueTicket<'a> {
+    queue: &'a NewSessionQueue,
+    key: QueueKey,
+    id: u64,
+}
+
+/// The number of requests waiting for a particular set of capabilities.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueDepth {
+    pub capabilities: QueueKey,
+    pub depth: usize,
+}
+
+impl NewSessionQueue {
+    /// Place a request at the back of the queue for its capabilities, unless that
+    /// queue already holds `max_depth` requests.
+    pub fn enqueue(&self, key: QueueKey, max_depth: usize) -> Option<QueueTicket<'_>> {
+        let id = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        match self.queues.lock() {
+            Ok(mut queues) => {
+                let queue = queues.entry(key.clone()).or_default();
+                if queue.len() >= max_depth {
+                    return None;
+                }
+                queue.push_back(id);
+            }
+            Err(e) => {
+                warn!("New session queue mutex was poisoned: {}", e);
+                return None;
+            }
+        }
+
+        Some(QueueTicket {
+            queue: self,
+            key,
+            id,
+        })
+    }
+
+    /// Returns a future which resolves the next time a slot may have been freed.
+    /// The future must be created before checking for capacity, so that wakeups
+    /// which happen in between aren't missed.
+    pub fn slot_freed(&self) -> Notified<'_> {
+        self.slot_freed.notified()
+    }
+
+    /// Wake every queued request so that the head of each queue retries routing.
+    pub fn notify_slot_freed(&self) {
+        self.slot_freed.notify_waiters();
+    }
+
+    /// The number of requests waiting, per set of requested capabilities.
+    pub fn depths(&self) -> Vec<QueueDepth> {
+        match self.queues.lock() {
+            Ok(queues) => queues
+                .iter()
+                .map(|(capabilities, queue)| QueueDepth {
+                    capabilities: capabilities.clone(),
+                    depth: queue.len(),
+                })
+                .collect(),
+            Err(e) => {
+                warn!("New session queue mutex was poisoned: {}", e);
+                vec![]
+            }
+        }
+    }
+
+    /// The total number of requests waiting across all queues.
+    pub fn total_depth(&self) -> usize {
+        self.depths().iter().map(|d| d.depth).sum()
+    }
+}
+
+impl QueueTicket<'_> {
+    /// Whether this request is first in line for its capabilities.
+    pub fn is_head(&self) -> bool {
+        match self.queue.queues.lock() {
+            Ok(queues) => queues
+                .get(&self.key)
+                .and_then(|q| q.front())
+                .is_some_and(|head| *head == self.id),
+            Err(_) => false,
+        }
+    }
+}
+
+impl Drop for QueueTicket<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut queues) = self.queue.queues.lock() {
+            if let Some(queue) = queues.get_mut(&self.key) {
+                queue.retain(|id| *id != self.id);
+                if queue.is_empty() {
+                    queues.remove(&self.key);
+                }
+            }
+        }
+
+        // The next request in line may be able to go now
+        self.queue.notify_slot_freed();
+    }
+}
+
+#[test]
+fn test_queue_is_fifo_and_bounded() {
+    let queue = NewSessionQueue::default();
+    let key = vec![NewSessionRequestCapability {
+        browserName: Some("firefox".into()),
+        ..Default::default()
+    }];
+
+    let first = queue.enqueue(key.clone(), 2).unwrap();
+    let second = queue.enqueue(key.clone(), 2).unwrap();
+    assert!(queue.enqueue(key.clone(), 2).is_none());
+    assert!(first.is_head());
+    assert!(!second.is_head());
+    assert_eq!(queue.total_depth(), 2);
+
+    drop(first);
+    assert!(second.is_head());
+    drop(second);
+    assert_eq!(queue.total_depth(), 0);
+}
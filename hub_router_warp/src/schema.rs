@@ -1,9 +1,18 @@
 //! Serde struct definitions for JSON schemae which we expect from various
 //! API endpoints on the Selenium hubs
 
+use std::{
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    time::SystemTime,
+};
+
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use uuid::Uuid;
 
-use crate::routing::Endpoint;
+use crate::routing::{Endpoint, RoutingDecision};
 
 #[derive(Deserialize, Debug, Clone)]
 #[allow(non_snake_case, unused)]
@@ -18,13 +27,17 @@ pub struct NewSessionResponseValue {
     pub capabilities: NewSessionResponseCapabilities,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[allow(non_snake_case, unused)]
 pub struct NewSessionResponseCapabilities {
     pub acceptInsecureCerts: Option<bool>,
     pub browserName: Option<String>,
     pub browserVersion: Option<String>,
     pub platformName: Option<String>,
+
+    /// Every other capability the session was created with
+    #[serde(flatten)]
+    pub additional: BTreeMap<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -69,7 +82,6 @@ pub struct HubStatusNodeSlotJSONSchema {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[allow(non_snake_case)]
-
 pub struct HubStatusNodeSlotIDJSONSchema {
     pub hostId: String,
     pub id: String,
@@ -77,7 +89,6 @@ pub struct HubStatusNodeSlotIDJSONSchema {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[allow(non_snake_case)]
-
 pub struct HubStatusNodeSlotSessionJSONSchema {
     pub capabilities: Option<HubStatusNodeSlotSessionCapabilitiesJSONSchema>,
     pub sessionId: String,
@@ -88,74 +99,216 @@ pub struct HubStatusNodeSlotSessionJSONSchema {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[allow(non_snake_case)]
-
 pub struct HubStatusNodeSlotSessionCapabilitiesJSONSchema {
     pub acceptInsecureCerts: Option<bool>,
     pub browserName: Option<String>,
     pub browserVersion: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq)]
+/// The capabilities a slot on a node advertises. Besides the browser and platform,
+/// nodes may report a browser version and any number of custom stereotype keys.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq)]
 #[allow(non_snake_case)]
-
 pub struct HubStatusStereotypeJSONSchema {
     pub browserName: String,
     pub platformName: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub browserVersion: Option<String>,
+
+    #[serde(flatten)]
+    pub additional: BTreeMap<String, Value>,
+}
+
+// Equality is case-insensitive, so hashing must be as well
+impl Hash for HubStatusStereotypeJSONSchema {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.browserName.to_ascii_lowercase().hash(state);
+        self.platformName.to_ascii_lowercase().hash(state);
+        self.browserVersion.hash(state);
+        hash_capability_values(&self.additional, state);
+    }
 }
 
 impl PartialEq for HubStatusStereotypeJSONSchema {
     fn eq(&self, other: &Self) -> bool {
         self.browserName.eq_ignore_ascii_case(&other.browserName)
             && self.platformName.eq_ignore_ascii_case(&other.platformName)
+            && self.browserVersion == other.browserVersion
+            && self.additional == other.additional
     }
 }
 
-impl Into<NewSessionRequestCapability> for HubStatusStereotypeJSONSchema {
-    fn into(self) -> NewSessionRequestCapability {
-        NewSessionRequestCapability {
-            browserName: Some(self.browserName),
-            platformName: Some(self.platformName),
-        }
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[allow(non_snake_case)]
-
 pub struct NewSessionRequestBody {
-    pub capabilities: NewSessionRequestCapabilities,
+    /// W3C capabilities, sent by every Selenium 4 (and most Selenium 3) client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<NewSessionRequestCapabilities>,
+
+    /// Legacy JSON Wire Protocol capabilities, sent by older Selenium 3 clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desiredCapabilities: Option<Map<String, Value>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+/// The raw `capabilities` object of a new session request, before capability processing.
+/// Either member may be omitted, in which case the spec defaults apply.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[allow(non_snake_case)]
-
 pub struct NewSessionRequestCapabilities {
-    pub alwaysMatch: NewSessionRequestCapability,
-    pub firstMatch: Vec<NewSessionRequestCapability>,
+    #[serde(default)]
+    pub alwaysMatch: Map<String, Value>,
+    #[serde(default = "default_first_match")]
+    pub firstMatch: Vec<Map<String, Value>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
-#[allow(non_snake_case)]
+fn default_first_match() -> Vec<Map<String, Value>> {
+    vec![Map::new()]
+}
 
+/// A single, merged set of capabilities (alwaysMatch combined with one firstMatch entry)
+/// which a hub must be able to satisfy to serve a new session request.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[allow(non_snake_case)]
 pub struct NewSessionRequestCapability {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub browserName: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub browserVersion: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub platformName: Option<String>,
+
+    /// Every other standard or extension capability which was requested
+    #[serde(flatten)]
+    pub additional: BTreeMap<String, Value>,
+}
+
+impl Hash for NewSessionRequestCapability {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.browserName.hash(state);
+        self.browserVersion.hash(state);
+        self.platformName.hash(state);
+        hash_capability_values(&self.additional, state);
+    }
 }
 
+/// Prefixes of extension capabilities which carry options for a particular browser
+/// driver. These configure the browser once a session starts, and never describe a node.
+const BROWSER_OPTION_PREFIXES: [&str; 5] = ["goog:", "moz:", "ms:", "safari:", "webkit:"];
+
+/// Prefix of extension capabilities which Selenium itself interprets.
+const SELENIUM_PREFIX: &str = "se:";
+
+/// Prefix of extension capabilities which the Hub Router interprets, such as `hubrouter:team`.
+const HUB_ROUTER_PREFIX: &str = "hubrouter:";
+
 impl NewSessionRequestCapability {
-    pub fn satisfied_by(&self, other: &NewSessionRequestCapability) -> bool {
-        if self.platformName.is_some() && self.platformName != other.platformName {
-            return false;
+    /// Check whether a slot with the given stereotype can serve these capabilities,
+    /// following the same rules Selenium Grid uses to match slots:
+    ///  - `browserName` and `platformName` must match, ignoring case (`platformName: any` matches every platform).
+    ///  - `browserVersion` must match the stereotype's version exactly, or be a prefix of
+    ///    it at a `.` boundary (`115` matches `115.0.5790.102`). `stable` and `latest` match any version.
+    ///  - Any other capability the stereotype advertises must be equal to the requested value.
+    ///  - Custom extension capabilities (not `se:`, `hubrouter:` or browser driver options) must be advertised by the stereotype.
+    pub fn satisfied_by(&self, stereotype: &HubStatusStereotypeJSONSchema) -> bool {
+        if let Some(browser_name) = &self.browserName {
+            if !browser_name.eq_ignore_ascii_case(&stereotype.browserName) {
+                return false;
+            }
         }
 
-        if self.browserName.is_some() && self.browserName != other.browserName {
-            return false;
+        if let Some(platform_name) = &self.platformName {
+            if !platform_name.eq_ignore_ascii_case("any")
+                && !platform_name.eq_ignore_ascii_case(&stereotype.platformName)
+            {
+                return false;
+            }
         }
 
-        return true;
+        if let Some(version) = &self.browserVersion {
+            let any_version = version.is_empty()
+                || version.eq_ignore_ascii_case("stable")
+                || version.eq_ignore_ascii_case("latest");
+            let matches_version = match &stereotype.browserVersion {
+                Some(advertised) => {
+                    advertised == version || advertised.starts_with(&format!("{}.", version))
+                }
+                None => false,
+            };
+            if !any_version && !matches_version {
+                return false;
+            }
+        }
+
+        self.additional.iter().all(|(name, value)| {
+            if BROWSER_OPTION_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+                || name.starts_with(HUB_ROUTER_PREFIX)
+            {
+                return true;
+            }
+
+            match stereotype.additional.get(name) {
+                Some(advertised) => capability_values_match(value, advertised),
+                None => !name.contains(':') || name.starts_with(SELENIUM_PREFIX),
+            }
+        })
+    }
+}
+
+/// Compare two capability values, ignoring the case of strings.
+fn capability_values_match(requested: &Value, advertised: &Value) -> bool {
+    match (requested, advertised) {
+        (Value::String(r), Value::String(a)) => r.eq_ignore_ascii_case(a),
+        (r, a) => r == a,
+    }
+}
+
+/// serde_json values don't implement Hash, so hash their canonical serialization instead.
+fn hash_capability_values<H: Hasher>(values: &BTreeMap<String, Value>, state: &mut H) {
+    for (name, value) in values {
+        name.hash(state);
+        value.to_string().hash(state);
     }
 }
 
+#[test]
+fn test_capability_satisfied_by() {
+    let stereotype: HubStatusStereotypeJSONSchema = serde_json::from_value(serde_json::json!({
+        "browserName": "chrome",
+        "browserVersion": "115.0",
+        "platformName": "LINUX",
+        "se:vncEnabled": true,
+        "myorg:gpu": "nvidia"
+    }))
+    .unwrap();
+
+    let capability = |value: Value| -> NewSessionRequestCapability {
+        serde_json::from_value(value).unwrap()
+    };
+
+    assert!(capability(serde_json::json!({"browserName": "Chrome", "platformName": "linux"}))
+        .satisfied_by(&stereotype));
+    assert!(capability(serde_json::json!({"platformName": "any"})).satisfied_by(&stereotype));
+    assert!(!capability(serde_json::json!({"browserName": "firefox"})).satisfied_by(&stereotype));
+
+    assert!(capability(serde_json::json!({"browserVersion": "115"})).satisfied_by(&stereotype));
+    assert!(capability(serde_json::json!({"browserVersion": "stable"})).satisfied_by(&stereotype));
+    assert!(!capability(serde_json::json!({"browserVersion": "11"})).satisfied_by(&stereotype));
+    assert!(!capability(serde_json::json!({"browserVersion": "116"})).satisfied_by(&stereotype));
+
+    assert!(capability(serde_json::json!({"acceptInsecureCerts": true, "goog:chromeOptions": {}}))
+        .satisfied_by(&stereotype));
+    assert!(capability(serde_json::json!({"se:vncEnabled": true, "se:name": "test"}))
+        .satisfied_by(&stereotype));
+    assert!(!capability(serde_json::json!({"se:vncEnabled": false})).satisfied_by(&stereotype));
+    assert!(capability(serde_json::json!({"myorg:gpu": "NVIDIA"})).satisfied_by(&stereotype));
+    assert!(!capability(serde_json::json!({"myorg:tpu": true})).satisfied_by(&stereotype));
+}
+
 /// Session is the object for serializing internal session data for
 /// consumption by the external API.
 #[derive(Debug, Serialize, Deserialize)]
@@ -165,18 +318,53 @@ pub struct Session {
     #[serde(serialize_with = "crate::utils::serialize_url")]
     #[serde(deserialize_with = "crate::utils::deserialize_url")]
     endpoint: Endpoint,
+
+    #[serde(serialize_with = "crate::utils::serialize_uuid")]
+    #[serde(deserialize_with = "crate::utils::deserialize_uuid")]
+    hub_uuid: Uuid,
+
+    /// Missing if the hub has since been deregistered.
+    hub_name: Option<String>,
+
+    browser_name: Option<String>,
+    browser_version: Option<String>,
+    platform_name: Option<String>,
+    client: Option<String>,
+    client_address: Option<SocketAddr>,
+    created: SystemTime,
+
+    last_command: Option<SystemTime>,
+
+    /// Only commands forwarded by this replica are counted.
+    command_count: u64,
 }
 
 impl Session {
-    pub fn new(id: &String, endpoint: &Endpoint) -> Self {
+    pub fn new(
+        id: &str,
+        decision: &RoutingDecision,
+        hub_name: Option<String>,
+        command_count: u64,
+    ) -> Self {
         Self {
-            id: id.clone(),
-            endpoint: endpoint.clone(),
+            id: id.to_string(),
+            endpoint: decision.hub_endpoint.clone(),
+            hub_uuid: decision.hub_uuid,
+            hub_name,
+            browser_name: decision.browser_name.clone(),
+            browser_version: decision.browser_version.clone(),
+            platform_name: decision.platform_name.clone(),
+            client: decision.client.clone(),
+            client_address: decision.client_address,
+            created: decision.decision_time,
+            last_command: decision.last_command,
+            command_count,
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
 pub struct HubGraphQLQuery {
     pub query: String,
 }
@@ -0,0 +1,305 @@
+//! Pluggable persistence for routing decisions, so that sessions which were
+//! in flight when the Hub Router restarted continue to be routed to their hub.
+//! Every decision is written through to a `SessionStore` as it is made or
+//! forgotten, and the store is replayed into the routing precedent map on startup.
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    client::SharedClients,
+    credentials::Secret,
+    peers::{parse_peers, PeerSessionStore},
+    routing::RoutingDecision,
+};
+
+/// Which backend routing decisions are persisted to.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionStoreConfig {
+    /// Routing decisions are only held in memory, and are lost on restart.
+    #[default]
+    Memory,
+
+    /// Routing decisions are journaled to a local file.
+    File { path: String },
+}
+
+/// A durable home for routing decisions. Implementations are written to
+/// synchronously from the request path, so they should be quick.
+pub trait SessionStore: Send + Sync + Debug {
+    /// Read back every routing decision which was recorded and not since forgotten.
+    fn load(&self) -> Result<Vec<(String, RoutingDecision)>, String>;
+
+    /// Remember the routing decision made for a session.
+    fn record(&self, session_id: &str, decision: &RoutingDecision) -> Result<(), String>;
+
+    /// Forget a session which has been deleted or reaped.
+    fn forget(&self, session_id: &str) -> Result<(), String>;
+
+    /// Remember a routing decision which another replica made. Stores which
+    /// share decisions between replicas must not send it back out.
+    fn record_replica(&self, session_id: &str, decision: &RoutingDecision) -> Result<(), String> {
+        self.record(session_id, decision)
+    }
+
+    /// Forget a session which another replica has deleted or reaped.
+    fn forget_replica(&self, session_id: &str) -> Result<(), String> {
+        self.forget(session_id)
+    }
+
+    /// Discard any history the store has built up, keeping only the given live sessions.
+    fn compact(&self, _live: &[(String, RoutingDecision)]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Build the session store described by the configuration. When peer replicas
+/// are configured, every change is also shared with them, authenticating with the peer token.
+pub fn session_store_from_config(
+    config: &SessionStoreConfig,
+    peers: &[String],
+    peer_token: Option<Secret>,
+    clients: Arc<SharedClients>,
+) -> Box<dyn SessionStore> {
+    let store: Box<dyn SessionStore> = match config {
+        SessionStoreConfig::Memory => Box::new(MemorySessionStore),
+        SessionStoreConfig::File { path } => Box::new(FileSessionStore::new(path)),
+    };
+
+    let peers = parse_peers(peers);
+    if peers.is_empty() {
+        store
+    } else {
+        Box::new(PeerSessionStore::new(store, peers, peer_token, clients))
+    }
+}
+
+/// The default store, which persists nothing.
+#[derive(Debug, Default)]
+pub struct MemorySessionStore;
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self) -> Result<Vec<(String, RoutingDecision)>, String> {
+        Ok(vec![])
+    }
+
+    fn record(&self, _session_id: &str, _decision: &RoutingDecision) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn forget(&self, _session_id: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A single line of the session journal.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum JournalEntry {
+    Record {
+        session_id: String,
+        decision: Box<RoutingDecision>,
+    },
+    Forget {
+        session_id: String,
+    },
+}
+
+/// Persists routing decisions to an append-only journal of JSON lines, which
+/// needs no external services. The journal is rewritten with only the live
+/// sessions on startup and whenever the reaper runs, so that it doesn't grow forever.
+#[derive(Debug)]
+pub struct FileSessionStore {
+    path: PathBuf,
+    journal: Mutex<Option<File>>,
+}
+
+impl FileSessionStore {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            journal: Mutex::new(None),
+        }
+    }
+
+    fn append(&self, entry: &JournalEntry) -> Result<(), String> {
+        let mut line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => return Err(format!("Error serializing session journal entry: {}", e)),
+        };
+        line.push('\n');
+
+        let mut journal = match self.journal.lock() {
+            Ok(journal) => journal,
+            Err(e) => return Err(format!("Session journal mutex was poisoned: {}", e)),
+        };
+
+        if journal.is_none() {
+            match OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+            {
+                Ok(file) => *journal = Some(file),
+                Err(e) => return Err(format!("Error opening session journal: {}", e)),
+            }
+        }
+
+        match journal.as_mut().map(|file| file.write_all(line.as_bytes())) {
+            Some(Err(e)) => {
+                // Reopen the file on the next write, in case it was moved from under us
+                *journal = None;
+                Err(format!("Error writing session journal: {}", e))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self) -> Result<Vec<(String, RoutingDecision)>, String> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(format!("Error opening session journal: {}", e)),
+        };
+
+        let mut sessions: HashMap<String, RoutingDecision> = HashMap::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Err(format!("Error reading session journal: {}", e)),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            // A crash mid-write can leave a truncated final line, which is safe to skip
+            match serde_json::from_str::<JournalEntry>(&line) {
+                Ok(JournalEntry::Record {
+                    session_id,
+                    decision,
+                }) => {
+                    sessions.insert(session_id, *decision);
+                }
+                Ok(JournalEntry::Forget { session_id }) => {
+                    sessions.remove(&session_id);
+                }
+                Err(e) => warn!(
+                    "Skipping malformed line {} of session journal: {}",
+                    number + 1,
+                    e
+                ),
+            }
+        }
+
+        let sessions: Vec<(String, RoutingDecision)> = sessions.into_iter().collect();
+        info!(
+            "Loaded {} sessions from {}",
+            sessions.len(),
+            self.path.display()
+        );
+        self.compact(&sessions)?;
+        Ok(sessions)
+    }
+
+    fn record(&self, session_id: &str, decision: &RoutingDecision) -> Result<(), String> {
+        self.append(&JournalEntry::Record {
+            session_id: session_id.to_string(),
+            decision: Box::new(decision.clone()),
+        })
+    }
+
+    fn forget(&self, session_id: &str) -> Result<(), String> {
+        self.append(&JournalEntry::Forget {
+            session_id: session_id.to_string(),
+        })
+    }
+
+    fn compact(&self, live: &[(String, RoutingDecision)]) -> Result<(), String> {
+        let mut contents = String::new();
+        for (session_id, decision) in live {
+            let entry = JournalEntry::Record {
+                session_id: session_id.clone(),
+                decision: Box::new(decision.clone()),
+            };
+            match serde_json::to_string(&entry) {
+                Ok(line) => {
+                    contents.push_str(&line);
+                    contents.push('\n');
+                }
+                Err(e) => return Err(format!("Error serializing session journal entry: {}", e)),
+            }
+        }
+
+        // Hold the journal lock so that no writes land in the file being replaced
+        let mut journal = match self.journal.lock() {
+            Ok(journal) => journal,
+            Err(e) => return Err(format!("Session journal mutex was poisoned: {}", e)),
+        };
+
+        // Write the compacted journal alongside the old one, then atomically swap it in
+        let mut compacted_path = self.path.clone().into_os_string();
+        compacted_path.push(".compact");
+        if let Err(e) = fs::write(&compacted_path, contents) {
+            return Err(format!("Error writing compacted session journal: {}", e));
+        }
+        if let Err(e) = fs::rename(&compacted_path, &self.path) {
+            return Err(format!("Error replacing session journal: {}", e));
+        }
+
+        *journal = None;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_file_session_store_round_trip() {
+    use std::time::SystemTime;
+
+    let path = std::env::temp_dir().join(format!(
+        "hub_router_sessions_{}.jsonl",
+        uuid::Uuid::new_v4()
+    ));
+    let store = FileSessionStore::new(path.to_str().unwrap());
+    assert!(store.load().unwrap().is_empty());
+
+    let decision = RoutingDecision::new(
+        uuid::Uuid::new_v4(),
+        url::Url::parse("http://hub.example.com:4444").unwrap(),
+        SystemTime::now(),
+    );
+    store.record("first", &decision).unwrap();
+    store.record("second", &decision).unwrap();
+    store.forget("first").unwrap();
+
+    // Simulate a torn write from a crash
+    OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap()
+        .write_all(b"{\"op\":\"rec")
+        .unwrap();
+
+    let reloaded = FileSessionStore::new(path.to_str().unwrap()).load().unwrap();
+    assert_eq!(reloaded.len(), 1);
+    assert_eq!(reloaded[0].0, "second");
+    assert_eq!(reloaded[0].1.hub_uuid, decision.hub_uuid);
+    assert_eq!(reloaded[0].1.hub_endpoint, decision.hub_endpoint);
+    assert_eq!(reloaded[0].1.decision_time, decision.decision_time);
+
+    // Loading compacts the journal down to the live sessions
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+    let _ = fs::remove_file(&path);
+}
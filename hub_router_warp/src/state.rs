@@ -1,14 +1,30 @@
 //! A single globally shared struct for the Hub Router's state,
 //! including configuration and the state of all of its registered hubs
 
-use crate::HubMap;
+use crate::{
+    api_auth::ApiAuthConfig,
+    audit::AuditLogConfig,
+    client::{OutboundPoolConfig, OutboundTlsConfig, SharedClients},
+    proxy_clients::{ClientUsage, ProxyClientsConfig},
+    queue::NewSessionQueue,
+    session_store::SessionStoreConfig,
+    strategy::RoutingStrategyKind,
+    timeouts::ProxyTimeoutsConfig,
+    tls::TlsConfig,
+    HubMap,
+};
 use log::warn;
 use serde::{Deserialize, Serialize};
 use std::{
     fs::{read_to_string, File},
     io::Write,
     net::Ipv4Addr,
-    sync::RwLock,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    sync::watch,
+    time::{self, Instant, Interval},
 };
 use utoipa::ToSchema;
 
@@ -17,9 +33,9 @@ enum PersistPath {
     Path(String),
 }
 
-impl Into<String> for PersistPath {
-    fn into(self) -> String {
-        match self {
+impl From<PersistPath> for String {
+    fn from(val: PersistPath) -> Self {
+        match val {
             PersistPath::Path(s) => s,
         }
     }
@@ -34,7 +50,7 @@ impl Default for PersistPath {
 /// HubRouterState is an encapsulation of all configurable state within a
 /// Hub Router instance. the notable exception to this is the session state
 /// table, as that is configured by the system at runtime dynamically and
-/// not persisted to disk.
+/// only persisted through the configured session store.
 ///
 /// The most notable data within this structure are the list of registered
 /// Hubs to route traffic to (this includes runtime state including the session
@@ -52,18 +68,106 @@ pub struct HubRouterState {
 
     #[serde(skip)]
     persist_file: PersistPath,
+
+    /// New session requests waiting for a hub to have a free slot
+    #[serde(skip)]
+    pub new_session_queue: NewSessionQueue,
+
+    /// How much of their quota each proxy client is using
+    #[serde(skip)]
+    pub client_usage: ClientUsage,
+
+    /// Notifies background tasks that the configuration has changed
+    #[serde(skip)]
+    config_changes: ConfigChanges,
+
+    /// The clients requests to hubs and peer replicas are sent with
+    #[serde(skip)]
+    pub clients: Arc<SharedClients>,
+}
+
+/// A channel which carries no value, only the fact that the configuration changed.
+/// Subscribers read the new configuration from the state themselves.
+#[derive(Debug)]
+struct ConfigChanges(watch::Sender<()>);
+
+impl Default for ConfigChanges {
+    fn default() -> Self {
+        Self(watch::channel(()).0)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(default)]
 pub struct HubRouterPrimitiveConfigs {
     pub reaper_thread_interval: u64,
+
+    /// How long (in minutes) a session may live before it is reaped, however busy it is.
     pub reaper_thread_duration_max: u64,
+
+    /// How long (in minutes) a session may go without any commands before it
+    /// is reaped. Zero only reaps sessions which reach the maximum duration.
+    pub reaper_thread_idle_max: u64,
     pub healthcheck_thread_interval: u64,
     pub healthcheck_timeout: u64,
     pub bind_port: u16,
     pub bind_ip: Ipv4Addr,
     pub api_bind_port: u16,
     pub api_bind_ip: Ipv4Addr,
+
+    /// Maximum number of new session requests which may wait for a free slot,
+    /// per set of requested capabilities. Zero disables queueing.
+    pub new_session_queue_size: u64,
+
+    /// How long (in seconds) a new session request may wait in the queue
+    /// before it is rejected.
+    pub new_session_wait_timeout: u64,
+
+    /// How many hubs a new session request may be attempted on before giving up.
+    pub new_session_max_attempts: u64,
+
+    /// How long (in seconds) after a new session request arrives that it may
+    /// still be retried on another hub.
+    pub new_session_retry_deadline: u64,
+
+    /// Where routing decisions are persisted, so that in-flight sessions
+    /// keep being routed to their hub across restarts.
+    pub session_store: SessionStoreConfig,
+
+    /// API addresses (such as `http://hub-router-1:8080`) of the other Hub Router
+    /// replicas to share routing decisions with.
+    pub peers: Vec<String>,
+
+    /// How new sessions are distributed between the hubs able to run them.
+    pub routing_strategy: RoutingStrategyKind,
+
+    /// Serve the proxy listener over TLS with these certificates.
+    pub proxy_tls: Option<TlsConfig>,
+
+    /// Serve the API and UI over TLS with these certificates.
+    pub api_tls: Option<TlsConfig>,
+
+    /// CAs to trust and the client certificate to present when connecting to
+    /// `https://` hubs and peers.
+    pub outbound_tls: OutboundTlsConfig,
+
+    /// How many connections to keep open to each hub and peer, and for how long.
+    pub outbound_pool: OutboundPoolConfig,
+
+    /// Who may use the API, and what they may do with it.
+    pub api_auth: ApiAuthConfig,
+
+    /// Origins allowed to make cross-origin requests to the API, or `*` for any.
+    pub api_cors_origins: Vec<String>,
+
+    /// Clients of the WebDriver proxy, and their new session quotas.
+    pub proxy_clients: ProxyClientsConfig,
+
+    /// How long to wait for hubs to create sessions and respond to commands.
+    pub proxy_timeouts: ProxyTimeoutsConfig,
+
+    /// Where to record the lifecycle of every session.
+    pub audit_log: AuditLogConfig,
 }
 
 impl Default for HubRouterPrimitiveConfigs {
@@ -71,12 +175,29 @@ impl Default for HubRouterPrimitiveConfigs {
         HubRouterPrimitiveConfigs {
             reaper_thread_interval: 60,
             reaper_thread_duration_max: 30,
+            reaper_thread_idle_max: 0,
             healthcheck_thread_interval: 10,
             healthcheck_timeout: 8,
             bind_port: 6543,
             bind_ip: Ipv4Addr::UNSPECIFIED,
             api_bind_port: 8080,
             api_bind_ip: Ipv4Addr::UNSPECIFIED,
+            new_session_queue_size: 256,
+            new_session_wait_timeout: 300,
+            new_session_max_attempts: 3,
+            new_session_retry_deadline: 300,
+            session_store: SessionStoreConfig::default(),
+            peers: vec![],
+            routing_strategy: RoutingStrategyKind::default(),
+            proxy_tls: None,
+            api_tls: None,
+            outbound_tls: OutboundTlsConfig::default(),
+            outbound_pool: OutboundPoolConfig::default(),
+            api_auth: ApiAuthConfig::default(),
+            api_cors_origins: vec!["*".into()],
+            proxy_clients: ProxyClientsConfig::default(),
+            proxy_timeouts: ProxyTimeoutsConfig::default(),
+            audit_log: AuditLogConfig::default(),
         }
     }
 }
@@ -103,6 +224,37 @@ impl HubRouterState {
         state
     }
 
+    /// A state with no hubs and the default configuration, persisted to the given file.
+    #[allow(unused)]
+    pub fn new_persisting_to(path: &str) -> Self {
+        Self {
+            persist_file: PersistPath::Path(path.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Let background tasks know that the configuration has changed,
+    /// so that they can pick up their new settings.
+    pub fn notify_config_changed(&self) {
+        self.config_changes.0.send_replace(());
+    }
+
+    /// A receiver which is marked as changed whenever the configuration changes.
+    pub fn watch_configs(&self) -> watch::Receiver<()> {
+        self.config_changes.0.subscribe()
+    }
+
+    /// Read the current configuration, falling back to the defaults if the lock is poisoned.
+    pub fn read_configs<T>(&self, read: impl FnOnce(&HubRouterPrimitiveConfigs) -> T) -> T {
+        match self.configs.read() {
+            Ok(conf) => read(&conf),
+            Err(e) => {
+                warn!("RwLock was poisoned reading configs: {}", e);
+                read(&HubRouterPrimitiveConfigs::default())
+            }
+        }
+    }
+
     #[allow(unused)]
     pub fn get_reaper_interval_secs(&self) -> Option<u64> {
         match self.configs.read() {
@@ -132,7 +284,7 @@ impl HubRouterState {
     pub fn persist(&self) -> Result<(), String> {
         let serialized = match serde_json::to_string_pretty(self) {
             Ok(str) => str,
-            Err(e) => return Err(format!("Error serializing state: {}", e.to_string())),
+            Err(e) => return Err(format!("Error serializing state: {}", e)),
         };
 
         let mut config_file = match File::create::<String>(self.persist_file.clone().into()) {
@@ -146,7 +298,7 @@ impl HubRouterState {
         };
 
         let bytes = serialized.as_bytes();
-        match config_file.write(bytes) {
+        match config_file.write_all(bytes) {
             Ok(_) => {}
             Err(e) => {
                 warn!("Error writing serialized hubs {}", e);
@@ -154,6 +306,74 @@ impl HubRouterState {
             }
         };
 
-        return Ok(());
+        Ok(())
+    }
+}
+
+/// A timer whose period is read from the configuration, and which starts over
+/// on the new period as soon as the configuration changes it.
+pub struct ConfigInterval {
+    state: Arc<HubRouterState>,
+    period: fn(&HubRouterPrimitiveConfigs) -> u64,
+    current: Duration,
+    interval: Interval,
+    changes: watch::Receiver<()>,
+}
+
+impl ConfigInterval {
+    /// Like `tokio::time::interval`, the first tick completes immediately.
+    /// Periods are in seconds, and never shorter than one.
+    pub fn new(state: Arc<HubRouterState>, period: fn(&HubRouterPrimitiveConfigs) -> u64) -> Self {
+        let current = Duration::from_secs(u64::max(state.read_configs(period), 1));
+        let changes = state.watch_configs();
+        Self {
+            state,
+            period,
+            current,
+            interval: time::interval(current),
+            changes,
+        }
+    }
+
+    pub async fn tick(&mut self) {
+        loop {
+            tokio::select! {
+                _ = self.interval.tick() => return,
+                Ok(()) = self.changes.changed() => {
+                    let period = Duration::from_secs(u64::max(self.state.read_configs(self.period), 1));
+                    if period != self.current {
+                        self.current = period;
+                        self.interval = time::interval_at(Instant::now() + period, period);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_config_interval_follows_config() {
+    let state = Arc::new(HubRouterState::default());
+    if let Ok(mut conf) = state.configs.write() {
+        conf.reaper_thread_interval = 3600;
+    }
+    let mut interval = ConfigInterval::new(state.clone(), |conf| conf.reaper_thread_interval);
+    interval.tick().await;
+
+    // The next tick is an hour away, until the interval is shortened
+    let waiting = tokio::time::timeout(Duration::from_millis(100), interval.tick()).await;
+    assert!(waiting.is_err());
+    if let Ok(mut conf) = state.configs.write() {
+        conf.reaper_thread_interval = 1;
     }
+    state.notify_config_changed();
+    let started = std::time::Instant::now();
+    let ticked = tokio::time::timeout(Duration::from_secs(3), interval.tick()).await;
+    assert!(ticked.is_ok());
+    assert!(started.elapsed() >= Duration::from_millis(900));
+
+    // Changes to other settings leave the timer alone
+    state.notify_config_changed();
+    let waiting = tokio::time::timeout(Duration::from_millis(500), interval.tick()).await;
+    assert!(waiting.is_err());
 }
@@ -0,0 +1,255 @@
+//! Routing strategies, which pick the hub a new session is sent to from the
+//! hubs which are healthy and able to run it. The strategy in use is read from
+//! the configuration on every decision, so it can be changed at runtime.
+
+use std::{
+    cmp::Ordering,
+    sync::atomic::{AtomicUsize, Ordering as AtomicOrdering},
+};
+
+use lazy_static::lazy_static;
+use rand::{random, seq::SliceRandom, thread_rng};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+lazy_static! {
+    static ref WEIGHTED_RANDOM: WeightedRandom = WeightedRandom;
+    static ref LEAST_LOADED: LeastLoaded = LeastLoaded;
+    static ref ROUND_ROBIN: RoundRobin = RoundRobin::default();
+    static ref POWER_OF_TWO: PowerOfTwoChoices = PowerOfTwoChoices;
+    static ref PRIORITY: Priority = Priority;
+}
+
+/// The routing strategies which can be selected from configuration.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingStrategyKind {
+    #[default]
+    WeightedRandom,
+    LeastLoaded,
+    RoundRobin,
+    PowerOfTwoChoices,
+    Priority,
+}
+
+impl RoutingStrategyKind {
+    /// The shared instance of this strategy. Strategies keep their state
+    /// (such as the round robin position) across configuration changes.
+    pub fn strategy(&self) -> &'static dyn RoutingStrategy {
+        match self {
+            RoutingStrategyKind::WeightedRandom => &*WEIGHTED_RANDOM,
+            RoutingStrategyKind::LeastLoaded => &*LEAST_LOADED,
+            RoutingStrategyKind::RoundRobin => &*ROUND_ROBIN,
+            RoutingStrategyKind::PowerOfTwoChoices => &*POWER_OF_TWO,
+            RoutingStrategyKind::Priority => &*PRIORITY,
+        }
+    }
+}
+
+impl std::str::FromStr for RoutingStrategyKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .map_err(|_| format!("unknown routing strategy: {}", s))
+    }
+}
+
+impl std::fmt::Display for RoutingStrategyKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => write!(f, "{}", name),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// A hub which is eligible for a routing decision, along with its fullness
+/// for the capability being requested (or for all of its slots, if none was).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub uuid: Uuid,
+    pub active: u64,
+    pub max: u64,
+    pub weight: u32,
+    pub priority: u32,
+}
+
+impl Candidate {
+    /// Compare how loaded two candidates are, as the fraction of their slots in use.
+    /// A hub which hasn't reported any slots is treated as empty.
+    fn cmp_load(&self, other: &Candidate) -> Ordering {
+        let load = |c: &Candidate| -> (u64, u64) {
+            if c.max == 0 {
+                (0, 1)
+            } else {
+                (c.active, c.max)
+            }
+        };
+        let ((a_active, a_max), (b_active, b_max)) = (load(self), load(other));
+        (a_active * b_max).cmp(&(b_active * a_max))
+    }
+}
+
+/// Picks one hub out of a non-empty set of candidates.
+pub trait RoutingStrategy: Send + Sync {
+    fn select(&self, candidates: &[Candidate]) -> Option<Uuid>;
+}
+
+/// Weighted random selection. A hub's weight is the number of slots it has which
+/// can run the test, plus the number of these slots which are empty, so empty slots count double,
+/// scaled by the static weight configured for the hub.
+#[derive(Debug, Default)]
+pub struct WeightedRandom;
+
+impl RoutingStrategy for WeightedRandom {
+    fn select(&self, candidates: &[Candidate]) -> Option<Uuid> {
+        let keys_and_weights: Vec<_> = candidates
+            .iter()
+            .map(|c| {
+                let capacity_weight = u64::max((2 * c.max).saturating_sub(c.active), 1);
+                (c.uuid, capacity_weight * c.weight as u64)
+            })
+            .collect();
+
+        // To select a weighted random hub, we compute the total sum of weights,
+        // pick a random number from 0 to that weight sum,
+        // and skip hubs until the cumulative weight of skipped hubs exceeds
+        // the random number.
+        let weight_sum = keys_and_weights
+            .iter()
+            .fold(0, |acc: u64, (_, weight)| acc + *weight);
+
+        // Only hubs with a static weight of zero are left, so treat them all equally
+        if weight_sum == 0 {
+            return candidates.choose(&mut thread_rng()).map(|c| c.uuid);
+        }
+
+        let selection_weight_distance: u64 = random::<u64>() % weight_sum;
+        let mut accumulated_weight: u64 = 0;
+
+        for (uuid, weight) in keys_and_weights {
+            accumulated_weight += weight;
+            if accumulated_weight > selection_weight_distance {
+                return Some(uuid);
+            }
+        }
+        None
+    }
+}
+
+/// Always picks the hub with the lowest fraction of its slots in use,
+/// breaking ties at random.
+#[derive(Debug, Default)]
+pub struct LeastLoaded;
+
+impl RoutingStrategy for LeastLoaded {
+    fn select(&self, candidates: &[Candidate]) -> Option<Uuid> {
+        let mut shuffled: Vec<&Candidate> = candidates.iter().collect();
+        shuffled.shuffle(&mut thread_rng());
+        shuffled
+            .into_iter()
+            .min_by(|a, b| a.cmp_load(b))
+            .map(|c| c.uuid)
+    }
+}
+
+/// Cycles through the candidates in a stable order.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl RoutingStrategy for RoundRobin {
+    fn select(&self, candidates: &[Candidate]) -> Option<Uuid> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let mut ordered: Vec<Uuid> = candidates.iter().map(|c| c.uuid).collect();
+        ordered.sort();
+        let position = self.next.fetch_add(1, AtomicOrdering::Relaxed);
+        Some(ordered[position % ordered.len()])
+    }
+}
+
+/// Picks two candidates at random, and sends the session to the less loaded of them.
+#[derive(Debug, Default)]
+pub struct PowerOfTwoChoices;
+
+impl RoutingStrategy for PowerOfTwoChoices {
+    fn select(&self, candidates: &[Candidate]) -> Option<Uuid> {
+        candidates
+            .choose_multiple(&mut thread_rng(), 2)
+            .min_by(|a, b| a.cmp_load(b))
+            .map(|c| c.uuid)
+    }
+}
+
+/// Strict failover ordering: every session goes to the hub with the lowest
+/// priority value, and only spills over to the next when it is full or unhealthy.
+/// Hubs with the same priority share load as least loaded does.
+#[derive(Debug, Default)]
+pub struct Priority;
+
+impl RoutingStrategy for Priority {
+    fn select(&self, candidates: &[Candidate]) -> Option<Uuid> {
+        let highest = candidates.iter().map(|c| c.priority).min()?;
+        let preferred: Vec<Candidate> = candidates
+            .iter()
+            .filter(|c| c.priority == highest)
+            .cloned()
+            .collect();
+        LeastLoaded.select(&preferred)
+    }
+}
+
+#[test]
+fn test_routing_strategies() {
+    let candidate = |n: u128, active: u64, max: u64, priority: u32| Candidate {
+        uuid: Uuid::from_u128(n),
+        active,
+        max,
+        weight: 1,
+        priority,
+    };
+    let candidates = vec![
+        candidate(1, 3, 4, 1),
+        candidate(2, 1, 4, 2),
+        candidate(3, 2, 4, 1),
+    ];
+
+    assert_eq!(LeastLoaded.select(&candidates), Some(Uuid::from_u128(2)));
+    assert_eq!(Priority.select(&candidates), Some(Uuid::from_u128(3)));
+
+    let round_robin = RoundRobin::default();
+    let picks: Vec<_> = (0..4).map(|_| round_robin.select(&candidates).unwrap()).collect();
+    assert_eq!(
+        picks,
+        vec![1, 2, 3, 1].into_iter().map(Uuid::from_u128).collect::<Vec<_>>()
+    );
+
+    // Power of two never picks the most loaded of three hubs
+    for _ in 0..50 {
+        assert_ne!(PowerOfTwoChoices.select(&candidates), Some(Uuid::from_u128(1)));
+    }
+
+    for _ in 0..50 {
+        let pick = WeightedRandom.select(&candidates).unwrap();
+        assert!(candidates.iter().any(|c| c.uuid == pick));
+    }
+
+    // A hub with no static weight is never picked while another hub is available
+    let mut weighted = candidates.clone();
+    weighted[0].weight = 0;
+    for _ in 0..50 {
+        assert_ne!(WeightedRandom.select(&weighted), Some(Uuid::from_u128(1)));
+    }
+
+    assert_eq!(LeastLoaded.select(&[]), None);
+    assert_eq!(
+        "power_of_two_choices".parse::<RoutingStrategyKind>(),
+        Ok(RoutingStrategyKind::PowerOfTwoChoices)
+    );
+    assert_eq!(RoutingStrategyKind::LeastLoaded.to_string(), "least_loaded");
+}
@@ -0,0 +1,171 @@
+//! Timeouts for requests proxied to hubs, so that a wedged hub fails a test
+//! promptly with a WebDriver `timeout` error, rather than leaving it hanging
+//! until the client gives up on its own.
+
+use std::{error::Error, io, time::Duration};
+
+use hyper::Method;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// How long to wait for hubs to respond. Connecting to a hub is limited by
+/// `outbound_pool.connect_timeout`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[serde(default)]
+pub struct ProxyTimeoutsConfig {
+    /// Seconds to wait for a hub to create a new session. Zero waits indefinitely.
+    pub new_session: u64,
+
+    /// Seconds to wait for a hub to respond to any other command. Zero waits indefinitely.
+    /// The default matches WebDriver's default page load timeout, so that navigation isn't cut short.
+    pub command: u64,
+
+    /// Timeouts for particular commands, which take the place of `command`.
+    /// The first override which matches a command is used.
+    pub overrides: Vec<CommandTimeout>,
+}
+
+impl Default for ProxyTimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            new_session: 300,
+            command: 300,
+            overrides: vec![],
+        }
+    }
+}
+
+/// A timeout for the commands matching a method and path.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct CommandTimeout {
+    /// Only match commands with this HTTP method, or any method if left out.
+    #[serde(default)]
+    pub method: Option<String>,
+
+    /// The command's path after `/session/{session id}/`, such as `execute/async`,
+    /// or the whole path for commands outside of a session. A `*` segment matches
+    /// any one segment, as in `element/*/click`.
+    pub command: String,
+
+    /// Seconds to wait. Zero waits indefinitely.
+    pub timeout: u64,
+}
+
+impl CommandTimeout {
+    fn matches(&self, method: &Method, command: &str) -> bool {
+        if self
+            .method
+            .as_ref()
+            .is_some_and(|m| !m.eq_ignore_ascii_case(method.as_str()))
+        {
+            return false;
+        }
+        let pattern = self.command.trim_matches('/');
+        pattern.split('/').count() == command.split('/').count()
+            && pattern
+                .split('/')
+                .zip(command.split('/'))
+                .all(|(p, c)| p == "*" || p == c)
+    }
+}
+
+impl ProxyTimeoutsConfig {
+    /// How long to wait for a hub to respond to a command, if there's a limit.
+    pub fn for_command(&self, method: &Method, path: &str) -> Option<Duration> {
+        lazy_static! {
+            static ref SESSION_PREFIX: Regex = Regex::new(r"^/session/[^/]*(/|\z)").unwrap();
+        }
+        let command = SESSION_PREFIX.replace(path, "");
+        let command = command.trim_matches('/');
+        let seconds = self
+            .overrides
+            .iter()
+            .find(|o| o.matches(method, command))
+            .map_or(self.command, |o| o.timeout);
+        limit(seconds)
+    }
+
+    /// How long to wait for a hub to create a new session, if there's a limit.
+    pub fn for_new_session(&self) -> Option<Duration> {
+        limit(self.new_session)
+    }
+}
+
+fn limit(seconds: u64) -> Option<Duration> {
+    Some(Duration::from_secs(seconds)).filter(|d| !d.is_zero())
+}
+
+/// Whether a request failed because a connection to the hub couldn't be made in time.
+pub fn is_connect_timeout(error: &hyper::Error) -> bool {
+    let mut source = error.source();
+    while let Some(e) = source {
+        if e
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+        {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+#[test]
+fn test_command_timeouts() {
+    let config = ProxyTimeoutsConfig {
+        command: 60,
+        overrides: vec![
+            CommandTimeout {
+                method: Some("post".into()),
+                command: "execute/async".into(),
+                timeout: 600,
+            },
+            CommandTimeout {
+                method: None,
+                command: "/element/*/click".into(),
+                timeout: 0,
+            },
+            CommandTimeout {
+                method: None,
+                command: "status".into(),
+                timeout: 5,
+            },
+        ],
+        ..Default::default()
+    };
+    let timeout = |method: Method, path: &str| config.for_command(&method, path);
+
+    assert_eq!(
+        timeout(Method::POST, "/session/abc/execute/async"),
+        Some(Duration::from_secs(600))
+    );
+    assert_eq!(
+        timeout(Method::GET, "/session/abc/execute/async"),
+        Some(Duration::from_secs(60))
+    );
+    assert_eq!(
+        timeout(Method::POST, "/session/abc/execute/sync"),
+        Some(Duration::from_secs(60))
+    );
+    assert_eq!(timeout(Method::POST, "/session/abc/element/def/click"), None);
+    assert_eq!(
+        timeout(Method::POST, "/session/abc/element/def/clear"),
+        Some(Duration::from_secs(60))
+    );
+    assert_eq!(timeout(Method::GET, "/status"), Some(Duration::from_secs(5)));
+    assert_eq!(timeout(Method::DELETE, "/session/abc"), Some(Duration::from_secs(60)));
+    assert_eq!(
+        ProxyTimeoutsConfig::default().for_new_session(),
+        Some(Duration::from_secs(300))
+    );
+    assert_eq!(
+        ProxyTimeoutsConfig {
+            new_session: 0,
+            ..Default::default()
+        }
+        .for_new_session(),
+        None
+    );
+}
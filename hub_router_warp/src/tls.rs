@@ -0,0 +1,291 @@
+//! TLS termination for the proxy and API listeners. Certificates are read
+//! from PEM files, which are polled for changes so that renewed certificates
+//! are picked up without a restart. When a client CA is configured, clients
+//! must present a certificate signed by it (mutual TLS).
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time,
+};
+use tokio_rustls::{
+    rustls::{
+        server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient},
+        Certificate, PrivateKey, RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use utoipa::ToSchema;
+
+/// TLS settings for a single listener.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct TlsConfig {
+    /// PEM file holding the certificate chain to present, leaf first.
+    pub cert_path: String,
+
+    /// PEM file holding the certificate's private key.
+    pub key_path: String,
+
+    /// PEM file of CA certificates which client certificates are verified against.
+    /// When set, clients must present a valid certificate, unless `client_cert_optional` is set.
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
+
+    /// Accept clients which present no certificate at all, while still
+    /// rejecting clients which present one the client CA didn't sign.
+    #[serde(default)]
+    pub client_cert_optional: bool,
+
+    /// How often (in seconds) the certificate files are checked for changes.
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+}
+
+fn default_reload_interval() -> u64 {
+    30
+}
+
+/// A TLS server configuration which is rebuilt whenever its files change on disk.
+#[derive(Debug)]
+pub struct ReloadingTlsConfig {
+    config: TlsConfig,
+    current: RwLock<Arc<ServerConfig>>,
+    modified: RwLock<Vec<Option<SystemTime>>>,
+}
+
+impl ReloadingTlsConfig {
+    pub fn load(config: TlsConfig) -> Result<Arc<Self>, String> {
+        let server_config = build_server_config(&config)?;
+        Ok(Arc::new(Self {
+            modified: RwLock::new(modification_times(&config)),
+            current: RwLock::new(Arc::new(server_config)),
+            config,
+        }))
+    }
+
+    /// An acceptor using the most recently loaded certificates.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        match self.current.read() {
+            Ok(current) => TlsAcceptor::from(current.clone()),
+            Err(e) => TlsAcceptor::from(e.into_inner().clone()),
+        }
+    }
+
+    /// Reload the certificates if any of their files changed since they were last loaded.
+    /// If the new files can't be loaded, the previous certificates stay in use.
+    pub fn reload_if_changed(&self) {
+        let modified = modification_times(&self.config);
+        let changed = match self.modified.read() {
+            Ok(previous) => *previous != modified,
+            Err(_) => true,
+        };
+        if !changed {
+            return;
+        }
+
+        match build_server_config(&self.config) {
+            Ok(server_config) => {
+                if let Ok(mut current) = self.current.write() {
+                    *current = Arc::new(server_config);
+                }
+                if let Ok(mut previous) = self.modified.write() {
+                    *previous = modified;
+                }
+                info!("Reloaded TLS certificate {}", self.config.cert_path);
+            }
+            Err(e) => warn!(
+                "Unable to reload TLS certificate {}, continuing with the previous one: {}",
+                self.config.cert_path, e
+            ),
+        }
+    }
+
+    /// How often to poll the certificate files for changes.
+    fn reload_interval(&self) -> Duration {
+        Duration::from_secs(u64::max(self.config.reload_interval, 1))
+    }
+}
+
+fn modification_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    [
+        Some(&config.cert_path),
+        Some(&config.key_path),
+        config.client_ca_path.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+    .collect()
+}
+
+fn read_pem(path: &str) -> Result<Vec<rustls_pemfile::Item>, String> {
+    let file = File::open(path).map_err(|e| format!("Unable to open {}: {}", path, e))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|e| format!("Unable to parse {}: {}", path, e))
+}
+
+pub fn read_certificates(path: &str) -> Result<Vec<Certificate>, String> {
+    let certificates: Vec<Certificate> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+
+    if certificates.is_empty() {
+        return Err(format!("No certificates found in {}", path));
+    }
+    Ok(certificates)
+}
+
+pub fn read_private_key(path: &str) -> Result<PrivateKey, String> {
+    read_pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("No private key found in {}", path))
+}
+
+pub fn build_server_config(config: &TlsConfig) -> Result<ServerConfig, String> {
+    let certificates = read_certificates(&config.cert_path)?;
+    let key = read_private_key(&config.key_path)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &config.client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(client_ca_path)? {
+                roots
+                    .add(&certificate)
+                    .map_err(|e| format!("Invalid client CA in {}: {}", client_ca_path, e))?;
+            }
+            if config.client_cert_optional {
+                builder.with_client_cert_verifier(
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+                )
+            } else {
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            }
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder
+        .with_single_cert(certificates, key)
+        .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
+/// Accept connections on the listener and perform TLS handshakes, yielding
+/// established TLS streams. Handshakes happen in their own tasks, so that a
+/// slow or misbehaving client can't hold up anyone else's connection.
+/// The certificate files are polled for changes until the stream is dropped,
+/// at which point the listener is closed.
+pub fn tls_incoming(
+    listener: TcpListener,
+    tls: Arc<ReloadingTlsConfig>,
+) -> ReceiverStream<Result<TlsStream<TcpStream>, io::Error>> {
+    let (sender, receiver) = mpsc::channel(64);
+
+    tokio::task::spawn(async move {
+        let mut reload_interval = time::interval(tls.reload_interval());
+        loop {
+            let accepted = tokio::select! {
+                _ = sender.closed() => break,
+                _ = reload_interval.tick() => {
+                    tls.reload_if_changed();
+                    continue;
+                }
+                accepted = listener.accept() => accepted,
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Error accepting connection: {}", e);
+                    continue;
+                }
+            };
+
+            let acceptor = tls.acceptor();
+            let sender = sender.clone();
+            tokio::task::spawn(async move {
+                match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let _ = sender.send(Ok(stream)).await;
+                    }
+                    Err(e) => warn!("TLS handshake with {} failed: {}", peer, e),
+                }
+            });
+        }
+    });
+
+    ReceiverStream::new(receiver)
+}
+
+#[test]
+fn test_reloading_tls_config() {
+    let directory = std::env::temp_dir().join(format!("hub_router_tls_{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&directory).unwrap();
+    let write_certificate = |name: &str| {
+        let certificate = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        fs::write(directory.join("cert.pem"), certificate.serialize_pem().unwrap()).unwrap();
+        fs::write(
+            directory.join("key.pem"),
+            certificate.serialize_private_key_pem(),
+        )
+        .unwrap();
+        certificate
+    };
+
+    let ca = write_certificate("localhost");
+    fs::write(directory.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+    let config = TlsConfig {
+        cert_path: directory.join("cert.pem").to_string_lossy().into(),
+        key_path: directory.join("key.pem").to_string_lossy().into(),
+        client_ca_path: Some(directory.join("ca.pem").to_string_lossy().into()),
+        client_cert_optional: false,
+        reload_interval: 1,
+    };
+    let tls = ReloadingTlsConfig::load(config.clone()).unwrap();
+    let before = tls.current.read().unwrap().clone();
+
+    // Nothing changed, so the same configuration stays in use
+    tls.reload_if_changed();
+    assert!(Arc::ptr_eq(&before, &tls.current.read().unwrap()));
+
+    // A broken certificate is ignored, keeping the previous one
+    fs::write(directory.join("cert.pem"), "not a certificate").unwrap();
+    *tls.modified.write().unwrap() = vec![];
+    tls.reload_if_changed();
+    assert!(Arc::ptr_eq(&before, &tls.current.read().unwrap()));
+
+    // A renewed certificate is picked up
+    write_certificate("localhost");
+    tls.reload_if_changed();
+    assert!(!Arc::ptr_eq(&before, &tls.current.read().unwrap()));
+
+    assert!(ReloadingTlsConfig::load(TlsConfig {
+        key_path: directory.join("missing.pem").to_string_lossy().into(),
+        ..config
+    })
+    .is_err());
+    let _ = fs::remove_dir_all(&directory);
+}
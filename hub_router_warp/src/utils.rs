@@ -68,7 +68,7 @@ pub fn serialize_url<S>(url: &url::Url, serializer: S) -> Result<S::Ok, S::Error
 where
     S: Serializer,
 {
-    serializer.serialize_str(&url.to_string())
+    serializer.serialize_str(url.as_ref())
 }
 
 pub fn deserialize_url<'de, D>(deserializer: D) -> Result<url::Url, D::Error>
@@ -94,7 +94,7 @@ impl<'de> Visitor<'de> for DashMapVisitor {
         let map = DashMap::new();
 
         while let Some(hub) = seq.next_element::<Hub>()? {
-            map.insert(hub.meta.uuid.clone(), hub);
+            map.insert(hub.meta.uuid, hub);
         }
 
         Ok(map)
@@ -120,3 +120,14 @@ where
 {
     deserializer.deserialize_seq(DashMapVisitor)
 }
+
+/// Deserialize a field which is present, even if null, into `Some`, so that
+/// with `#[serde(default)]` an absent field (`None`) can be told apart from
+/// an explicit null (`Some(None)`).
+pub fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: serde::Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}